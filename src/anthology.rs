@@ -0,0 +1,261 @@
+use std::collections::HashSet;
+
+use crate::Split;
+use crate::fb2_creator::normalizer::{is_notes, walk_content, blocks_mut, link_map};
+use crate::fb2_parser::{BookData, Section};
+use crate::fb2_parser::metadata_reader::{Metadata, Sequence};
+use crate::fb2_parser::content_reader::*;
+
+
+fn paragraph_text(p: &Paragraph) -> String {
+    match p {
+        Paragraph::Text(blocks) | Paragraph::Subtitle(blocks) | Paragraph::TextAuthor(blocks) =>
+            blocks.iter().map(|b| b.text.as_str()).collect::<String>().trim().to_string(),
+        _ => String::new()
+    }
+}
+
+/// Строка похожа на имя: "Роберт Шекли", "А. Н. Толстой", "Аркадий и Борис Стругацкие" -
+/// от двух до пяти слов с заглавной буквы, кроме "и", без цифр
+fn looks_like_name(s: &str) -> bool {
+    let words: Vec<&str> = s.split_whitespace().collect();
+    (2..=5).contains(&words.len())
+        && !s.chars().any(|c| c.is_numeric())
+        && words.iter().all(|w| matches!(*w, "и" | "and" | "&") || w.chars().next().is_some_and(|c| c.is_uppercase()))
+}
+
+/// Авторы из строки "Роберт Шекли, Гарри Гаррисон" или "Аркадий и Борис Стругацкие",
+/// None - если это не похоже на авторов
+fn parse_authors(s: &str) -> Option<Vec<String>> {
+    let s = s.trim().trim_end_matches(['.', ':']);
+    let authors: Vec<String> = s.split([',', ';'])
+        .map(|a| a.trim().to_string())
+        .filter(|a| !a.is_empty())
+        .collect();
+    if authors.is_empty() {return None}
+    if authors.iter().all(|a| looks_like_name(a)) {Some(authors)} else {None}
+}
+
+/// Название и авторы произведения. Авторы - первые строки заголовка из нескольких строк
+/// ("Роберт Шекли" / "Запах мысли") или text-author и подзаголовок перед текстом
+fn work_title(section: &Section) -> (String, Option<Vec<String>>) {
+    let lines: Vec<String> = section.title.iter()
+        .map(paragraph_text)
+        .filter(|l| !l.is_empty())
+        .collect();
+
+    if lines.len() > 1 && let Some(authors) = parse_authors(&lines[0]) {
+        return (lines[1..].join(". "), Some(authors))
+    };
+
+    let authors = section.paragraphs.iter()
+        .take_while(|p| !matches!(p, Paragraph::Text(_) | Paragraph::Poem(_)))
+        .filter(|p| matches!(p, Paragraph::TextAuthor(_) | Paragraph::Subtitle(_)))
+        .find_map(|p| parse_authors(&paragraph_text(p)));
+
+    (lines.join(". "), authors)
+}
+
+/// Продолжение секции после её подсекций: парсер выносит его в отдельную
+/// секцию того же уровня без заголовка и id
+pub fn is_continuation(content: &[Section], i: usize, level: u8) -> bool {
+    let section = &content[i];
+    i > 0 && section.level == level && section.title.is_empty()
+        && section.id.is_none() && content[i - 1].level > level
+}
+
+/// Конец части, которая начинается с секции start: все её подсекции и продолжения
+fn part_end(content: &[Section], start: usize) -> usize {
+    let level = content[start].level;
+    let mut end = start + 1;
+    while end < content.len() && !is_notes(&content[end])
+        && (content[end].level > level || is_continuation(content, end, level)) {
+        end += 1
+    };
+
+    end
+}
+
+/// Ссылки (#id) и картинки из абзацев
+fn references(content: &mut Vec<Section>) -> HashSet<String> {
+    let mut refs: HashSet<String> = HashSet::new();
+    walk_content(content, &mut |p| {
+        if let Paragraph::Image(Some(href)) = p {
+            refs.insert(href.clone());
+        };
+        for blocks in blocks_mut(p) {
+            for block in blocks.iter() {
+                if let Some(link) = &block.link && link.link.starts_with('#') {
+                    refs.insert(link.link.clone());
+                }
+            }
+        }
+    });
+
+    refs
+}
+
+/// Примечания, на которые ссылается часть, в том числе через другие примечания
+fn part_notes(notes: &[Section], mut refs: HashSet<String>) -> (Vec<Section>, HashSet<String>) {
+    loop {
+        let mut result: Vec<Section> = Vec::new();
+        for section in notes {
+            let paragraphs: Vec<Paragraph> = section.paragraphs.iter()
+                .filter(|p| matches!(p, Paragraph::Note(note)
+                    if note.id.as_ref().is_some_and(|id| refs.contains(&format!("#{id}")))))
+                .cloned()
+                .collect();
+            if !paragraphs.is_empty() {
+                result.push(Section {paragraphs, ..section.clone()})
+            }
+        };
+
+        let found = references(&mut result);
+        if found.is_subset(&refs) {
+            return (result, refs)
+        };
+        refs.extend(found)
+    }
+}
+
+/// Делит книгу на части: по секциям верхнего уровня или с секций с заданными id.
+/// Часть получает только свои примечания и картинки, её метаданные -
+/// из заголовка секции и метаданных книги, сама книга становится серией
+pub fn split(mut data: BookData, split: &Split) -> Result<Vec<BookData>, Box<dyn std::error::Error>> {
+    let notes: Vec<Section> = data.content.iter().filter(|s| is_notes(s)).cloned().collect();
+    let content = std::mem::take(&mut data.content);
+
+    let starts: Vec<usize> = match split {
+        Split::Sections => (0..content.len())
+            .filter(|&i| !is_notes(&content[i]) && content[i].level == 1 && !is_continuation(&content, i, 1))
+            .collect(),
+        Split::Ids(ids) => {
+            let mut starts = Vec::new();
+            for id in ids {
+                let id = id.trim_start_matches('#');
+                let i = content.iter()
+                    .position(|s| !is_notes(s) && s.id.as_deref() == Some(id))
+                    .ok_or(format!("There's no section with id {id}"))?;
+                starts.push(i)
+            };
+            starts.sort();
+            starts.dedup();
+            starts
+        }
+    };
+    if starts.is_empty() {
+        return Err("There's no sections to split the book at".into())
+    };
+
+    // Секция идёт в самую внутреннюю часть, в которую входит:
+    // вложенная часть вырезается из внешней, и та продолжается после неё
+    let mut owner: Vec<Option<usize>> = vec![None; content.len()];
+    for (n, &start) in starts.iter().enumerate() {
+        owner[start..part_end(&content, start)].fill(Some(n))
+    };
+    // текст вне частей не теряется молча, заголовок тела книги не в счёт
+    for (i, section) in content.iter().enumerate() {
+        if owner[i].is_some() || is_notes(section) || section.level == 0 {continue}
+        let name = match &section.id {
+            Some(id) => id.clone(),
+            None => section.title.iter().map(paragraph_text).find(|l| !l.is_empty())
+                .unwrap_or_else(|| (i + 1).to_string())
+        };
+        data.warnings.push(format!("Section {name} is not in any part, left out"))
+    };
+
+    let mut parts: Vec<BookData> = Vec::new();
+    for (n, &start) in starts.iter().enumerate() {
+        let shift = content[start].level.saturating_sub(1);
+        let mut sections: Vec<Section> = (start..content.len())
+            .filter(|&i| owner[i] == Some(n))
+            .map(|i| Section {level: content[i].level.saturating_sub(shift), ..content[i].clone()})
+            .collect();
+
+        let (notes, refs) = part_notes(&notes, references(&mut sections));
+        sections.extend(notes);
+        // ссылки на другие части становятся текстом
+        let links = link_map(&sections);
+        walk_content(&mut sections, &mut |p| {
+            for blocks in blocks_mut(p) {
+                for block in blocks {
+                    if block.link.as_ref().is_some_and(|l| l.link.starts_with('#') && !links.contains_key(&l.link)) {
+                        block.link = None
+                    }
+                }
+            }
+        });
+
+        let images = data.images.iter()
+            .filter(|(key, _)| refs.contains(*key) || data.meta.cover.as_ref() == Some(*key))
+            .map(|(key, image)| (key.clone(), image.clone()))
+            .collect();
+
+        let (title, authors) = work_title(&content[start]);
+        let number = (n + 1).to_string();
+        let meta = Metadata {
+            title: if title.is_empty() {format!("{} {number}", data.meta.title)} else {title},
+            authors: authors.unwrap_or_else(|| data.meta.authors.clone()),
+            genres: data.meta.genres.clone(),
+            language: data.meta.language.clone(),
+            sequence: Some(Sequence {name: data.meta.title.clone(), number: number.clone()}),
+            annotation: None,
+            cover: data.meta.cover.clone(),
+            id: data.meta.id.as_ref().map(|id| format!("{id}-{number}"))
+        };
+
+        parts.push(BookData {
+            meta,
+            link_map: links,
+            content: sections,
+            images,
+            encoding: data.encoding.clone(),
+            warnings: data.warnings.clone(),
+            // описание книги к части не подходит, оно строится по meta
+            description: None
+        })
+    };
+
+    Ok(parts)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::split;
+    use crate::Split;
+    use crate::fb2_parser::get_data_from_bytes;
+
+    const FIXTURE: &[u8] = include_bytes!("../tests/fixtures/roundtrip.fb2");
+
+    fn ids(part: &crate::fb2_parser::BookData) -> Vec<&str> {
+        part.content.iter().filter_map(|s| s.id.as_deref()).collect()
+    }
+
+    /// Вложенная часть вырезается из внешней, остальной текст внешней части остаётся в ней
+    #[test]
+    fn nested_part_keeps_rest_of_outer() {
+        let mut data = get_data_from_bytes(FIXTURE).unwrap();
+        data.warnings.push(String::from("parser warning"));
+        let parts = split(data, &Split::Ids(vec![String::from("ch1"), String::from("ch1_1")])).unwrap();
+
+        assert_eq!(parts.len(), 2);
+        assert_eq!(ids(&parts[0]), ["ch1", "ch1_2"]);
+        assert_eq!(ids(&parts[1]), ["ch1_1"]);
+        // ch2 не попал ни в одну часть
+        for part in &parts {
+            assert_eq!(part.warnings, ["parser warning", "Section ch2 is not in any part, left out"]);
+        }
+    }
+
+    #[test]
+    fn sections_split_leaves_nothing_out() {
+        let data = get_data_from_bytes(FIXTURE).unwrap();
+        let parts = split(data, &Split::Sections).unwrap();
+
+        assert_eq!(parts.len(), 2);
+        assert_eq!(ids(&parts[0]), ["ch1", "ch1_1", "ch1_2"]);
+        assert_eq!(ids(&parts[1]), ["ch2"]);
+        assert!(parts.iter().all(|p| p.warnings.is_empty()));
+    }
+}
@@ -0,0 +1,112 @@
+use std::path::PathBuf;
+
+use crate::fb2_parser::BookData;
+use crate::fb2_parser::content_reader::{Paragraph, TextBlock};
+
+
+/// Section of a book: nesting level, title and number of words in it
+#[derive(Debug, Clone)]
+pub struct SectionInfo {
+    pub level: u8,
+    pub title: String,
+    pub words: usize
+}
+
+/// Metadata and statistics of a book, see info()
+#[derive(Debug, Clone)]
+pub struct BookInfo {
+    /// Path of the book inside an archive, None for a single book
+    pub source: Option<PathBuf>,
+    pub title: String,
+    pub authors: Vec<String>,
+    /// Series name and index
+    pub series: Option<(String, String)>,
+    pub genres: Vec<String>,
+    pub language: String,
+    /// Annotation by paragraphs
+    pub annotation: Vec<String>,
+    /// Id of the document (document-info in fb2, identifier in epub)
+    pub id: Option<String>,
+    pub cover: bool,
+    /// Encoding the book was read in, None for epub and fb3
+    pub encoding: Option<String>,
+    /// Number of images, 0 if only metadata was read
+    pub images: usize,
+    /// Sections in reading order, empty if only metadata was read
+    pub sections: Vec<SectionInfo>,
+    /// Number of words in the text, 0 if only metadata was read
+    pub words: usize
+}
+
+
+fn blocks_text(blocks: &[TextBlock], text: &mut String) {
+    for block in blocks {
+        text.push_str(&block.text)
+    };
+    text.push(' ')
+}
+
+/// Весь текст абзацев, включая стихи, цитаты и эпиграфы
+fn paragraphs_text(paragraphs: &[Paragraph], text: &mut String) {
+    for p in paragraphs {
+        match p {
+            Paragraph::Text(blocks) | Paragraph::V(blocks) |
+            Paragraph::TextAuthor(blocks) | Paragraph::Subtitle(blocks) => blocks_text(blocks, text),
+            Paragraph::Note(s) | Paragraph::Epigraph(s) |
+            Paragraph::Cite(s) | Paragraph::Annotation(s) => {
+                paragraphs_text(&s.title, text);
+                paragraphs_text(&s.paragraphs, text);
+            },
+            Paragraph::Poem(poem) => {
+                paragraphs_text(&poem.title, text);
+                for stanza in &poem.stanzas {
+                    paragraphs_text(&stanza.title, text);
+                    paragraphs_text(&stanza.v, text);
+                };
+                paragraphs_text(&poem.paragraphs, text);
+                blocks_text(&poem.date, text);
+            },
+            Paragraph::Image(_) | Paragraph::EmptyLine => {}
+        }
+    }
+}
+
+fn count_words(text: &str) -> usize {
+    text.split_whitespace()
+        .filter(|w| w.chars().any(|c| c.is_alphanumeric()))
+        .count()
+}
+
+
+pub fn from_data(data: BookData, source: Option<PathBuf>) -> BookInfo {
+    let sections: Vec<SectionInfo> = data.content.iter()
+        .map(|section| {
+            let mut title = String::new();
+            paragraphs_text(&section.title, &mut title);
+            let mut text = String::new();
+            paragraphs_text(&section.paragraphs, &mut text);
+
+            SectionInfo {
+                level: section.level,
+                title: title.split_whitespace().collect::<Vec<_>>().join(" "),
+                words: count_words(&title) + count_words(&text)
+            }
+        })
+        .collect();
+
+    BookInfo {
+        source,
+        title: data.meta.title,
+        authors: data.meta.authors,
+        series: data.meta.sequence.map(|s| (s.name, s.number)),
+        genres: data.meta.genres,
+        language: data.meta.language,
+        annotation: data.meta.annotation.unwrap_or_default(),
+        id: data.meta.id,
+        cover: data.meta.cover.is_some(),
+        encoding: data.encoding,
+        images: data.images.len(),
+        words: sections.iter().map(|s| s.words).sum(),
+        sections
+    }
+}
@@ -0,0 +1,127 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+
+use bzip2::read::MultiBzDecoder;
+use flate2::read::MultiGzDecoder;
+use lzma_rust2::XzReader;
+
+
+/// Больше одна книга или вложенный архив после распаковки не бывает.
+/// Защита от zip-бомб: сжатые данные могут распаковаться в гигабайты
+pub const MAX_UNPACKED: u64 = 512 * 1024 * 1024;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    Gzip,
+    Bzip2,
+    Xz
+}
+
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default()
+        .to_lowercase()
+}
+
+pub fn get_compression(path: &Path) -> Option<Compression> {
+    let name = file_name(path);
+    match name.rsplit('.').next() {
+        Some("gz" | "tgz") => Some(Compression::Gzip),
+        Some("bz2" | "tbz" | "tbz2") => Some(Compression::Bzip2),
+        Some("xz" | "txz") => Some(Compression::Xz),
+        _ => None
+    }
+}
+
+/// Имя файла без суффикса сжатия в нижнем регистре: book.fb2.gz -> book.fb2, dump.tgz -> dump.tar
+pub fn inner_name(path: &Path) -> String {
+    let name = file_name(path);
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((s, e)) if get_compression(path).is_some() => (s.to_string(), e.to_string()),
+        _ => return name
+    };
+
+    match &extension[..] {
+        "tgz" | "tbz" | "tbz2" | "txz" => format!("{stem}.tar"),
+        _ => stem
+    }
+}
+
+/// Открывает файл, распаковывая его на лету, если он сжат
+pub fn open(path: &Path) -> io::Result<Box<dyn Read>> {
+    let file = BufReader::new(File::open(path)?);
+
+    Ok(match get_compression(path) {
+        Some(Compression::Gzip) => Box::new(MultiGzDecoder::new(file)),
+        Some(Compression::Bzip2) => Box::new(MultiBzDecoder::new(file)),
+        Some(Compression::Xz) => Box::new(XzReader::new(file, true)),
+        None => Box::new(file)
+    })
+}
+
+fn too_big(max: u64) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Unpacked data is bigger than {} MB", max / 1024 / 1024))
+}
+
+fn read_at_most<R: Read>(reader: R, max: u64) -> io::Result<Vec<u8>> {
+    let mut bytes: Vec<u8> = Vec::new();
+    reader.take(max + 1).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 > max {
+        return Err(too_big(max))
+    };
+
+    Ok(bytes)
+}
+
+fn copy_at_most<R: Read, W: Write>(reader: R, writer: &mut W, max: u64) -> io::Result<u64> {
+    let copied = io::copy(&mut reader.take(max + 1), writer)?;
+    if copied > max {
+        return Err(too_big(max))
+    };
+
+    Ok(copied)
+}
+
+/// Читает распакованные данные в память, но не больше MAX_UNPACKED
+pub fn read_limited<R: Read>(reader: R) -> io::Result<Vec<u8>> {
+    read_at_most(reader, MAX_UNPACKED)
+}
+
+/// io::copy не больше MAX_UNPACKED
+pub fn copy_limited<R: Read, W: Write>(reader: R, writer: &mut W) -> io::Result<u64> {
+    copy_at_most(reader, writer, MAX_UNPACKED)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use flate2::read::GzDecoder;
+
+    use super::{copy_at_most, read_at_most};
+
+    /// Маленький gz, который распаковывается в много нулей, обрывается на пределе
+    #[test]
+    fn bomb_is_stopped() {
+        let mut gz = GzEncoder::new(Vec::new(), Compression::best());
+        gz.write_all(&vec![0; 4 * 1024 * 1024]).unwrap();
+        let packed = gz.finish().unwrap();
+        assert!(packed.len() < 64 * 1024);
+
+        let unpacked = |max: u64| read_at_most(GzDecoder::new(&packed[..]), max);
+        let err = unpacked(1024 * 1024).unwrap_err();
+        assert_eq!(err.to_string(), "Unpacked data is bigger than 1 MB");
+        assert_eq!(unpacked(4 * 1024 * 1024).unwrap().len(), 4 * 1024 * 1024);
+
+        let mut copied = Vec::new();
+        assert!(copy_at_most(GzDecoder::new(&packed[..]), &mut copied, 1024 * 1024).is_err());
+        assert!(copied.len() <= 1024 * 1024 + 1);
+    }
+}
@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::RunArgs;
+
+
+/// Настройки из конфига: ключи как длинные флаги, незаданные - None
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct Layer {
+    output: Option<PathBuf>,
+    styles: Option<PathBuf>,
+    recursive: Option<bool>,
+    format: Option<String>,
+    keep_structure: Option<bool>,
+    zip_output: Option<bool>,
+    name_template: Option<String>,
+    transliterate: Option<bool>,
+    on_exists: Option<String>,
+    verify: Option<bool>,
+    trash: Option<PathBuf>,
+    jobs: Option<usize>,
+    split_level: Option<u8>,
+    max_file_size: Option<usize>,
+    min_file_size: Option<usize>,
+    footnotes: Option<String>,
+    max_image_size: Option<usize>,
+    epub_version: Option<EpubVersion>,
+    quiet: Option<bool>,
+    verbose: Option<bool>,
+    metadata_map: Option<PathBuf>,
+    no_sidecars: Option<bool>,
+    title: Option<String>,
+    author: Option<Vec<String>>,
+    language: Option<String>,
+    series: Option<String>,
+    series_index: Option<String>,
+    genre: Option<Vec<String>>,
+    annotation: Option<Vec<String>>
+}
+
+impl Layer {
+    /// Поля other поверх своих
+    fn merge(self, other: Layer) -> Layer {
+        Layer {
+            output: other.output.or(self.output),
+            styles: other.styles.or(self.styles),
+            recursive: other.recursive.or(self.recursive),
+            format: other.format.or(self.format),
+            keep_structure: other.keep_structure.or(self.keep_structure),
+            zip_output: other.zip_output.or(self.zip_output),
+            name_template: other.name_template.or(self.name_template),
+            transliterate: other.transliterate.or(self.transliterate),
+            on_exists: other.on_exists.or(self.on_exists),
+            verify: other.verify.or(self.verify),
+            trash: other.trash.or(self.trash),
+            jobs: other.jobs.or(self.jobs),
+            split_level: other.split_level.or(self.split_level),
+            max_file_size: other.max_file_size.or(self.max_file_size),
+            min_file_size: other.min_file_size.or(self.min_file_size),
+            footnotes: other.footnotes.or(self.footnotes),
+            max_image_size: other.max_image_size.or(self.max_image_size),
+            epub_version: other.epub_version.or(self.epub_version),
+            quiet: other.quiet.or(self.quiet),
+            verbose: other.verbose.or(self.verbose),
+            metadata_map: other.metadata_map.or(self.metadata_map),
+            no_sidecars: other.no_sidecars.or(self.no_sidecars),
+            title: other.title.or(self.title),
+            author: other.author.or(self.author),
+            language: other.language.or(self.language),
+            series: other.series.or(self.series),
+            series_index: other.series_index.or(self.series_index),
+            genre: other.genre.or(self.genre),
+            annotation: other.annotation.or(self.annotation)
+        }
+    }
+
+    /// Пути в конфиге - от его папки, ~/ - домашняя папка
+    fn resolve_paths(&mut self, folder: &Path) {
+        let resolve = |path: &mut Option<PathBuf>| {
+            if let Some(p) = path.take() {
+                *path = Some(match (p.strip_prefix("~"), home_dir()) {
+                    (Ok(rest), Some(home)) => home.join(rest),
+                    _ => folder.join(p)
+                })
+            }
+        };
+        resolve(&mut self.output);
+        resolve(&mut self.styles);
+        resolve(&mut self.trash);
+        resolve(&mut self.metadata_map);
+    }
+}
+
+
+pub fn home_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .filter(|h| !h.is_empty())
+        .map(PathBuf::from)
+}
+
+/// $XDG_CONFIG_HOME/fb2epub/config.toml, без XDG_CONFIG_HOME - ~/.config,
+/// на Windows - %APPDATA%
+fn default_path() -> Option<PathBuf> {
+    let folder = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|f| !f.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("APPDATA").filter(|f| !f.is_empty()).map(PathBuf::from))
+        .or_else(|| Some(home_dir()?.join(".config")))?;
+
+    Some(folder.join("fb2epub").join("config.toml"))
+}
+
+/// Общие настройки конфига и поверх них настройки пресета
+fn read(path: &Path, preset: Option<&str>) -> Result<Layer, Box<dyn Error>> {
+    let mut table: toml::Table = toml::from_str(&fs::read_to_string(path)?)?;
+    let presets: HashMap<String, toml::Table> = match table.remove("preset") {
+        Some(presets) => presets.try_into()?,
+        None => HashMap::new()
+    };
+    let folder = path.parent().unwrap_or(Path::new("."));
+
+    let mut layer: Layer = table.try_into()?;
+    layer.resolve_paths(folder);
+    let preset = match preset {
+        Some(name) => presets.get(name)
+            .ok_or(format!("There's no preset {name}"))?
+            .clone(),
+        None => return Ok(layer)
+    };
+    let mut preset: Layer = preset.try_into()?;
+    preset.resolve_paths(folder);
+
+    Ok(layer.merge(preset))
+}
+
+/// Версия EPUB в конфиге - число или строка: epub-version = 2, epub-version = "3.0"
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum EpubVersion {
+    Text(String),
+    Integer(i64),
+    Float(f64)
+}
+
+impl EpubVersion {
+    fn parse(self) -> Result<fb2epub::EpubVersion, String> {
+        match self {
+            EpubVersion::Text(s) => s.parse(),
+            EpubVersion::Integer(i) => i.to_string().parse(),
+            EpubVersion::Float(f) => f.to_string().parse()
+        }
+    }
+}
+
+/// Флаг из командной строки: --flag - Some(true), --no-flag - Some(false)
+fn flag(on: bool, off: bool) -> Option<bool> {
+    if on {Some(true)} else if off {Some(false)} else {None}
+}
+
+/// Заполняет незаданные в командной строке настройки из конфига: --config
+/// или файла по умолчанию, если он есть. Флаги-переключатели из конфига
+/// выключаются через --no-flag
+pub fn apply(args: &mut RunArgs) -> Result<(), Box<dyn Error>> {
+    let path = match &args.config {
+        Some(path) => path.clone(),
+        None => match default_path() {
+            Some(path) if path.is_file() => path,
+            _ if args.preset.is_some() => return Err("There's no config file for --preset".into()),
+            _ => return Ok(())
+        }
+    };
+    let layer = read(&path, args.preset.as_deref())
+        .map_err(|err| format!("{:#?}: {err}", path))?;
+
+    let format = match layer.format {
+        Some(f) => Some(f.parse::<fb2epub::Format>()?),
+        None => None
+    };
+    let on_exists = match layer.on_exists {
+        Some(o) => Some(o.parse::<fb2epub::OnExists>()?),
+        None => None
+    };
+    let footnotes = match layer.footnotes {
+        Some(f) => Some(f.parse::<fb2epub::Footnotes>()?),
+        None => None
+    };
+    let epub_version = match layer.epub_version {
+        Some(v) => Some(v.parse()?),
+        None => None
+    };
+
+    args.output = args.output.take().or(layer.output.map(|o| o.to_string_lossy().to_string()));
+    args.styles = args.styles.take().or(layer.styles.map(|s| s.to_string_lossy().to_string()));
+    args.recursive = flag(args.recursive, args.no_recursive).or(layer.recursive).unwrap_or_default();
+    args.format = args.format.or(format);
+    args.keep_structure = flag(args.keep_structure, args.no_keep_structure).or(layer.keep_structure).unwrap_or_default();
+    args.zip_output = flag(args.zip_output, args.no_zip_output).or(layer.zip_output).unwrap_or_default();
+    args.name_template = args.name_template.take().or(layer.name_template);
+    args.transliterate = flag(args.transliterate, args.no_transliterate).or(layer.transliterate).unwrap_or_default();
+    args.on_exists = args.on_exists.or(on_exists);
+    args.verify = flag(args.verify, args.no_verify).or(layer.verify).unwrap_or_default();
+    args.trash = args.trash.take().or(layer.trash);
+    args.jobs = args.jobs.or(layer.jobs);
+    args.split_level = args.split_level.or(layer.split_level);
+    args.max_file_size = args.max_file_size.or(layer.max_file_size);
+    args.min_file_size = args.min_file_size.or(layer.min_file_size);
+    args.footnotes = args.footnotes.or(footnotes);
+    args.max_image_size = args.max_image_size.or(layer.max_image_size);
+    args.epub_version = args.epub_version.or(epub_version);
+    // --quiet и --verbose несовместимы, флаг из командной строки важнее конфига
+    let quiet = flag(args.quiet, args.no_quiet);
+    let verbose = flag(args.verbose, args.no_verbose);
+    args.quiet = quiet.or(verbose.filter(|v| *v).map(|_| false)).or(layer.quiet).unwrap_or_default();
+    args.verbose = verbose.or(layer.verbose).unwrap_or_default() && !args.quiet;
+    args.metadata_map = args.metadata_map.take().or(layer.metadata_map);
+    args.no_sidecars = flag(args.no_sidecars, args.sidecars).or(layer.no_sidecars).unwrap_or_default();
+
+    let meta = &mut args.meta;
+    meta.title = meta.title.take().or(layer.title);
+    meta.author = meta.author.take().or(layer.author);
+    meta.language = meta.language.take().or(layer.language);
+    meta.series = meta.series.take().or(layer.series);
+    meta.series_index = meta.series_index.take().or(layer.series_index);
+    meta.genre = meta.genre.take().or(layer.genre);
+    meta.annotation = meta.annotation.take().or(layer.annotation);
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use clap::Parser;
+
+    use super::apply;
+    use crate::Args;
+
+    fn args(config: &std::path::Path, flags: &[&str]) -> crate::RunArgs {
+        let mut line = vec!["fb2epub", "-i", "book.fb2", "--config", config.to_str().unwrap()];
+        line.extend(flags);
+        let mut args = Args::try_parse_from(line).unwrap().run;
+        apply(&mut args).unwrap();
+        args
+    }
+
+    /// Поздние слои важнее: пресет поверх конфига, командная строка поверх обоих
+    #[test]
+    fn later_layers_override() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("config.toml");
+        fs::write(&config, concat!(
+            "verify = true\nrecursive = true\nquiet = true\nepub-version = 2\nfootnotes = \"links\"\n",
+            "[preset.kobo]\nrecursive = false\nmax-image-size = 500\nepub-version = \"3\"\n"
+        )).unwrap();
+
+        let plain = args(&config, &[]);
+        assert!(plain.verify && plain.recursive && plain.quiet);
+        assert_eq!(plain.epub_version, Some(fb2epub::EpubVersion::V2));
+        assert_eq!(plain.footnotes, Some(fb2epub::Footnotes::Links));
+
+        let kobo = args(&config, &["--preset", "kobo", "--no-verify", "--verbose"]);
+        assert!(!kobo.verify && !kobo.recursive && !kobo.quiet && kobo.verbose);
+        assert_eq!(kobo.max_image_size, Some(500));
+        assert_eq!(kobo.epub_version, Some(fb2epub::EpubVersion::V3));
+
+        let last_wins = args(&config, &["--no-verify", "--verify", "--footnotes", "popup"]);
+        assert!(last_wins.verify);
+        assert_eq!(last_wins.footnotes, Some(fb2epub::Footnotes::Popup));
+    }
+}
@@ -0,0 +1,192 @@
+mod document_builder;
+
+use std::io::{Seek, Write};
+
+use quick_xml::escape::{escape, partial_escape};
+use zip::ZipWriter;
+use zip::write::SimpleFileOptions;
+
+use crate::fb2_parser::BookData;
+use crate::fb2_parser::content_reader::*;
+use crate::docx_creator::document_builder::{DocumentBuilder, SECT_PR};
+
+
+const W_NS: &str = "http://schemas.openxmlformats.org/wordprocessingml/2006/main";
+const R_NS: &str = "http://schemas.openxmlformats.org/officeDocument/2006/relationships";
+const WP_NS: &str = "http://schemas.openxmlformats.org/drawingml/2006/wordprocessingDrawing";
+
+const CONTENT_TYPES: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Types xmlns="http://schemas.openxmlformats.org/package/2006/content-types">
+    <Default Extension="rels" ContentType="application/vnd.openxmlformats-package.relationships+xml"/>
+    <Default Extension="xml" ContentType="application/xml"/>
+    <Default Extension="png" ContentType="image/png"/>
+    <Default Extension="jpeg" ContentType="image/jpeg"/>
+    <Default Extension="gif" ContentType="image/gif"/>
+    <Override PartName="/word/document.xml" ContentType="application/vnd.openxmlformats-officedocument.wordprocessingml.document.main+xml"/>
+    <Override PartName="/word/styles.xml" ContentType="application/vnd.openxmlformats-officedocument.wordprocessingml.styles+xml"/>
+    <Override PartName="/word/settings.xml" ContentType="application/vnd.openxmlformats-officedocument.wordprocessingml.settings+xml"/>
+    <Override PartName="/word/footnotes.xml" ContentType="application/vnd.openxmlformats-officedocument.wordprocessingml.footnotes+xml"/>
+    <Override PartName="/docProps/core.xml" ContentType="application/vnd.openxmlformats-package.core-properties+xml"/>
+</Types>
+"#;
+
+const ROOT_RELS: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships">
+    <Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/officeDocument" Target="word/document.xml"/>
+    <Relationship Id="rId2" Type="http://schemas.openxmlformats.org/package/2006/relationships/metadata/core-properties" Target="docProps/core.xml"/>
+</Relationships>
+"#;
+
+const SETTINGS: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<w:settings xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main">
+    <w:footnotePr>
+        <w:footnote w:id="-1"/>
+        <w:footnote w:id="0"/>
+    </w:footnotePr>
+    <w:compat>
+        <w:compatSetting w:name="compatibilityMode" w:uri="http://schemas.microsoft.com/office/word" w:val="15"/>
+    </w:compat>
+</w:settings>
+"#;
+
+const SEPARATORS: &str = concat!(
+    r#"<w:footnote w:type="separator" w:id="-1"><w:p><w:pPr><w:spacing w:after="0" w:line="240" w:lineRule="auto"/></w:pPr><w:r><w:separator/></w:r></w:p></w:footnote>"#,
+    r#"<w:footnote w:type="continuationSeparator" w:id="0"><w:p><w:pPr><w:spacing w:after="0" w:line="240" w:lineRule="auto"/></w:pPr><w:r><w:continuationSeparator/></w:r></w:p></w:footnote>"#
+);
+
+
+fn get_styles() -> String {
+    include_str!("../assets/docx_styles.xml").to_string()
+}
+
+fn get_core(data: &BookData) -> String {
+    let meta = &data.meta;
+    let mut s = String::from(concat!(
+        r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#, "\n",
+        r#"<cp:coreProperties xmlns:cp="http://schemas.openxmlformats.org/package/2006/metadata/core-properties" "#,
+        r#"xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:dcterms="http://purl.org/dc/terms/" "#,
+        r#"xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">"#));
+
+    s.push_str(&format!("<dc:title>{}</dc:title>", partial_escape(meta.title.as_str())));
+    if !meta.authors.is_empty() {
+        s.push_str(&format!("<dc:creator>{}</dc:creator>", partial_escape(meta.authors.join("; "))));
+    };
+    if !meta.language.is_empty() {
+        s.push_str(&format!("<dc:language>{}</dc:language>", partial_escape(meta.language.as_str())));
+    };
+    if let Some(annotation) = &meta.annotation {
+        s.push_str(&format!("<dc:description>{}</dc:description>", partial_escape(annotation.join("\n"))));
+    };
+    if !meta.genres.is_empty() {
+        s.push_str(&format!("<cp:keywords>{}</cp:keywords>", partial_escape(meta.genres.join(", "))));
+    };
+    s.push_str("</cp:coreProperties>\n");
+
+    s
+}
+
+
+/// Создаёт docx: заголовки секций в стилях Heading 1-6 по уровню,
+/// эпиграфы, цитаты и стихи в своих стилях, примечания в виде сносок
+pub fn write_docx<W: Write + Seek>(
+    data: &BookData,
+    writer: W,
+    suspend_error_messages: bool
+) -> Result<(), Box<dyn std::error::Error>> {
+    let is_notes = |name: &Option<String>| matches!(name.as_deref(), Some("notes") | Some("comments"));
+    let mut builder = DocumentBuilder::new(data, suspend_error_messages);
+    let mut body = String::new();
+
+    // Обложка и название, если у тела книги нет своего заголовка
+    if let Some(cover) = &data.meta.cover {
+        body.push_str(&builder.unwrap_image(&Some(cover.clone())));
+    };
+    let has_title = data.content.first()
+        .is_some_and(|s| s.level == 0 && !s.title.is_empty() && !is_notes(&s.file_name));
+    if !has_title && !data.meta.title.is_empty() {
+        let title = vec![Paragraph::Text(vec![TextBlock {
+            text: data.meta.title.clone(),
+            strong: false,
+            emphasis: false,
+            strikethrough: false,
+            code: false,
+            sup: false,
+            sub: false,
+            link: None
+        }])];
+        body.push_str(&builder.unwrap_title(&title, "Title"));
+    };
+
+    for section in data.content.iter().filter(|s| !is_notes(&s.file_name)) {
+        body.push_str(&builder.unwrap_section(section));
+    };
+
+    // Примечания, на которые не нашлось ссылок, идут в конец книги
+    for section in data.content.iter().filter(|s| is_notes(&s.file_name)) {
+        let rest: Vec<&Paragraph> = section.paragraphs.iter()
+            .filter(|p| !matches!(p, Paragraph::Note(n) if builder.is_used_note(&n.id)))
+            .collect();
+        if rest.is_empty() {continue}
+
+        body.push_str(&builder.unwrap_title(&section.title, "Heading1"));
+        for p in rest {
+            body.push_str(&builder.unwrap_paragraph(p, None));
+        };
+    };
+
+
+    let document = format!(concat!(
+        r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#, "\n",
+        r#"<w:document xmlns:w="{}" xmlns:r="{}" xmlns:wp="{}"><w:body>{}{}</w:body></w:document>"#, "\n"),
+        W_NS, R_NS, WP_NS, body, SECT_PR);
+
+    let footnotes = format!(concat!(
+        r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#, "\n",
+        r#"<w:footnotes xmlns:w="{}" xmlns:r="{}">{}{}</w:footnotes>"#, "\n"),
+        W_NS, R_NS, SEPARATORS, builder.footnotes.concat());
+
+    let mut rels = String::from(concat!(
+        r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#, "\n",
+        r#"<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships">"#, "\n"));
+    for (id, rel_type, target) in [("rId1", "styles", "styles.xml"), ("rId2", "settings", "settings.xml"), ("rId3", "footnotes", "footnotes.xml")] {
+        rels.push_str(&format!("    <Relationship Id=\"{id}\" Type=\"{R_NS}/{rel_type}\" Target=\"{target}\"/>\n"));
+    };
+    for rel in &builder.relations {
+        rels.push_str(&format!("    <Relationship Id=\"{}\" Type=\"{R_NS}/{}\" Target=\"{}\"{}/>\n",
+            rel.id,
+            rel.rel_type,
+            escape(rel.target.as_str()),
+            if rel.external {" TargetMode=\"External\""} else {""}));
+    };
+    rels.push_str("</Relationships>\n");
+
+
+    let mut zip = ZipWriter::new(writer);
+    let options = SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated);
+
+    let core = get_core(data);
+    let styles = get_styles();
+    let parts: [(&str, &str); 8] = [
+        ("[Content_Types].xml", CONTENT_TYPES),
+        ("_rels/.rels", ROOT_RELS),
+        ("docProps/core.xml", &core),
+        ("word/document.xml", &document),
+        ("word/styles.xml", &styles),
+        ("word/settings.xml", SETTINGS),
+        ("word/footnotes.xml", &footnotes),
+        ("word/_rels/document.xml.rels", &rels)
+    ];
+    for (name, content) in parts {
+        zip.start_file(name, options)?;
+        zip.write_all(content.as_bytes())?;
+    };
+
+    for (name, bytes) in &builder.media {
+        zip.start_file(format!("word/{name}"), options)?;
+        zip.write_all(bytes)?;
+    };
+
+    zip.finish()?;
+    Ok(())
+}
@@ -0,0 +1,481 @@
+use std::collections::HashMap;
+
+use base64::{Engine as _, engine::general_purpose};
+use quick_xml::escape::{escape, partial_escape};
+
+use crate::fb2_parser::{BookData, Image, Section};
+use crate::fb2_parser::content_reader::*;
+
+
+// Ширина и высота текста на странице A4 с полями из sectPr, в EMU (1 twip = 635 EMU)
+const MAX_WIDTH: u64 = 9355 * 635;
+const MAX_HEIGHT: u64 = 14570 * 635;
+// 1 px при 96 dpi
+const EMU_PER_PX: u64 = 9525;
+
+pub const SECT_PR: &str = r#"<w:sectPr><w:pgSz w:w="11906" w:h="16838"/><w:pgMar w:top="1134" w:right="850" w:bottom="1134" w:left="1701" w:header="708" w:footer="708" w:gutter="0"/></w:sectPr>"#;
+
+
+pub struct Relation {
+    pub id: String,
+    pub rel_type: &'static str,
+    pub target: String,
+    pub external: bool
+}
+
+pub struct DocumentBuilder<'a> {
+    images: &'a HashMap<String, Image>,
+    notes: HashMap<String, &'a Section>,    // #id -> примечание из тела notes
+
+    pub relations: Vec<Relation>,
+    pub media: Vec<(String, Vec<u8>)>,      // путь внутри word/ и содержимое
+    pub footnotes: Vec<String>,
+    used_notes: Vec<String>,
+
+    image_rels: HashMap<String, Option<(String, u64, u64)>>,
+    bookmarks: Vec<String>,
+    bookmark_counter: usize,
+    drawing_counter: usize,
+
+    in_footnote: bool,
+    footnote_ref: bool,
+    suspend_error_messages: bool
+}
+
+
+/// Размер картинки в пикселях из заголовка PNG, GIF или JPEG
+fn image_size(bytes: &[u8]) -> Option<(u64, u64)> {
+    let be16 = |i: usize| -> Option<u64> {
+        Some(u16::from_be_bytes([*bytes.get(i)?, *bytes.get(i + 1)?]) as u64)
+    };
+
+    if bytes.starts_with(b"\x89PNG") {
+        let w = u32::from_be_bytes(bytes.get(16..20)?.try_into().ok()?) as u64;
+        let h = u32::from_be_bytes(bytes.get(20..24)?.try_into().ok()?) as u64;
+        return Some((w, h))
+    };
+
+    if bytes.starts_with(b"GIF") {
+        let w = u16::from_le_bytes([*bytes.get(6)?, *bytes.get(7)?]) as u64;
+        let h = u16::from_le_bytes([*bytes.get(8)?, *bytes.get(9)?]) as u64;
+        return Some((w, h))
+    };
+
+    if bytes.starts_with(&[0xFF, 0xD8]) {
+        let mut i = 2;
+        while i + 9 < bytes.len() {
+            if bytes[i] != 0xFF {
+                i += 1;
+                continue
+            };
+            let marker = bytes[i + 1];
+            // SOF0..SOF15, кроме DHT, JPG и DAC
+            if (0xC0..=0xCF).contains(&marker) && !matches!(marker, 0xC4 | 0xC8 | 0xCC) {
+                return Some((be16(i + 7)?, be16(i + 5)?))
+            };
+            i += 2 + be16(i + 2)? as usize;
+        }
+    };
+
+    None
+}
+
+fn media_extension(content_type: &str) -> Option<&'static str> {
+    match content_type {
+        "image/png" => Some("png"),
+        "image/jpeg" | "image/jpg" => Some("jpeg"),
+        "image/gif" => Some("gif"),
+        _ => None
+    }
+}
+
+fn run_text(text: &str) -> String {
+    let text: String = text.chars()
+        .map(|c| if c.is_whitespace() {' '} else {c})
+        .collect();
+
+    format!("<w:t xml:space=\"preserve\">{}</w:t>", partial_escape(text.as_str()))
+}
+
+fn run(block: &TextBlock, char_style: Option<&str>) -> String {
+    let mut props = String::new();
+    if let Some(s) = char_style {
+        props.push_str(&format!("<w:rStyle w:val=\"{s}\"/>"))
+    } else if block.code {
+        props.push_str("<w:rStyle w:val=\"Code\"/>")
+    };
+    if block.strong {props.push_str("<w:b/>")}
+    if block.emphasis {props.push_str("<w:i/>")}
+    if block.strikethrough {props.push_str("<w:strike/>")}
+    if block.sup {props.push_str("<w:vertAlign w:val=\"superscript\"/>")}
+    else if block.sub {props.push_str("<w:vertAlign w:val=\"subscript\"/>")}
+
+    if props.is_empty() {
+        format!("<w:r>{}</w:r>", run_text(&block.text))
+    } else {
+        format!("<w:r><w:rPr>{props}</w:rPr>{}</w:r>", run_text(&block.text))
+    }
+}
+
+
+impl<'a> DocumentBuilder<'a> {
+    pub fn new(data: &'a BookData, suspend_error_messages: bool) -> DocumentBuilder<'a> {
+        let mut notes = HashMap::new();
+        let notes_bodies = data.content.iter()
+            .filter(|s| matches!(s.file_name.as_deref(), Some("notes") | Some("comments")));
+        for body in notes_bodies {
+            for p in &body.paragraphs {
+                if let Paragraph::Note(note) = p
+                    && let Some(id) = &note.id {
+                    notes.insert(format!("#{id}"), note);
+                }
+            }
+        };
+
+        DocumentBuilder {
+            images: &data.images,
+            notes,
+            relations: Vec::new(),
+            media: Vec::new(),
+            footnotes: Vec::new(),
+            used_notes: Vec::new(),
+            image_rels: HashMap::new(),
+            bookmarks: Vec::new(),
+            bookmark_counter: 0,
+            drawing_counter: 0,
+            in_footnote: false,
+            footnote_ref: false,
+            suspend_error_messages
+        }
+    }
+
+    pub fn is_used_note(&self, id: &Option<String>) -> bool {
+        match id {
+            Some(id) => self.used_notes.contains(&format!("#{id}")),
+            None => false
+        }
+    }
+
+    fn add_relation(&mut self, rel_type: &'static str, target: String, external: bool) -> String {
+        // rId1-rId3 заняты под styles, settings и footnotes
+        let id = format!("rId{}", self.relations.len() + 4);
+        self.relations.push(Relation {id: id.clone(), rel_type, target, external});
+
+        id
+    }
+
+    fn paragraph(&mut self, style: Option<&str>, content: &str) -> String {
+        let style = if self.in_footnote {Some("FootnoteText")} else {style};
+        let mut s = String::from("<w:p>");
+        if let Some(style) = style {
+            s.push_str(&format!("<w:pPr><w:pStyle w:val=\"{style}\"/></w:pPr>"))
+        };
+
+        if self.in_footnote {
+            if self.footnote_ref {
+                self.footnote_ref = false;
+                s.push_str("<w:r><w:rPr><w:rStyle w:val=\"FootnoteReference\"/></w:rPr><w:footnoteRef/></w:r>");
+                s.push_str("<w:r><w:t xml:space=\"preserve\"> </w:t></w:r>");
+            }
+        } else {
+            // закладки для внутренних ссылок ставятся в начало ближайшего абзаца
+            for name in self.bookmarks.drain(..) {
+                s.push_str(&format!("<w:bookmarkStart w:id=\"{0}\" w:name=\"{1}\"/><w:bookmarkEnd w:id=\"{0}\"/>",
+                    self.bookmark_counter, escape(name.as_str())));
+                self.bookmark_counter += 1;
+            }
+        };
+
+        s.push_str(content);
+        s.push_str("</w:p>");
+        s
+    }
+
+    fn bookmark(&mut self, id: &Option<String>) {
+        if let Some(id) = id
+            && !id.is_empty() && !self.in_footnote {
+            self.bookmarks.push(id.clone())
+        }
+    }
+
+    fn footnote(&mut self, link: &str) -> Option<String> {
+        let note = *self.notes.get(link)?;
+        if self.used_notes.iter().any(|l| l == link) {
+            return None
+        };
+        self.used_notes.push(link.to_string());
+
+        // id 0 и -1 заняты разделителями
+        let id = self.footnotes.len() + 1;
+        self.in_footnote = true;
+        self.footnote_ref = true;
+        let mut content = String::new();
+        for p in &note.paragraphs {
+            content.push_str(&self.unwrap_paragraph(p, None))
+        };
+        if self.footnote_ref {
+            content.push_str(&self.paragraph(None, ""))
+        };
+        self.in_footnote = false;
+
+        self.footnotes.push(format!("<w:footnote w:id=\"{id}\">{content}</w:footnote>"));
+
+        Some(format!("<w:r><w:rPr><w:rStyle w:val=\"FootnoteReference\"/></w:rPr><w:footnoteReference w:id=\"{id}\"/></w:r>"))
+    }
+
+    pub fn runs(&mut self, blocks: &[TextBlock]) -> String {
+        let mut s = String::new();
+        let mut i = 0;
+        while i < blocks.len() {
+            let link = match &blocks[i].link {
+                Some(l) => l.link.clone(),
+                None => {
+                    s.push_str(&run(&blocks[i], None));
+                    i += 1;
+                    continue
+                }
+            };
+
+            // соседние блоки с одной ссылкой - одна ссылка
+            let mut end = i + 1;
+            while end < blocks.len() && blocks[end].link.as_ref().map(|l| &l.link) == Some(&link) {
+                end += 1
+            };
+            let group = &blocks[i..end];
+            i = end;
+
+            if self.in_footnote {
+                // в сносках ссылки остаются простым текстом
+                for block in group {
+                    s.push_str(&run(block, None))
+                };
+                continue
+            };
+
+            if self.notes.contains_key(&link) {
+                match self.footnote(&link) {
+                    Some(r) => s.push_str(&r),
+                    None => for block in group {
+                        s.push_str(&run(block, Some("FootnoteReference")))
+                    }
+                };
+                continue
+            };
+
+            let open = if let Some(anchor) = link.strip_prefix('#') {
+                format!("<w:hyperlink w:anchor=\"{}\" w:history=\"1\">", escape(anchor))
+            } else {
+                let id = self.add_relation("hyperlink", link.clone(), true);
+                format!("<w:hyperlink r:id=\"{id}\" w:history=\"1\">")
+            };
+            s.push_str(&open);
+            for block in group {
+                s.push_str(&run(block, Some("Hyperlink")))
+            };
+            s.push_str("</w:hyperlink>");
+        };
+
+        s
+    }
+
+    fn image_run(&mut self, href: &str) -> Option<String> {
+        if !self.image_rels.contains_key(href) {
+            let rel = self.add_image(href);
+            self.image_rels.insert(href.to_string(), rel);
+        };
+        let (rel_id, cx, cy) = self.image_rels.get(href)?.clone()?;
+
+        self.drawing_counter += 1;
+        let n = self.drawing_counter;
+        Some(format!(concat!(
+            "<w:r><w:drawing><wp:inline distT=\"0\" distB=\"0\" distL=\"0\" distR=\"0\">",
+            "<wp:extent cx=\"{cx}\" cy=\"{cy}\"/><wp:docPr id=\"{n}\" name=\"Picture {n}\"/>",
+            "<a:graphic xmlns:a=\"http://schemas.openxmlformats.org/drawingml/2006/main\">",
+            "<a:graphicData uri=\"http://schemas.openxmlformats.org/drawingml/2006/picture\">",
+            "<pic:pic xmlns:pic=\"http://schemas.openxmlformats.org/drawingml/2006/picture\">",
+            "<pic:nvPicPr><pic:cNvPr id=\"{n}\" name=\"Picture {n}\"/><pic:cNvPicPr/></pic:nvPicPr>",
+            "<pic:blipFill><a:blip r:embed=\"{rel_id}\"/><a:stretch><a:fillRect/></a:stretch></pic:blipFill>",
+            "<pic:spPr><a:xfrm><a:off x=\"0\" y=\"0\"/><a:ext cx=\"{cx}\" cy=\"{cy}\"/></a:xfrm>",
+            "<a:prstGeom prst=\"rect\"><a:avLst/></a:prstGeom></pic:spPr>",
+            "</pic:pic></a:graphicData></a:graphic></wp:inline></w:drawing></w:r>"),
+            cx = cx, cy = cy, n = n, rel_id = rel_id))
+    }
+
+    fn add_image(&mut self, href: &str) -> Option<(String, u64, u64)> {
+        let image = self.images.get(href)?;
+        let extension = media_extension(&image.content_type)?;
+        let bytes = match general_purpose::STANDARD.decode(image.binary.trim()) {
+            Ok(b) => b,
+            Err(err) => {
+                if !self.suspend_error_messages {
+                    eprintln!("Image decoder error: {}", err)
+                };
+                return None
+            }
+        };
+
+        let (w, h) = image_size(&bytes).unwrap_or((400, 300));
+        let (mut cx, mut cy) = (w.max(1) * EMU_PER_PX, h.max(1) * EMU_PER_PX);
+        if cx > MAX_WIDTH {
+            cy = cy * MAX_WIDTH / cx;
+            cx = MAX_WIDTH;
+        };
+        if cy > MAX_HEIGHT {
+            cx = cx * MAX_HEIGHT / cy;
+            cy = MAX_HEIGHT;
+        };
+
+        let name = format!("media/image{}.{extension}", self.media.len() + 1);
+        self.media.push((name.clone(), bytes));
+        let id = self.add_relation("image", name, false);
+
+        Some((id, cx, cy))
+    }
+
+    pub fn unwrap_image(&mut self, href: &Option<String>) -> String {
+        // картинки в сносках не поддерживаются
+        if self.in_footnote {
+            return String::new()
+        };
+        match href.as_ref().and_then(|h| self.image_run(h)) {
+            Some(r) => self.paragraph(Some("Image"), &r),
+            None => String::new()
+        }
+    }
+
+    /// Заголовок одним абзацем, строки разделены переносом
+    pub fn unwrap_title(&mut self, title: &[Paragraph], style: &str) -> String {
+        let mut content = String::new();
+        for p in title {
+            let blocks = match p {
+                Paragraph::Text(b) | Paragraph::V(b) |
+                Paragraph::Subtitle(b) | Paragraph::TextAuthor(b) => b,
+                _ => continue
+            };
+            if !content.is_empty() {
+                content.push_str("<w:r><w:br/></w:r>")
+            };
+            content.push_str(&self.runs(blocks));
+        };
+
+        if content.is_empty() {
+            return String::new()
+        };
+        self.paragraph(Some(style), &content)
+    }
+
+    fn unwrap_container(&mut self, section: &Section, style: &str) -> String {
+        self.bookmark(&section.id);
+        let mut s = self.unwrap_title(&section.title, "Subtitle");
+        for p in &section.paragraphs {
+            s.push_str(&self.unwrap_paragraph(p, Some(style)))
+        };
+
+        s
+    }
+
+    fn unwrap_poem(&mut self, poem: &Poem) -> String {
+        self.bookmark(&poem.id);
+        let mut s = String::new();
+        for p in &poem.title {
+            if let Paragraph::Text(b) = p {
+                let runs = self.runs(b);
+                s.push_str(&self.paragraph(Some("PoemTitle"), &runs))
+            }
+        };
+
+        for p in &poem.paragraphs {
+            if let Paragraph::Epigraph(e) = p {
+                s.push_str(&self.unwrap_container(e, "Epigraph"))
+            }
+        };
+
+        for (i, stanza) in poem.stanzas.iter().enumerate() {
+            if i != 0 {
+                s.push_str(&self.paragraph(Some("Poem"), ""))
+            };
+            self.bookmark(&stanza.id);
+            for p in &stanza.title {
+                if let Paragraph::Text(b) = p {
+                    let runs = self.runs(b);
+                    s.push_str(&self.paragraph(Some("PoemTitle"), &runs))
+                }
+            };
+            for v in &stanza.v {
+                match v {
+                    Paragraph::Subtitle(b) => {
+                        let runs = self.runs(b);
+                        s.push_str(&self.paragraph(Some("Subtitle"), &runs))
+                    },
+                    Paragraph::V(b) | Paragraph::Text(b) => {
+                        let runs = self.runs(b);
+                        s.push_str(&self.paragraph(Some("Poem"), &runs))
+                    },
+                    _ => {}
+                }
+            };
+        };
+
+        for p in &poem.paragraphs {
+            if let Paragraph::TextAuthor(b) | Paragraph::Text(b) = p {
+                let runs = self.runs(b);
+                s.push_str(&self.paragraph(Some("TextAuthor"), &runs))
+            }
+        };
+
+        if !poem.date.is_empty() {
+            let runs = self.runs(&poem.date);
+            s.push_str(&self.paragraph(Some("TextAuthor"), &runs))
+        };
+
+        s
+    }
+
+    /// style - стиль контейнера (эпиграф, цитата), для обычного текста None
+    pub fn unwrap_paragraph(&mut self, paragraph: &Paragraph, style: Option<&str>) -> String {
+        match paragraph {
+            Paragraph::Text(b) | Paragraph::V(b) => {
+                let runs = self.runs(b);
+                self.paragraph(style, &runs)
+            },
+            Paragraph::TextAuthor(b) => {
+                let runs = self.runs(b);
+                self.paragraph(Some("TextAuthor"), &runs)
+            },
+            Paragraph::Subtitle(b) => {
+                let runs = self.runs(b);
+                self.paragraph(Some("Subtitle"), &runs)
+            },
+            Paragraph::EmptyLine => self.paragraph(style, ""),
+            Paragraph::Image(href) => self.unwrap_image(href),
+            Paragraph::Epigraph(s) => self.unwrap_container(s, "Epigraph"),
+            Paragraph::Cite(s) => self.unwrap_container(s, "Cite"),
+            Paragraph::Annotation(s) => self.unwrap_container(s, "Annotation"),
+            Paragraph::Poem(poem) => self.unwrap_poem(poem),
+            Paragraph::Note(s) => {
+                self.bookmark(&s.id);
+                let mut content = self.unwrap_title(&s.title, "Heading2");
+                for p in &s.paragraphs {
+                    content.push_str(&self.unwrap_paragraph(p, style))
+                };
+                content
+            }
+        }
+    }
+
+    /// Секция: заголовок по уровню (Title для тела книги, дальше Heading 1-6) и содержимое
+    pub fn unwrap_section(&mut self, section: &Section) -> String {
+        self.bookmark(&section.id);
+        let style = match section.level {
+            0 => String::from("Title"),
+            l => format!("Heading{}", l.min(6))
+        };
+
+        let mut s = self.unwrap_title(&section.title, &style);
+        for p in &section.paragraphs {
+            s.push_str(&self.unwrap_paragraph(p, None))
+        };
+
+        s
+    }
+}
@@ -1,18 +1,26 @@
 mod html_builder;
+mod layout;
 
-use std::fs::{self, File};
-use std::path::{Path, PathBuf};
+use std::fs;
+use std::io::{Cursor, Read, Write};
+use std::path::Path;
 
 use epub_builder::EpubBuilder;
 use epub_builder::EpubContent;
+use epub_builder::Toc;
+use epub_builder::TocElement;
 use epub_builder::ZipLibrary;
-use epub_builder::Result;
+use zip::{ZipArchive, ZipWriter};
+use zip::write::SimpleFileOptions;
 
 use base64::{Engine as _, engine::general_purpose};
 
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
 
 use crate::fb2_parser;
-use crate::epub_creator::html_builder::html_builder;
+use crate::{EpubVersion, Footnotes, Layout, Options};
+use crate::epub_creator::html_builder::{html_builder, plain_notes};
 use crate::fb2_parser::content_reader::*;
 use crate::fb2_parser::get_counter_str;
 
@@ -54,21 +62,31 @@ fn get_css_from_file(s_path: &Path) -> std::io::Result<Vec<u8>> {
     fs::read(s_path)
 }
 
-pub fn create_epub(
+pub fn write_epub<W: Write>(
     data: &mut fb2_parser::BookData,
-    output: &Path,
+    writer: &mut W,
     styles_path: Option<&Path>,
-    suspend_error_messages: bool
-) -> Result<PathBuf> {
+    suspend_error_messages: bool,
+    options: &Options
+) -> Result<()> {
     let mut builder = EpubBuilder::new(ZipLibrary::new()?)?;
     let cover_key = &data.meta.cover;
     
+    // Раскладка секций по файлам, по умолчанию файлы и ссылки те же, что дал парсер
+    let files = layout::layout(&data.content, &options.layout);
+    if options.layout != Layout::default() {
+        data.link_map.extend(layout::files_link_map(&files))
+    };
+    
     
     // Добавление метаданных
     {
         let metadata = &data.meta;
         builder
-            .epub_version(epub_builder::EpubVersion::V33)
+            .epub_version(match options.epub_version {
+                EpubVersion::V2 => epub_builder::EpubVersion::V20,
+                EpubVersion::V3 => epub_builder::EpubVersion::V33
+            })
             .metadata("generator", "fb2epub")?
             .metadata("lang", &metadata.language)?
             .metadata("title", &metadata.title)?;
@@ -88,7 +106,21 @@ pub fn create_epub(
             };
             builder.metadata("description", description)?;
         };
-        if let Some(seq) = &metadata.sequence {
+        // в EPUB 2 серия - как у calibre
+        if let Some(seq) = &metadata.sequence && options.epub_version == EpubVersion::V2 {
+            if !seq.name.is_empty() {
+                builder.add_metadata_opf(Box::new(epub_builder::MetadataOpf {
+                    name: String::from("calibre:series"),
+                    content: seq.name.clone()
+                }));
+                if !seq.number.is_empty() {
+                    builder.add_metadata_opf(Box::new(epub_builder::MetadataOpf {
+                        name: String::from("calibre:series_index"),
+                        content: seq.number.clone()
+                    }));
+                }
+            };
+        } else if let Some(seq) = &metadata.sequence {
             if !seq.name.is_empty() {
                 builder.add_metadata_opf(
                     Box::new(epub_builder::MetadataOpfV3 {
@@ -182,6 +214,10 @@ pub fn create_epub(
                 continue
             }
         };
+        if let Some(max) = options.max_image_size && binary.len() > max * 1024 {
+            data.warnings.push(format!("Image {} is bigger than {max} KB, left out", key.trim_start_matches('#')));
+            continue
+        };
         
         builder
             .add_resource(
@@ -195,28 +231,45 @@ pub fn create_epub(
     }};
     
     
-    // Добавление текстовых документов
-    let mut counter = 1;
-    for section in &data.content {
+    // Добавление текстовых документов. В оглавлении epub-builder пункты одного файла
+    // могут быть только вложены в первый, поэтому нужное оглавление строится отдельно
+    let mut added = Toc::new();
+    let mut wanted = Toc::new();
+    for file in &files {
         let prefix = "text/".to_string();
         let suffix = ".xhtml";
-        let file_name = if let Some(name) = &section.file_name {name.clone()}
-        else {format!("section_{}", get_counter_str(counter))};
-        counter += 1;
+        let url = prefix + &file.name + suffix;
         
+        let section = &file.sections[0];
         let title = unwrap_title(&section.title);
         let level: i32 = (section.level + 1).into();
-        let html_content = html_builder(&section, &data.link_map, &title);
-        if title.is_empty() {
-            builder.add_content(EpubContent::new(prefix + &file_name + suffix, html_content.as_bytes()))?;
-        } else {
-            builder.add_content(
-                EpubContent::new(prefix + &file_name + suffix, html_content.as_bytes())
-                    .title(title)
-                    .level(level)
-            )?;
-        }
-        
+        let mut html_content = html_builder(&file.sections, &data.link_map, &title);
+        if options.footnotes == Footnotes::Links || options.epub_version == EpubVersion::V2 {
+            html_content = plain_notes(html_content)
+        };
+        let mut content = EpubContent::new(url.clone(), html_content.as_bytes());
+        // каждая секция с заголовком - пункт на своём уровне
+        for (j, section) in file.sections.iter().enumerate() {
+            let title = unwrap_title(&section.title);
+            if title.is_empty() {continue}
+            let href = match &section.id {
+                Some(id) if j > 0 => format!("{url}#{id}"),
+                _ => url.clone()
+            };
+            wanted.add(TocElement::new(href, title).level((section.level + 1).into()));
+        };
+        if !title.is_empty() {
+            content = content.title(title).level(level);
+            // секции глубже первой, до следующей соседней, - в оглавление внутри неё
+            for section in file.sections[1..].iter().take_while(|s| s.level > section.level) {
+                let title = unwrap_title(&section.title);
+                if let Some(id) = &section.id && !title.is_empty() {
+                    content.toc.add(TocElement::new(format!("{url}#{id}"), title).level((section.level + 1).into()))
+                }
+            };
+            added.add(content.toc.clone());
+        };
+        builder.add_content(content)?;
     };
     
     // Добавление стилей
@@ -227,9 +280,78 @@ pub fn create_epub(
     };
     
     
-    let mut new_book = File::create(output)?;
-    builder.generate(&mut new_book)?;
+    let mut epub: Vec<u8> = Vec::new();
+    builder.generate(&mut epub)?;
+    if added.render_epub(true) != wanted.render_epub(true) {
+        epub = replace_toc(&epub, &mut added, &mut wanted)?
+    };
+    writer.write_all(&epub)?;
     
     
-    Ok(output.to_path_buf())
+    Ok(())
+}
+
+/// Заменяет в готовой книге оглавление toc.ncx и nav.xhtml: added - то, что построил
+/// epub-builder, wanted - нужное. Остальные файлы копируются как есть
+fn replace_toc(epub: &[u8], added: &mut Toc, wanted: &mut Toc) -> Result<Vec<u8>> {
+    // так же, как их выводит epub-builder
+    let replaces = [
+        (added.render_epub(true), wanted.render_epub(true)),
+        (added.render(true, true), wanted.render(true, true))
+    ];
+    let mut archive = ZipArchive::new(Cursor::new(epub))?;
+    let mut output = ZipWriter::new(Cursor::new(Vec::new()));
+    for i in 0..archive.len() {
+        let name = archive.by_index_raw(i)?.name().to_string();
+        if name != "OEBPS/toc.ncx" && name != "OEBPS/nav.xhtml" {
+            output.raw_copy_file(archive.by_index_raw(i)?)?;
+            continue
+        };
+
+        let mut text = String::new();
+        archive.by_index(i)?.read_to_string(&mut text)?;
+        for (old, new) in &replaces {
+            if !old.is_empty() {
+                text = text.replacen(old.as_str(), new, 1)
+            }
+        };
+        output.start_file(name, SimpleFileOptions::default())?;
+        output.write_all(text.as_bytes())?;
+    };
+
+    Ok(output.finish()?.into_inner())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::write_epub;
+    use crate::{Layout, Options};
+    use crate::fb2_parser::get_data_from_bytes;
+
+    /// Соседние секции в одном файле - соседние пункты оглавления, а не вложенные
+    #[test]
+    fn merged_siblings_in_toc() {
+        let mut data = get_data_from_bytes(include_bytes!("../tests/fixtures/roundtrip.fb2")).unwrap();
+        let start = data.content.iter().position(|s| s.id.as_deref() == Some("ch1")).unwrap();
+        data.content.drain(..start);
+        let options = Options {layout: Layout {min_size: Some(1000), ..Layout::default()}, ..Options::default()};
+        let mut epub: Vec<u8> = Vec::new();
+        write_epub(&mut data, &mut epub, None, true, &options).unwrap();
+
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(epub)).unwrap();
+        for name in ["OEBPS/toc.ncx", "OEBPS/nav.xhtml"] {
+            let mut toc = String::new();
+            archive.by_name(name).unwrap().read_to_string(&mut toc).unwrap();
+            let depth = |href: &str| {
+                let before = &toc[..toc.find(href).unwrap()];
+                before.matches("<navPoint").count() + before.matches("<ol>").count()
+                    - before.matches("</navPoint>").count() - before.matches("</ol>").count()
+            };
+            assert_eq!(depth("#ch2\""), depth("text/notes.xhtml\""), "{name}");
+            assert!(depth("#ch1_2\"") > depth("#ch2\""), "{name}");
+        }
+    }
 }
@@ -1,5 +1,7 @@
 use std::collections::HashMap;
 
+use quick_xml::escape::{escape, partial_escape};
+
 use crate::fb2_parser::Section;
 use crate::fb2_parser::content_reader::*;
 
@@ -12,9 +14,9 @@ fn get_head(head_title: &str, id: &Option<String>) -> String {
 <!DOCTYPE html>
 <html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
 {TAB}<head>
-{TAB}{TAB}<title>{head_title}</title>
+{TAB}{TAB}<title>{}</title>
 {TAB}{TAB}<link href="../stylesheet.css" rel="stylesheet" type="text/css"/>
-{TAB}</head>{}"#, "\n");
+{TAB}</head>{}"#, partial_escape(head_title), "\n");
     
     s.push_str(
         &match id {
@@ -120,15 +122,17 @@ fn push_style_tags(
 
 fn get_link_start(link: &Link, link_map: &HashMap<String, String>) -> String {
     let mut is_note = false;
-    let href = if link.link.starts_with("#") {
+    let href = escape(if link.link.starts_with("#") {
         if let Some(l) = link_map.get(&link.link) {
-            if l.starts_with("comments") || l.starts_with("notes") {
+            // в сборнике примечания книг - b01_notes.xhtml, b02_notes.xhtml...
+            let file = l.split('#').next().unwrap_or_default();
+            if file.ends_with("comments.xhtml") || file.ends_with("notes.xhtml") {
                 is_note = true;
             };
             
             l
         } else {&link.link}
-    } else {&link.link};
+    } else {&link.link});
 
     return match &link.link_type {
         Some(t) if t == "note" => {
@@ -191,7 +195,7 @@ fn unwrap_blocks(blocks: &Vec<TextBlock>, tabs: &str, block_type: &str, link_map
         push_style_tags(&mut left_part, &block, false, &styles_ignore);
 
         s.push_str(&left_part);
-        s.push_str(&block.text);
+        s.push_str(&partial_escape(block.text.as_str()));
         s.push_str(&right_part);
     }
     
@@ -350,14 +354,76 @@ fn unwrap_section(section: &Section, link_map: &HashMap<String, String>, indent:
     return s
 }
 
-pub fn html_builder(section: &Section, link_map: &HashMap<String, String>, title: &str) -> String {
+/// Размер XHTML заголовка и каждого абзаца секции, чтобы раскладывать секции по файлам
+pub fn section_sizes(section: &Section) -> (usize, Vec<usize>) {
+    let link_map = HashMap::new();
+    let title = unwrap_title(section.level, &section.title, 2, &link_map).len();
+    let paragraphs = section.paragraphs.iter()
+        .map(|p| unwrap_paragraph(p, &link_map, 2).len())
+        .collect();
+    
+    (title, paragraphs)
+}
+
+/// Файл из нескольких секций: id первой - у body, остальные секции с id - в div с ним
+pub fn html_builder(sections: &[Section], link_map: &HashMap<String, String>, title: &str) -> String {
     let mut html = String::new();
     let indent = 2;
     
-    html.push_str(&get_head(title, &section.id));
-    html.push_str(&unwrap_section(section, link_map, indent, "section"));
+    html.push_str(&get_head(title, &sections[0].id));
+    html.push_str(&unwrap_section(&sections[0], link_map, indent, "section"));
+    for section in &sections[1..] {
+        if let Some(i) = &section.id {
+            html.push_str(&format!("{TAB}{TAB}<div id=\"{i}\">\n"));
+            html.push_str(&unwrap_section(section, link_map, indent + 1, "section"));
+            html.push_str(&format!("{TAB}{TAB}</div>\n"));
+        } else {
+            html.push_str(&unwrap_section(section, link_map, indent, "section"));
+        }
+    };
     html.push_str(&format!("{TAB}</body>\n</html>"));
     
     // println!("{html}\n\n");
     return html
 }
+
+/// Сноски простыми ссылками: без epub:type и aside. Заменяется только разметка,
+/// в тексте `<` экранирован
+pub fn plain_notes(html: String) -> String {
+    html.replace("<a class=\"reference\" epub:type=\"noteref\" ", "<a class=\"reference\" ")
+        .replace("<aside epub:type=\"footnote\" class=\"note\"", "<div class=\"note\"")
+        .replace("</aside>", "</div>")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{html_builder, plain_notes};
+    use crate::fb2_parser::get_data_from_bytes;
+
+    /// Текст и ссылки экранируются, а сноски получают noteref
+    #[test]
+    fn text_is_escaped() {
+        let data = get_data_from_bytes(include_bytes!("../../tests/fixtures/roundtrip.fb2")).unwrap();
+        let section = data.content.iter().find(|s| s.id.as_deref() == Some("ch1")).unwrap();
+        let html = html_builder(std::slice::from_ref(section), &data.link_map, "Глава 1");
+
+        assert!(html.contains("x &lt; y &amp;&amp; z"));
+        assert!(html.contains(r#"epub:type="noteref" href="notes.xhtml#n1""#));
+        assert!(html.contains(r#"<body id="ch1">"#));
+    }
+
+    /// Простые сноски - без разметки EPUB 3, но с теми же ссылками
+    #[test]
+    fn plain_notes_keep_links() {
+        let data = get_data_from_bytes(include_bytes!("../../tests/fixtures/roundtrip.fb2")).unwrap();
+        let section = data.content.iter().find(|s| s.id.as_deref() == Some("ch1")).unwrap();
+        let html = plain_notes(html_builder(std::slice::from_ref(section), &data.link_map, "Глава 1"));
+        assert!(html.contains(r#"<a class="reference" href="notes.xhtml#n1""#));
+
+        let notes = data.content.iter().find(|s| s.file_name.as_deref() == Some("notes")).unwrap();
+        let html = plain_notes(html_builder(std::slice::from_ref(notes), &data.link_map, "Примечания"));
+        assert!(html.contains(r#"<div class="note" id="n1">"#));
+        assert!(!html.contains("epub:type") && !html.contains("aside"));
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+
+use crate::Layout;
+use crate::anthology::is_continuation;
+use crate::epub_creator::html_builder::section_sizes;
+use crate::fb2_creator::normalizer::{is_notes, link_map};
+use crate::fb2_parser::Section;
+use crate::fb2_parser::get_counter_str;
+
+
+const KB: usize = 1024;
+
+/// Файл текста книги: имя без расширения и секции в нём
+pub struct File {
+    pub name: String,
+    pub sections: Vec<Section>
+}
+
+impl File {
+    fn new(name: String, section: Section) -> File {
+        File {name, sections: vec![section]}
+    }
+
+    fn size(&self) -> usize {
+        self.sections.iter().map(section_size).sum()
+    }
+
+    /// Секция может быть в этом файле, если она не пропадёт из оглавления: секции
+    /// с заголовками попадают в него на своём уровне, но только в файле с заголовком в начале
+    fn can_take(&self, section: &Section) -> bool {
+        let first = &self.sections[0];
+        !is_notes(first) && !is_notes(section)
+            && (section.title.is_empty() || !first.title.is_empty())
+    }
+}
+
+fn section_size(section: &Section) -> usize {
+    let (title, paragraphs) = section_sizes(section);
+    title + paragraphs.iter().sum::<usize>()
+}
+
+/// Делит большую секцию по абзацам, продолжения - без заголовка и id
+fn split_section(section: &Section, max: usize) -> Vec<Section> {
+    let (title, sizes) = section_sizes(section);
+    let mut parts: Vec<Section> = Vec::new();
+    let mut start = 0;
+    let mut size = title;
+    for (i, p_size) in sizes.iter().enumerate() {
+        if i > start && size + p_size > max {
+            parts.push(Section {paragraphs: section.paragraphs[start..i].to_vec(), ..part_head(section, parts.is_empty())});
+            start = i;
+            size = 0;
+        };
+        size += p_size
+    };
+    parts.push(Section {paragraphs: section.paragraphs[start..].to_vec(), ..part_head(section, parts.is_empty())});
+
+    parts
+}
+
+fn part_head(section: &Section, first: bool) -> Section {
+    Section {
+        level: section.level,
+        id: if first {section.id.clone()} else {None},
+        file_name: section.file_name.clone(),
+        title: if first {section.title.clone()} else {Vec::new()},
+        paragraphs: Vec::new()
+    }
+}
+
+/// Делит большой файл на файлы name, name_2, name_3... по секциям, а слишком большие секции - по абзацам
+fn split_file(file: File, max: usize) -> Vec<File> {
+    if is_notes(&file.sections[0]) || file.size() <= max {
+        return vec![file]
+    };
+
+    let mut files: Vec<File> = Vec::new();
+    let mut size = 0;
+    for section in file.sections {
+        let parts = if section_size(&section) > max {split_section(&section, max)} else {vec![section]};
+        for part in parts {
+            let part_size = section_size(&part);
+            match files.last_mut() {
+                Some(last) if size + part_size <= max && last.can_take(&part) => last.sections.push(part),
+                _ => {
+                    let name = if files.is_empty() {file.name.clone()}
+                    else {format!("{}_{}", file.name, files.len() + 1)};
+                    files.push(File::new(name, part));
+                    size = 0
+                }
+            };
+            size += part_size
+        }
+    };
+
+    files
+}
+
+/// Раскладывает секции по файлам. По умолчанию - файл на секцию, как у парсера.
+/// Секции глубже split_level идут в файл родителя, большие файлы делятся,
+/// соседние маленькие - объединяются, примечания всегда в своём файле
+pub fn layout(content: &[Section], layout: &Layout) -> Vec<File> {
+    let mut files: Vec<File> = Vec::new();
+    for (i, section) in content.iter().enumerate() {
+        let name = match &section.file_name {
+            Some(name) => name.clone(),
+            None => format!("section_{}", get_counter_str(i + 1))
+        };
+        let nested = layout.split_level.is_some_and(|level| section.level > level || (section.level > 0
+            && is_continuation(content, i, section.level)));
+        match files.last_mut() {
+            Some(last) if nested && last.can_take(section) => last.sections.push(section.clone()),
+            _ => files.push(File::new(name, section.clone()))
+        }
+    };
+
+    if let Some(max) = layout.max_size {
+        files = files.into_iter().flat_map(|f| split_file(f, max.max(1) * KB)).collect()
+    };
+
+    if let Some(min) = layout.min_size {
+        let max = layout.max_size.map(|m| m.max(1) * KB).unwrap_or(usize::MAX);
+        let mut merged: Vec<File> = Vec::new();
+        for file in files {
+            match merged.last_mut() {
+                Some(last) if last.size() < min * KB && file.size() < min * KB
+                    && last.size() + file.size() <= max && last.can_take(&file.sections[0]) => {
+                    last.sections.extend(file.sections)
+                },
+                _ => merged.push(file)
+            }
+        };
+        files = merged
+    };
+
+    // якоря для оглавления у секций с заголовком не в начале файла
+    for file in &mut files {
+        for (j, section) in file.sections.iter_mut().enumerate() {
+            if j > 0 && section.id.is_none() && !section.title.is_empty() {
+                section.id = Some(format!("{}_{}", file.name, j + 1))
+            };
+            section.file_name = Some(file.name.clone())
+        }
+    };
+
+    files
+}
+
+/// Ссылки на id по новым файлам
+pub fn files_link_map(files: &[File]) -> HashMap<String, String> {
+    files.iter().flat_map(|f| link_map(&f.sections)).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{layout, split_file, split_section};
+    use crate::Layout;
+    use crate::fb2_parser::get_data_from_bytes;
+
+    const FIXTURE: &[u8] = include_bytes!("../../tests/fixtures/roundtrip.fb2");
+
+    /// Соседние секции одного уровня объединяются, у всех с заголовком есть якорь
+    #[test]
+    fn siblings_are_merged() {
+        let data = get_data_from_bytes(FIXTURE).unwrap();
+        // без титульной секции, иначе всё идёт в её файл как подсекции
+        let start = data.content.iter().position(|s| s.id.as_deref() == Some("ch1")).unwrap();
+        let files = layout(&data.content[start..], &Layout {split_level: None, max_size: None, min_size: Some(1000)});
+
+        let text = files.iter().find(|f| f.sections.iter().any(|s| s.id.as_deref() == Some("ch1"))).unwrap();
+        let ch2 = text.sections.iter().find(|s| s.id.as_deref() == Some("ch2")).unwrap();
+        assert_eq!(ch2.level, text.sections.iter().find(|s| s.id.as_deref() == Some("ch1")).unwrap().level);
+        assert!(text.sections.iter().skip(1).all(|s| s.title.is_empty() || s.id.is_some()));
+        assert!(text.sections.iter().all(|s| s.file_name.as_deref() == Some(text.name.as_str())));
+
+        let notes = files.iter().find(|f| f.name == "notes").unwrap();
+        assert_eq!(notes.sections.len(), 1);
+    }
+
+    /// Примечания книг сборника остаются в своих файлах и не делятся
+    #[test]
+    fn omnibus_notes_keep_files() {
+        let books = vec![
+            ("one".to_string(), get_data_from_bytes(FIXTURE).unwrap()),
+            ("two".to_string(), get_data_from_bytes(FIXTURE).unwrap())
+        ];
+        let data = crate::omnibus::merge(books);
+        let files = layout(&data.content, &Layout {split_level: None, max_size: None, min_size: Some(1000)});
+
+        for name in ["b01_notes", "b02_notes"] {
+            let notes: Vec<_> = files.iter().filter(|f| f.sections.iter().any(|s| s.file_name.as_deref() == Some(name))).collect();
+            assert_eq!(notes.len(), 1);
+            assert_eq!(notes[0].name, name);
+            assert_eq!(notes[0].sections.len(), 1);
+        };
+
+        // и не делятся, даже если больше предела
+        let notes = files.into_iter().find(|f| f.name == "b01_notes").unwrap();
+        assert_eq!(split_file(notes, 1).len(), 1);
+    }
+
+    /// Большая секция делится по абзацам: заголовок и id - только у первой части
+    #[test]
+    fn split_keeps_head_in_first_part() {
+        let data = get_data_from_bytes(FIXTURE).unwrap();
+        let section = data.content.iter().find(|s| s.id.as_deref() == Some("ch1")).unwrap();
+        let parts = split_section(section, 1);
+
+        assert_eq!(parts.len(), section.paragraphs.len());
+        assert_eq!(parts[0].id, section.id);
+        assert!(!parts[0].title.is_empty());
+        assert!(parts[1..].iter().all(|p| p.id.is_none() && p.title.is_empty() && p.level == section.level));
+    }
+}
@@ -0,0 +1,316 @@
+pub mod opf_reader;
+pub mod xhtml_reader;
+
+
+use std::path::Path;
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::collections::HashMap;
+
+use base64::{Engine as _, engine::general_purpose};
+use zip::ZipArchive;
+
+use crate::fb2_parser::{BookData, Image, get_counter_str, sanitize_id};
+use crate::fb2_parser::content_reader::*;
+use crate::epub_parser::opf_reader::{container_reader, opf_reader};
+use crate::epub_parser::xhtml_reader::{xhtml_reader, State, Target};
+
+
+pub fn read_entry<R: Read + Seek>(archive: &mut ZipArchive<R>, name: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let file = archive.by_name(name)?;
+
+    Ok(crate::compressed_reader::read_limited(file)?)
+}
+
+fn relink_blocks(blocks: &mut Vec<TextBlock>, links: &HashMap<String, Option<(String, bool)>>) {
+    for block in blocks {
+        let link = match &mut block.link {
+            Some(l) => l,
+            None => continue
+        };
+        match links.get(&link.link) {
+            Some(Some((href, is_note))) => {
+                link.link = href.clone();
+                if *is_note && link.link_type.is_none() {
+                    link.link_type = Some(String::from("note"))
+                }
+            },
+            Some(None) => block.link = None,
+            None => {}
+        }
+    }
+}
+
+fn relink_paragraphs(paragraphs: &mut Vec<Paragraph>, links: &HashMap<String, Option<(String, bool)>>) {
+    for p in paragraphs {
+        match p {
+            Paragraph::Text(blocks) | Paragraph::V(blocks) |
+            Paragraph::TextAuthor(blocks) | Paragraph::Subtitle(blocks) => relink_blocks(blocks, links),
+            Paragraph::Note(s) | Paragraph::Epigraph(s) |
+            Paragraph::Cite(s) | Paragraph::Annotation(s) => {
+                relink_paragraphs(&mut s.title, links);
+                relink_paragraphs(&mut s.paragraphs, links);
+            },
+            Paragraph::Poem(poem) => {
+                relink_paragraphs(&mut poem.title, links);
+                relink_paragraphs(&mut poem.paragraphs, links);
+                relink_blocks(&mut poem.date, links);
+                for stanza in &mut poem.stanzas {
+                    relink_paragraphs(&mut stanza.title, links);
+                    relink_paragraphs(&mut stanza.v, links);
+                }
+            },
+            Paragraph::Image(_) | Paragraph::EmptyLine => {}
+        }
+    }
+}
+
+fn collect_links(paragraphs: &Vec<Paragraph>, links: &mut Vec<String>) {
+    let mut push_blocks = |blocks: &Vec<TextBlock>| {
+        for block in blocks {
+            if let Some(l) = &block.link {
+                links.push(l.link.clone())
+            }
+        }
+    };
+
+    let mut nested: Vec<&Vec<Paragraph>> = Vec::new();
+    for p in paragraphs {
+        match p {
+            Paragraph::Text(blocks) | Paragraph::V(blocks) |
+            Paragraph::TextAuthor(blocks) | Paragraph::Subtitle(blocks) => push_blocks(blocks),
+            Paragraph::Note(s) | Paragraph::Epigraph(s) |
+            Paragraph::Cite(s) | Paragraph::Annotation(s) => {
+                nested.push(&s.title);
+                nested.push(&s.paragraphs);
+            },
+            Paragraph::Poem(poem) => {
+                push_blocks(&poem.date);
+                nested.push(&poem.title);
+                nested.push(&poem.paragraphs);
+                for stanza in &poem.stanzas {
+                    nested.push(&stanza.title);
+                    nested.push(&stanza.v);
+                }
+            },
+            Paragraph::Image(_) | Paragraph::EmptyLine => {}
+        }
+    };
+
+    for n in nested {
+        collect_links(n, links)
+    }
+}
+
+
+/// Только метаданные из OPF, главы не читаются
+pub fn get_meta(book: &Path) -> Result<BookData, Box<dyn std::error::Error>> {
+    get_meta_from_reader(File::open(book)?)
+}
+
+/// То же, что get_meta, но из памяти или другого потока
+pub fn get_meta_from_reader<R: Read + Seek>(reader: R) -> Result<BookData, Box<dyn std::error::Error>> {
+    let mut archive = ZipArchive::new(reader)?;
+
+    let container = String::from_utf8_lossy(&read_entry(&mut archive, "META-INF/container.xml")?).to_string();
+    let opf_path = container_reader(&container)
+        .ok_or("Cannot find OPF")?;
+    let opf = String::from_utf8_lossy(&read_entry(&mut archive, &opf_path)?).to_string();
+
+    Ok(BookData {
+        meta: opf_reader(&opf, &opf_path)?.meta,
+        content: Vec::new(),
+        images: HashMap::new(),
+        link_map: HashMap::new(),
+        encoding: None,
+        warnings: Vec::new(),
+        description: None
+    })
+}
+
+/// Читает EPUB и раскладывает его в ту же структуру, что и fb2_parser
+pub fn get_data(book: &Path) -> Result<BookData, Box<dyn std::error::Error>> {
+    get_data_from_reader(File::open(book)?)
+}
+
+/// То же, что get_data, но из памяти или другого потока
+pub fn get_data_from_reader<R: Read + Seek>(reader: R) -> Result<BookData, Box<dyn std::error::Error>> {
+    let mut archive = ZipArchive::new(reader)?;
+
+    let container = String::from_utf8_lossy(&read_entry(&mut archive, "META-INF/container.xml")?).to_string();
+    let opf_path = container_reader(&container)
+        .ok_or("Cannot find OPF")?;
+    let opf = String::from_utf8_lossy(&read_entry(&mut archive, &opf_path)?).to_string();
+    let package = opf_reader(&opf, &opf_path)?;
+
+    let mut state = State::default();
+
+    // Обложка регистрируется первой, чтобы получить ключ
+    if let Some(item) = package.cover_id.as_ref().and_then(|id| package.manifest.get(id))
+        && item.media_type.starts_with("image/") {
+        let name = item.href.rsplit('/').next().unwrap_or("cover");
+        state.images.insert(item.href.clone(), format!("#{}", sanitize_id(name)));
+        state.cover = Some(item.href.clone());
+    };
+
+    for idref in &package.spine {
+        let item = match package.manifest.get(idref) {
+            Some(i) => i,
+            None => continue
+        };
+        if item.properties.split_whitespace().any(|p| p == "nav") {continue}
+        if !item.media_type.contains("html") {continue}
+
+        let bytes = read_entry(&mut archive, &item.href)?;
+        let xml = String::from_utf8_lossy(&bytes);
+        xhtml_reader(xml.trim_start_matches('\u{feff}'), &item.href, &mut state)?;
+    };
+
+
+    // Уровни: самый крупный заголовок становится первым уровнем
+    let min_level = state.sections.iter()
+        .filter(|s| s.level > 0)
+        .map(|s| s.level)
+        .min()
+        .unwrap_or(1);
+    for section in &mut state.sections {
+        section.level = if section.level == 0 {1} else {section.level - min_level + 1};
+    };
+
+
+    // Ссылки: путь#якорь -> #id
+    let mut raw_links: Vec<String> = Vec::new();
+    for section in &state.sections {
+        collect_links(&section.title, &mut raw_links);
+        collect_links(&section.paragraphs, &mut raw_links);
+    };
+    for note in &state.notes {
+        collect_links(&note.title, &mut raw_links);
+        collect_links(&note.paragraphs, &mut raw_links);
+    };
+
+    let mut used_ids: Vec<String> = Vec::new();
+    let mut links: HashMap<String, Option<(String, bool)>> = HashMap::new();
+    for raw in raw_links {
+        if links.contains_key(&raw) || raw.contains("://") || raw.starts_with("mailto:") {continue}
+
+        let target = state.targets.get(&raw).copied().or_else(|| {
+            // неизвестный якорь: ссылка на начало документа
+            let doc = raw.split('#').next().unwrap_or("");
+            state.targets.get(&format!("{doc}#")).copied()
+        });
+        let section = match target {
+            Some(Target::Section(i)) => state.sections.get_mut(i),
+            Some(Target::Note(i)) => state.notes.get_mut(i),
+            None => None
+        };
+        let section = match section {
+            Some(s) => s,
+            None => {
+                links.insert(raw, None);
+                continue
+            }
+        };
+
+        let id = match &section.id {
+            Some(id) => id.clone(),
+            None => {
+                let fragment = raw.split('#').nth(1).unwrap_or("");
+                let base = if fragment.is_empty() {
+                    let doc = raw.split('#').next().unwrap_or("");
+                    let name = doc.rsplit('/').next().unwrap_or(doc);
+                    sanitize_id(name.split('.').next().unwrap_or(name))
+                } else {sanitize_id(fragment)};
+
+                let mut id = base.clone();
+                let mut counter = 1;
+                while used_ids.contains(&id) {
+                    id = format!("{base}_{counter}");
+                    counter += 1;
+                };
+                used_ids.push(id.clone());
+                section.id = Some(id.clone());
+
+                id
+            }
+        };
+
+        links.insert(raw, Some((format!("#{id}"), matches!(target, Some(Target::Note(_))))));
+    };
+
+    for section in &mut state.sections {
+        relink_paragraphs(&mut section.title, &links);
+        relink_paragraphs(&mut section.paragraphs, &links);
+    };
+    for note in &mut state.notes {
+        relink_paragraphs(&mut note.title, &links);
+        relink_paragraphs(&mut note.paragraphs, &links);
+    };
+
+
+    let mut data = BookData {
+        meta: package.meta,
+        content: Vec::new(),
+        images: HashMap::new(),
+        link_map: HashMap::new(),
+        encoding: None,
+        warnings: Vec::new(),
+        description: None
+    };
+
+    for (i, mut section) in state.sections.into_iter().enumerate() {
+        let file_name = format!("section_{}", get_counter_str(i + 1));
+        if let Some(id) = &section.id {
+            data.link_map.insert(format!("#{id}"), format!("{file_name}.xhtml#{id}"));
+        };
+        section.file_name = Some(file_name);
+        data.content.push(section);
+    };
+
+    if !state.notes.is_empty() {
+        let mut notes = Section {
+            level: 0,
+            id: None,
+            file_name: Some(String::from("notes")),
+            title: state.notes_title,
+            paragraphs: Vec::new()
+        };
+        for note in state.notes {
+            if let Some(id) = &note.id {
+                data.link_map.insert(format!("#{id}"), format!("notes.xhtml#{id}"));
+            };
+            notes.paragraphs.push(Paragraph::Note(note));
+        };
+        data.content.push(notes);
+    };
+
+
+    // Картинки
+    for (path, key) in state.images {
+        let content_type = package.manifest.values()
+            .find(|i| i.href == path)
+            .map(|i| i.media_type.clone())
+            .unwrap_or_else(|| match path.rsplit('.').next().map(|e| e.to_lowercase()).as_deref() {
+                Some("png") => String::from("image/png"),
+                Some("gif") => String::from("image/gif"),
+                Some("svg") => String::from("image/svg+xml"),
+                _ => String::from("image/jpeg")
+            });
+
+        let bytes = match read_entry(&mut archive, &path) {
+            Ok(b) => b,
+            Err(_) => continue
+        };
+
+        if state.cover.as_ref() == Some(&path) {
+            data.meta.cover = Some(key.clone())
+        };
+        data.images.insert(key.clone(), Image {
+            id: key[1..].to_string(),
+            content_type,
+            binary: general_purpose::STANDARD.encode(bytes)
+        });
+    };
+
+    Ok(data)
+}
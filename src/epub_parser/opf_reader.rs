@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::reader::Reader;
+
+use crate::fb2_parser::get_attr;
+use crate::fb2_parser::metadata_reader::{Metadata, Sequence};
+
+
+#[derive(Clone, Debug)]
+pub struct Item {
+    pub href: String,          // полный путь внутри архива
+    pub media_type: String,
+    pub properties: String
+}
+
+#[derive(Debug)]
+pub struct Package {
+    pub meta: Metadata,
+    pub manifest: HashMap<String, Item>,
+    pub spine: Vec<String>,
+    pub cover_id: Option<String>
+}
+
+
+/// Путь к файлу относительно директории base (оба пути внутри архива)
+pub fn resolve_path(base: &str, href: &str) -> String {
+    let mut parts: Vec<&str> = match base.rfind('/') {
+        Some(i) => base[..i].split('/').collect(),
+        None => Vec::new()
+    };
+
+    let href = href.split('#').next().unwrap_or("");
+    for part in href.split('/') {
+        match part {
+            "" | "." => {},
+            ".." => {parts.pop();},
+            p => parts.push(p)
+        }
+    };
+
+    unescape_href(&parts.join("/"))
+}
+
+fn unescape_href(href: &str) -> String {
+    if !href.contains('%') {
+        return href.to_string()
+    };
+
+    let bytes = href.as_bytes();
+    let mut result: Vec<u8> = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len()
+            && let Ok(b) = u8::from_str_radix(&String::from_utf8_lossy(&bytes[i + 1..i + 3]), 16) {
+            result.push(b);
+            i += 3;
+            continue
+        };
+        result.push(bytes[i]);
+        i += 1;
+    };
+
+    String::from_utf8_lossy(&result).to_string()
+}
+
+fn local_attr(e: &BytesStart, query: &str, reader: &Reader<&[u8]>) -> String {
+    for attr in e.attributes().flatten() {
+        if attr.key.local_name().as_ref() == query.as_bytes() {
+            return attr.decode_and_unescape_value(reader.decoder())
+                .map(|s| s.to_string())
+                .unwrap_or_default()
+        }
+    };
+
+    String::new()
+}
+
+fn strip_tags(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    let mut line = String::new();
+    let mut in_tag = false;
+    let mut tag = String::new();
+
+    for c in text.chars() {
+        match c {
+            '<' => {
+                in_tag = true;
+                tag.clear();
+            },
+            '>' if in_tag => {
+                in_tag = false;
+                let name = tag.trim_start_matches('/').split_whitespace().next()
+                    .unwrap_or("").to_lowercase();
+                if matches!(&name[..], "p" | "br" | "br/" | "div" | "li") {
+                    if !line.trim().is_empty() {
+                        lines.push(line.trim().to_string())
+                    };
+                    line.clear();
+                }
+            },
+            c if in_tag => tag.push(c),
+            '\n' => {
+                if !line.trim().is_empty() {
+                    lines.push(line.trim().to_string())
+                };
+                line.clear();
+            },
+            c => line.push(c)
+        }
+    };
+    if !line.trim().is_empty() {
+        lines.push(line.trim().to_string())
+    };
+
+    lines
+        .into_iter()
+        .map(|l| l
+            .replace("&nbsp;", " ")
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&amp;", "&"))
+        .collect()
+}
+
+
+pub fn container_reader(xml: &str) -> Option<String> {
+    let mut reader = Reader::from_str(xml);
+
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(ref e)) | Ok(Event::Empty(ref e))
+                if e.local_name().as_ref() == b"rootfile" => {
+                let path = get_attr(e, "full-path", reader.decoder());
+                if !path.is_empty() {
+                    return Some(path)
+                }
+            },
+            Ok(Event::Eof) | Err(_) => return None,
+            _ => {}
+        }
+    }
+}
+
+pub fn opf_reader(xml: &str, opf_path: &str) -> Result<Package, Box<dyn std::error::Error>> {
+    let mut reader = Reader::from_str(xml);
+    let mut package = Package {
+        meta: Metadata {
+            title: String::new(),
+            authors: Vec::new(),
+            genres: Vec::new(),
+            language: String::new(),
+            sequence: None,
+            annotation: None,
+            cover: None,
+            id: None
+        },
+        manifest: HashMap::new(),
+        spine: Vec::new(),
+        cover_id: None
+    };
+
+    // id автора и его имя, роли из <meta refines>
+    let mut creators: Vec<(String, String, String)> = Vec::new();
+    let mut roles: HashMap<String, String> = HashMap::new();
+    let mut collections: HashMap<String, String> = HashMap::new();
+    let mut positions: HashMap<String, String> = HashMap::new();
+    let mut unique_identifier = String::new();
+    let mut identifiers: Vec<(String, String)> = Vec::new();
+
+    let mut current: Option<(String, String, String)> = None; // тег, id, opf:role
+    let mut meta_property: Option<(String, String, String)> = None; // property, id, refines
+    let mut text = String::new();
+
+    let mut series_name = String::new();
+    let mut series_index = String::new();
+
+
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(ref e)) => {
+                match e.local_name().as_ref() {
+                    b"package" => unique_identifier = local_attr(e, "unique-identifier", &reader),
+                    name @ (b"title" | b"creator" | b"language" | b"description" | b"subject" | b"identifier") => {
+                        current = Some((
+                            String::from_utf8_lossy(name).to_string(),
+                            local_attr(e, "id", &reader),
+                            local_attr(e, "role", &reader)
+                        ));
+                        text.clear();
+                    },
+                    b"meta" => {
+                        let property = local_attr(e, "property", &reader);
+                        if !property.is_empty() {
+                            meta_property = Some((
+                                property,
+                                local_attr(e, "id", &reader),
+                                local_attr(e, "refines", &reader).trim_start_matches('#').to_string()
+                            ));
+                            text.clear();
+                        }
+                    },
+                    _ => {}
+                }
+            },
+
+            Ok(Event::Empty(ref e)) => {
+                match e.local_name().as_ref() {
+                    b"meta" => {
+                        let name = local_attr(e, "name", &reader);
+                        let content = local_attr(e, "content", &reader);
+                        match &name[..] {
+                            "cover" => package.cover_id = Some(content),
+                            "calibre:series" => series_name = content,
+                            "calibre:series_index" => series_index = content,
+                            _ => {}
+                        }
+                    },
+                    b"item" => {
+                        let id = local_attr(e, "id", &reader);
+                        let href = local_attr(e, "href", &reader);
+                        if id.is_empty() || href.is_empty() {continue}
+
+                        let item = Item {
+                            href: resolve_path(opf_path, &href),
+                            media_type: local_attr(e, "media-type", &reader),
+                            properties: local_attr(e, "properties", &reader)
+                        };
+                        if item.properties.split_whitespace().any(|p| p == "cover-image") {
+                            package.cover_id = Some(id.clone());
+                        };
+                        package.manifest.insert(id, item);
+                    },
+                    b"itemref" => {
+                        let idref = local_attr(e, "idref", &reader);
+                        if !idref.is_empty() {
+                            package.spine.push(idref)
+                        }
+                    },
+                    _ => {}
+                }
+            },
+
+            Ok(Event::Text(e)) if current.is_some() || meta_property.is_some() => {
+                text.push_str(&e.decode()?)
+            },
+
+            Ok(Event::GeneralRef(e)) if current.is_some() || meta_property.is_some() => {
+                if let Ok(Some(c)) = e.resolve_char_ref() {
+                    text.push(c)
+                } else if let Some(s) = quick_xml::escape::resolve_predefined_entity(&e.decode()?) {
+                    text.push_str(s)
+                }
+            },
+
+            Ok(Event::End(ref e)) => {
+                match e.local_name().as_ref() {
+                    b"meta" => {
+                        if let Some((property, id, refines)) = meta_property.take() {
+                            let value = text.trim().to_string();
+                            match &property[..] {
+                                "role" => {roles.insert(refines, value);},
+                                "belongs-to-collection" => {collections.insert(id, value);},
+                                "group-position" => {positions.insert(refines, value);},
+                                _ => {}
+                            }
+                        }
+                    },
+                    b"title" | b"creator" | b"language" | b"description" | b"subject" | b"identifier" => {
+                        let (tag, id, role) = match current.take() {
+                            Some(c) => c,
+                            None => continue
+                        };
+                        let value = text.trim().to_string();
+                        if value.is_empty() {continue}
+
+                        match &tag[..] {
+                            "title" if package.meta.title.is_empty() => package.meta.title = value,
+                            "creator" => creators.push((id, value, role)),
+                            "language" if package.meta.language.is_empty() => package.meta.language = value,
+                            "description" => package.meta.annotation = Some(strip_tags(&value)),
+                            "subject" => package.meta.genres.push(value),
+                            "identifier" => identifiers.push((id, value)),
+                            _ => {}
+                        }
+                    },
+                    b"metadata" => {},
+                    _ => {}
+                }
+            },
+
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(Box::new(e)),
+            _ => {}
+        }
+    };
+
+    // Авторы: без роли или с ролью aut
+    for (id, name, role) in creators {
+        let role = if !role.is_empty() {role}
+            else {roles.get(&id).cloned().unwrap_or_default()};
+        if role.is_empty() || role == "aut" {
+            package.meta.authors.push(name)
+        }
+    };
+
+    // Серия: сначала EPUB 3, потом calibre
+    if let Some((id, name)) = collections.iter().next() {
+        package.meta.sequence = Some(Sequence {
+            name: name.clone(),
+            number: positions.get(id).cloned().unwrap_or_default()
+        })
+    } else if !series_name.is_empty() {
+        package.meta.sequence = Some(Sequence {
+            name: series_name,
+            number: match series_index.strip_suffix(".0") {
+                Some(n) => n.to_string(),
+                None => series_index
+            }
+        })
+    };
+
+    package.meta.id = identifiers.iter()
+        .find(|(id, _)| !id.is_empty() && id == &unique_identifier)
+        .or(identifiers.first())
+        .map(|(_, value)| value.clone());
+
+    Ok(package)
+}
@@ -0,0 +1,763 @@
+use std::collections::HashMap;
+
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::reader::Reader;
+
+use crate::fb2_parser::content_reader::*;
+use crate::fb2_parser::{resolve_entity, sanitize_id};
+use crate::epub_parser::opf_reader::resolve_path;
+
+
+/// Куда указывает якорь из XHTML
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Target {
+    Section(usize),
+    Note(usize)
+}
+
+/// Состояние, общее для всех документов из spine
+#[derive(Default)]
+pub struct State {
+    pub sections: Vec<Section>,
+    pub notes: Vec<Section>,
+    pub notes_title: Vec<Paragraph>,
+    pub targets: HashMap<String, Target>,  // "путь#id" -> секция или примечание
+    pub images: HashMap<String, String>,   // путь картинки -> ключ вида "#id"
+    pub cover: Option<String>              // путь обложки
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum FrameKind {
+    Section,
+    Epigraph,
+    Cite,
+    Annotation,
+    Poem,
+    Stanza,
+    Note
+}
+
+struct Frame {
+    kind: FrameKind,
+    level: u8,
+    anchors: Vec<String>,
+    title: Vec<Paragraph>,
+    paragraphs: Vec<Paragraph>,
+    stanzas: Vec<Stanza>,
+    date: Vec<TextBlock>
+}
+
+impl Frame {
+    fn new(kind: FrameKind, level: u8) -> Self {
+        Frame {
+            kind,
+            level,
+            anchors: Vec::new(),
+            title: Vec::new(),
+            paragraphs: Vec::new(),
+            stanzas: Vec::new(),
+            date: Vec::new()
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.title.is_empty() && self.paragraphs.is_empty()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum BlockKind {
+    P,
+    V,
+    TextAuthor,
+    Subtitle,
+    Date
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Style {
+    Strong,
+    Emphasis,
+    Strikethrough,
+    Code,
+    Sup,
+    Sub
+}
+
+/// Что нужно сделать при закрытии тега
+enum Open {
+    Frame,
+    Block,
+    Container,
+    Heading,
+    Style(Style),
+    Link,
+    Skip,
+    Other
+}
+
+
+struct DocReader<'a> {
+    state: &'a mut State,
+    doc_path: &'a str,
+
+    frames: Vec<Frame>,
+    sections: Vec<Section>,
+    notes: Vec<Section>,
+    targets: Vec<(String, Target)>,
+
+    blocks: Vec<BlockKind>,
+    paragraph: Vec<TextBlock>,
+    heading: Option<Vec<Paragraph>>,
+    heading_level: u8,
+    heading_anchors: Vec<String>,
+    pending: Vec<String>,
+
+    styles: [usize; 6],
+    link: Option<Link>,
+    skip: usize
+}
+
+impl<'a> DocReader<'a> {
+    fn top(&mut self) -> &mut Frame {
+        self.frames.last_mut().expect("root frame is always present")
+    }
+
+    fn text_block(&self, text: String) -> TextBlock {
+        TextBlock {
+            text,
+            strong: self.styles[0] > 0,
+            emphasis: self.styles[1] > 0,
+            strikethrough: self.styles[2] > 0,
+            code: self.styles[3] > 0,
+            sup: self.styles[4] > 0,
+            sub: self.styles[5] > 0,
+            link: self.link.clone()
+        }
+    }
+
+    fn push_text(&mut self, text: &str) {
+        let mut normalized = String::new();
+        let mut was_space = false;
+        for c in text.chars() {
+            if c.is_whitespace() && c != '\u{a0}' {
+                if !was_space {normalized.push(' ')}
+                was_space = true;
+            } else {
+                normalized.push(c);
+                was_space = false;
+            }
+        };
+
+        if self.paragraph.is_empty() {
+            normalized = normalized.trim_start().to_string()
+        };
+        if normalized.is_empty() {return}
+
+        let block = self.text_block(String::new());
+        if let Some(last) = self.paragraph.last_mut() {
+            let same_style = {
+                last.strong == block.strong &&
+                    last.emphasis == block.emphasis &&
+                    last.strikethrough == block.strikethrough &&
+                    last.code == block.code &&
+                    last.sup == block.sup &&
+                    last.sub == block.sub &&
+                    last.link == block.link
+            };
+            if same_style {
+                last.text.push_str(&normalized);
+                return
+            }
+        };
+
+        self.paragraph.push(TextBlock {text: normalized, ..block});
+    }
+
+    fn take_paragraph(&mut self) -> Option<Vec<TextBlock>> {
+        if let Some(last) = self.paragraph.last_mut() {
+            let trimmed = last.text.trim_end().to_string();
+            last.text = trimmed;
+        };
+        self.paragraph.retain(|b| !b.text.is_empty());
+
+        if self.paragraph.is_empty() {
+            None
+        } else {
+            Some(std::mem::take(&mut self.paragraph))
+        }
+    }
+
+    /// Якоря перед содержимым относятся к текущей секции
+    fn settle_pending(&mut self) {
+        let pending: Vec<String> = self.pending.drain(..).collect();
+        self.frames[0].anchors.extend(pending);
+    }
+
+    fn flush(&mut self) {
+        let blocks = match self.take_paragraph() {
+            Some(b) => b,
+            None => return
+        };
+
+        if let Some(heading) = &mut self.heading {
+            heading.push(Paragraph::Text(blocks));
+            return
+        };
+        self.settle_pending();
+
+        let kind = self.blocks.last().copied().unwrap_or(BlockKind::P);
+        let frame = self.top();
+        match kind {
+            BlockKind::P => frame.paragraphs.push(Paragraph::Text(blocks)),
+            BlockKind::V => frame.paragraphs.push(Paragraph::V(blocks)),
+            BlockKind::TextAuthor => frame.paragraphs.push(Paragraph::TextAuthor(blocks)),
+            BlockKind::Subtitle => frame.paragraphs.push(Paragraph::Subtitle(blocks)),
+            BlockKind::Date => {
+                // дата бывает только у стиха
+                if let Some(poem) = self.frames.iter_mut().rev()
+                    .find(|f| f.kind == FrameKind::Poem) {
+                    poem.date.extend(blocks)
+                } else {
+                    self.top().paragraphs.push(Paragraph::Text(blocks))
+                }
+            }
+        }
+    }
+
+    fn add_anchor(&mut self, id: &str) {
+        let key = format!("{}#{}", self.doc_path, id);
+        if self.heading.is_some() {
+            self.heading_anchors.push(key);
+            return
+        };
+
+        // якоря внутри примечания указывают на само примечание
+        if let Some(note) = self.frames.iter_mut().rev()
+            .find(|f| f.kind == FrameKind::Note) {
+            note.anchors.push(key);
+            return
+        };
+
+        // может оказаться якорем следующего заголовка
+        if self.frames.len() == 1 && self.paragraph.is_empty() {
+            self.pending.push(key);
+            return
+        };
+
+        self.frames[0].anchors.push(key);
+    }
+
+    fn push_section(&mut self) {
+        if self.frames[0].is_empty() {return}
+        self.settle_pending();
+
+        let root = &mut self.frames[0];
+
+        let index = self.sections.len();
+        for anchor in root.anchors.drain(..) {
+            self.targets.push((anchor, Target::Section(index)))
+        };
+
+        self.sections.push(Section {
+            level: root.level,
+            id: None,
+            file_name: None,
+            title: std::mem::take(&mut root.title),
+            paragraphs: std::mem::take(&mut root.paragraphs)
+        });
+    }
+
+    fn start_heading(&mut self, level: u8) {
+        self.flush();
+        self.heading = Some(Vec::new());
+        self.heading_level = level;
+        if self.frames.len() == 1 {
+            let pending: Vec<String> = self.pending.drain(..).collect();
+            self.heading_anchors.extend(pending);
+        }
+    }
+
+    fn end_heading(&mut self) {
+        self.flush();
+        let title = self.heading.take().unwrap_or_default();
+        let anchors: Vec<String> = self.heading_anchors.drain(..).collect();
+
+        if self.frames.len() == 1 {
+            self.push_section();
+            let root = &mut self.frames[0];
+            root.level = self.heading_level;
+            root.title = title;
+            root.anchors.extend(anchors);
+        } else {
+            let frame = self.top();
+            if frame.is_empty() {
+                frame.title = title;
+            } else {
+                for p in title {
+                    if let Paragraph::Text(blocks) = p {
+                        frame.paragraphs.push(Paragraph::Subtitle(blocks))
+                    }
+                }
+            };
+            for anchor in anchors {
+                self.add_anchor_key(anchor)
+            }
+        }
+    }
+
+    fn add_anchor_key(&mut self, key: String) {
+        if let Some(note) = self.frames.iter_mut().rev()
+            .find(|f| f.kind == FrameKind::Note) {
+            note.anchors.push(key);
+        } else {
+            self.frames[0].anchors.push(key);
+        }
+    }
+
+    fn start_frame(&mut self, kind: FrameKind) {
+        self.flush();
+        let level = self.frames[0].level + 1;
+        self.frames.push(Frame::new(kind, level));
+    }
+
+    fn end_frame(&mut self) {
+        self.flush();
+        if self.frames.len() < 2 {return}
+        let frame = match self.frames.pop() {
+            Some(f) => f,
+            None => return
+        };
+
+        let section = Section {
+            level: frame.level,
+            id: None,
+            file_name: None,
+            title: frame.title,
+            paragraphs: frame.paragraphs
+        };
+
+        match frame.kind {
+            FrameKind::Note => {
+                if section.title.is_empty() && section.paragraphs.is_empty() {return}
+                let index = self.state.notes.len() + self.notes.len();
+                for anchor in frame.anchors {
+                    self.targets.push((anchor, Target::Note(index)))
+                };
+                self.notes.push(Section {level: 1, ..section});
+                return
+            },
+            _ => for anchor in frame.anchors {
+                self.add_anchor_key(anchor)
+            }
+        };
+
+        let paragraph = match frame.kind {
+            FrameKind::Epigraph => Paragraph::Epigraph(section),
+            FrameKind::Cite => Paragraph::Cite(section),
+            FrameKind::Annotation => Paragraph::Annotation(section),
+            FrameKind::Poem => Paragraph::Poem(Poem {
+                level: section.level,
+                id: None,
+                title: section.title,
+                stanzas: frame.stanzas,
+                paragraphs: section.paragraphs,
+                date: frame.date
+            }),
+            FrameKind::Stanza => {
+                let stanza = Stanza {
+                    level: section.level,
+                    id: None,
+                    title: section.title,
+                    v: section.paragraphs
+                };
+                if let Some(poem) = self.frames.iter_mut().rev()
+                    .find(|f| f.kind == FrameKind::Poem) {
+                    poem.stanzas.push(stanza);
+                } else {
+                    self.top().paragraphs.extend(stanza.v);
+                };
+                return
+            },
+            FrameKind::Section | FrameKind::Note => return
+        };
+
+        if matches!(&paragraph, Paragraph::Cite(s) | Paragraph::Epigraph(s) | Paragraph::Annotation(s)
+            if s.title.is_empty() && s.paragraphs.is_empty()) {
+            return
+        };
+        self.top().paragraphs.push(paragraph);
+    }
+
+    fn add_image(&mut self, src: &str) {
+        if src.is_empty() || src.starts_with("data:") {return}
+        self.flush();
+
+        let path = resolve_path(self.doc_path, src);
+        let key = match self.state.images.get(&path) {
+            Some(k) => k.clone(),
+            None => {
+                let name = path.rsplit('/').next().unwrap_or("image");
+                let mut key = format!("#{}", sanitize_id(name));
+                let mut counter = 1;
+                while self.state.images.values().any(|k| k == &key) {
+                    key = format!("#{}_{counter}", sanitize_id(name));
+                    counter += 1;
+                };
+                self.state.images.insert(path, key.clone());
+
+                key
+            }
+        };
+
+        if self.heading.is_none() {
+            self.settle_pending();
+            self.top().paragraphs.push(Paragraph::Image(Some(key)))
+        }
+    }
+
+    fn start_link(&mut self, href: &str, noteref: bool) {
+        if href.is_empty() {
+            self.link = None;
+            return
+        };
+
+        let link = if href.contains("://") || href.starts_with("mailto:") {
+            href.to_string()
+        } else {
+            // внутренние ссылки разрешаются после чтения всех документов
+            let fragment = href.split_once('#').map(|(_, f)| f).unwrap_or("");
+            let path = if href.starts_with('#') {self.doc_path.to_string()}
+                else {resolve_path(self.doc_path, href)};
+            format!("{path}#{fragment}")
+        };
+
+        self.link = Some(Link {
+            link,
+            link_type: if noteref {Some(String::from("note"))} else {None}
+        });
+    }
+}
+
+
+fn attr(e: &BytesStart, query: &str, reader: &Reader<&[u8]>) -> String {
+    for attr in e.attributes().flatten() {
+        if attr.key.as_ref() == query.as_bytes() || attr.key.local_name().as_ref() == query.as_bytes() {
+            return attr.decode_and_unescape_value(reader.decoder())
+                .map(|s| s.to_string())
+                .unwrap_or_default()
+        }
+    };
+
+    String::new()
+}
+
+fn has_class(class: &str, name: &str) -> bool {
+    class.split_whitespace().any(|c| c == name)
+}
+
+fn heading_level(name: &[u8], class: &str) -> Option<u8> {
+    match name {
+        b"h1" => Some(1),
+        b"h2" => Some(2),
+        b"h3" => Some(3),
+        b"h4" => Some(4),
+        b"h5" => Some(5),
+        b"h6" => Some(6),
+        b"div" => {
+            (1..=6).find(|l| has_class(class, &format!("title{l}")))
+        },
+        _ => None
+    }
+}
+
+/// Читает один XHTML документ из spine, добавляя секции и примечания в state
+pub fn xhtml_reader(
+    xml: &str,
+    doc_path: &str,
+    state: &mut State
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut reader = Reader::from_str(xml);
+    reader.config_mut().check_end_names = false;
+
+    let mut doc = DocReader {
+        state,
+        doc_path,
+        frames: vec![Frame::new(FrameKind::Section, 0)],
+        sections: Vec::new(),
+        notes: Vec::new(),
+        targets: Vec::new(),
+        blocks: Vec::new(),
+        paragraph: Vec::new(),
+        heading: None,
+        heading_level: 0,
+        heading_anchors: Vec::new(),
+        pending: Vec::new(),
+        styles: [0; 6],
+        link: None,
+        skip: 0
+    };
+    // ссылка на сам документ
+    doc.frames[0].anchors.push(format!("{doc_path}#"));
+
+    let mut opened: Vec<Open> = Vec::new();
+    let mut in_body = false;
+
+    loop {
+        let event = reader.read_event()?;
+        match event {
+            Event::Start(ref e) | Event::Empty(ref e) => {
+                let is_empty = matches!(event, Event::Empty(_));
+                let name = e.local_name().as_ref().to_ascii_lowercase();
+
+                if !in_body {
+                    if name == b"body" {in_body = true}
+                    if !is_empty {opened.push(Open::Other)}
+                    continue
+                };
+                if doc.skip > 0 {
+                    if !is_empty {
+                        doc.skip += 1;
+                        opened.push(Open::Skip);
+                    };
+                    continue
+                };
+
+                let class = attr(e, "class", &reader);
+                let epub_type = attr(e, "type", &reader);
+                let id = attr(e, "id", &reader);
+
+                let heading = heading_level(&name, &class);
+                if heading.is_some() && !is_empty {
+                    doc.start_heading(heading.unwrap_or(1));
+                };
+                if !id.is_empty() {
+                    doc.add_anchor(&id)
+                };
+                if heading.is_some() {
+                    if !is_empty {opened.push(Open::Heading)}
+                    continue
+                };
+
+                let is_note = epub_type.split_whitespace()
+                    .any(|t| matches!(t, "footnote" | "endnote" | "rearnote" | "note"));
+                let frame = if is_note {
+                    Some(FrameKind::Note)
+                } else {
+                    match &name[..] {
+                        b"blockquote" => Some(FrameKind::Cite),
+                        b"div" if has_class(&class, "epigraph") => Some(FrameKind::Epigraph),
+                        b"div" if has_class(&class, "cite") => Some(FrameKind::Cite),
+                        b"div" if has_class(&class, "annotation") => Some(FrameKind::Annotation),
+                        b"div" if has_class(&class, "poem") => Some(FrameKind::Poem),
+                        b"div" if has_class(&class, "stanza") => Some(FrameKind::Stanza),
+                        _ => None
+                    }
+                };
+
+                if let Some(kind) = frame {
+                    if !is_empty {
+                        doc.start_frame(kind);
+                        if !id.is_empty() && kind != FrameKind::Note {
+                            doc.add_anchor(&id)
+                        };
+                        opened.push(Open::Frame);
+                    };
+                    continue
+                };
+
+                let open = match &name[..] {
+                    b"p" | b"li" | b"dt" | b"dd" | b"td" | b"th" | b"pre" | b"address"
+                    | b"figcaption" | b"caption" | b"subtitle" => {
+                        doc.flush();
+                        let kind = if name == b"subtitle" || has_class(&class, "subtitle") {
+                            BlockKind::Subtitle
+                        } else if has_class(&class, "v") {
+                            BlockKind::V
+                        } else if has_class(&class, "text-author") {
+                            BlockKind::TextAuthor
+                        } else if has_class(&class, "date") {
+                            BlockKind::Date
+                        } else {
+                            BlockKind::P
+                        };
+                        doc.blocks.push(kind);
+                        if name == b"pre" {
+                            doc.styles[3] += 1;
+                            opened.push(Open::Style(Style::Code));
+                        };
+
+                        Open::Block
+                    },
+                    b"div" | b"section" | b"article" | b"aside" | b"ul" | b"ol" | b"dl"
+                    | b"table" | b"tr" | b"tbody" | b"thead" | b"figure" | b"header"
+                    | b"footer" | b"main" | b"nav" | b"center" => {
+                        doc.flush();
+                        Open::Container
+                    },
+                    b"b" | b"strong" => Open::Style(Style::Strong),
+                    b"i" | b"em" | b"cite" | b"dfn" | b"var" => Open::Style(Style::Emphasis),
+                    b"s" | b"strike" | b"del" => Open::Style(Style::Strikethrough),
+                    b"code" | b"tt" | b"kbd" | b"samp" => Open::Style(Style::Code),
+                    b"sup" => Open::Style(Style::Sup),
+                    b"sub" => Open::Style(Style::Sub),
+                    b"a" => {
+                        let noteref = epub_type.split_whitespace().any(|t| t == "noteref");
+                        doc.start_link(&attr(e, "href", &reader), noteref);
+                        Open::Link
+                    },
+                    b"img" => {
+                        doc.add_image(&attr(e, "src", &reader));
+                        Open::Other
+                    },
+                    b"image" => {
+                        doc.add_image(&attr(e, "href", &reader));
+                        Open::Other
+                    },
+                    b"br" => {
+                        doc.flush();
+                        Open::Other
+                    },
+                    b"hr" | b"empty-line" => {
+                        doc.flush();
+                        if doc.heading.is_none() {
+                            doc.top().paragraphs.push(Paragraph::EmptyLine)
+                        };
+                        Open::Other
+                    },
+                    b"script" | b"style" | b"head" | b"title" => {
+                        if !is_empty {doc.skip += 1}
+                        Open::Skip
+                    },
+                    _ => Open::Other
+                };
+
+                if is_empty {
+                    // пустой тег ничего не открывает
+                    if let Open::Block = open {
+                        if name == b"p" && doc.heading.is_none() {
+                            doc.top().paragraphs.push(Paragraph::EmptyLine)
+                        };
+                        doc.blocks.pop();
+                    };
+                    if let Open::Link = open {doc.link = None};
+                    continue
+                };
+
+                if let Open::Style(style) = open {
+                    doc.styles[style as usize] += 1
+                };
+                opened.push(open);
+            },
+
+            Event::End(ref e) => {
+                if e.local_name().as_ref().eq_ignore_ascii_case(b"body") {
+                    break
+                };
+
+                match opened.pop() {
+                    Some(Open::Skip) => doc.skip = doc.skip.saturating_sub(1),
+                    Some(Open::Heading) => doc.end_heading(),
+                    Some(Open::Frame) => doc.end_frame(),
+                    Some(Open::Block) => {
+                        doc.flush();
+                        doc.blocks.pop();
+                        // <pre> открывает ещё и стиль
+                        if let Some(Open::Style(Style::Code)) = opened.last()
+                            && e.local_name().as_ref().eq_ignore_ascii_case(b"pre") {
+                            opened.pop();
+                            doc.styles[3] = doc.styles[3].saturating_sub(1);
+                        }
+                    },
+                    Some(Open::Container) => doc.flush(),
+                    Some(Open::Style(style)) => {
+                        doc.styles[style as usize] = doc.styles[style as usize].saturating_sub(1)
+                    },
+                    Some(Open::Link) => doc.link = None,
+                    Some(Open::Other) | None => {}
+                }
+            },
+
+            Event::Text(ref e) if in_body && doc.skip == 0 => {
+                let text = e.decode()?.into_owned();
+                doc.push_text(&text);
+            },
+
+            Event::CData(ref e) if in_body && doc.skip == 0 => {
+                let text = e.decode()?.into_owned();
+                doc.push_text(&text);
+            },
+
+            Event::GeneralRef(ref e) if in_body && doc.skip == 0 => {
+                if let Ok(Some(c)) = e.resolve_char_ref() {
+                    doc.push_text(&c.to_string());
+                } else if let Some(s) = resolve_entity(&e.decode()?) {
+                    doc.push_text(s);
+                }
+            },
+
+            Event::Eof => break,
+            _ => {}
+        }
+    };
+
+    // незакрытые теги
+    while let Some(open) = opened.pop() {
+        match open {
+            Open::Heading => doc.end_heading(),
+            Open::Frame => doc.end_frame(),
+            _ => {}
+        }
+    };
+    doc.flush();
+    doc.push_section();
+    if let Some(last) = doc.sections.len().checked_sub(1) {
+        for anchor in doc.pending.drain(..) {
+            doc.targets.push((anchor, Target::Section(last)))
+        }
+    };
+
+    // Документ только с примечаниями: заголовок уходит в тело примечаний
+    let notes_only = !doc.notes.is_empty() &&
+        doc.sections.iter().all(|s| s.paragraphs.is_empty());
+    // Страница с одной обложкой
+    let cover_key = doc.state.cover.as_ref().and_then(|c| doc.state.images.get(c));
+    let cover_only = !doc.sections.is_empty() && doc.sections.iter().all(|s| s.title.is_empty() &&
+        s.paragraphs.iter().all(|p| match p {
+            Paragraph::Image(Some(key)) => Some(key) == cover_key,
+            Paragraph::EmptyLine => true,
+            _ => false
+        }));
+
+    let offset = doc.state.sections.len();
+    let first_note = doc.state.notes.len();
+    let mut sections = std::mem::take(&mut doc.sections);
+    let notes = std::mem::take(&mut doc.notes);
+    let targets = std::mem::take(&mut doc.targets);
+    let state = doc.state;
+
+    if notes_only {
+        if state.notes_title.is_empty()
+            && let Some(s) = sections.iter().find(|s| !s.title.is_empty()) {
+            state.notes_title = s.title.clone()
+        };
+        sections.clear();
+    } else if cover_only {
+        sections.clear();
+    };
+
+    for (key, target) in targets {
+        let target = match target {
+            Target::Section(_) if sections.is_empty() => {
+                if notes_only {Target::Note(first_note)} else {continue}
+            },
+            Target::Section(i) => Target::Section(i + offset),
+            note => note
+        };
+        state.targets.entry(key).or_insert(target);
+    };
+    state.sections.extend(sections);
+    state.notes.extend(notes);
+
+    Ok(())
+}
@@ -0,0 +1,249 @@
+mod xml_builder;
+pub mod normalizer;
+
+use std::io::Write;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use quick_xml::escape::{escape, partial_escape};
+
+use crate::fb2_parser::BookData;
+use crate::fb2_creator::xml_builder::{body_builder, TAB};
+use crate::fb2_creator::normalizer::normalize;
+
+
+/// Текущая дата в виде YYYY-MM-DD
+fn get_date() -> String {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() / 86400)
+        .unwrap_or(0) as i64;
+
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 {mp + 3} else {mp - 9};
+    let year = yoe + era * 400 + if month <= 2 {1} else {0};
+
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+fn get_id(data: &BookData) -> String {
+    if let Some(id) = &data.meta.id {
+        return id.clone()
+    };
+
+    let mut hasher = DefaultHasher::new();
+    data.meta.title.hash(&mut hasher);
+    data.meta.authors.hash(&mut hasher);
+    SystemTime::now().hash(&mut hasher);
+
+    format!("fb2epub-{:016x}", hasher.finish())
+}
+
+fn unwrap_author(author: &str, indent: usize) -> String {
+    let tabs = TAB.repeat(indent);
+    let inner = TAB.repeat(indent + 1);
+    let words: Vec<&str> = author.split_whitespace().collect();
+
+    let mut s = format!("{tabs}<author>\n");
+    match words.len() {
+        0 => return String::new(),
+        1 => s.push_str(&format!("{inner}<nickname>{}</nickname>\n", partial_escape(words[0]))),
+        n => {
+            s.push_str(&format!("{inner}<first-name>{}</first-name>\n", partial_escape(words[0])));
+            if n > 2 {
+                s.push_str(&format!("{inner}<middle-name>{}</middle-name>\n", partial_escape(words[1..n - 1].join(" "))));
+            };
+            s.push_str(&format!("{inner}<last-name>{}</last-name>\n", partial_escape(words[n - 1])));
+        }
+    };
+    s.push_str(&format!("{tabs}</author>\n"));
+
+    s
+}
+
+/// Описание книги: исходное, если книга была fb2, иначе строится по meta
+fn get_description(data: &BookData) -> String {
+    if let Some(description) = &data.description {
+        return format!("{description}\n")
+    };
+
+    let meta = &data.meta;
+    let t2 = TAB.repeat(2);
+    let t3 = TAB.repeat(3);
+
+    let mut s = format!("{TAB}<description>\n{t2}<title-info>\n");
+
+    if meta.genres.is_empty() {
+        // как и calibre, жанр по умолчанию
+        s.push_str(&format!("{t3}<genre>antique</genre>\n"));
+    };
+    for genre in &meta.genres {
+        s.push_str(&format!("{t3}<genre>{}</genre>\n", partial_escape(genre.as_str())));
+    };
+
+    let authors: String = meta.authors.iter().map(|a| unwrap_author(a, 3)).collect();
+    if authors.is_empty() {
+        s.push_str(&unwrap_author("Unknown", 3));
+    } else {
+        s.push_str(&authors);
+    };
+
+    s.push_str(&format!("{t3}<book-title>{}</book-title>\n", partial_escape(meta.title.as_str())));
+
+    if let Some(annotation) = &meta.annotation {
+        s.push_str(&format!("{t3}<annotation>\n"));
+        for p in annotation {
+            s.push_str(&format!("{t3}{TAB}<p>{}</p>\n", partial_escape(p.as_str())));
+        };
+        s.push_str(&format!("{t3}</annotation>\n"));
+    };
+
+    if let Some(cover) = &meta.cover
+        && data.images.contains_key(cover) {
+        s.push_str(&format!("{t3}<coverpage>\n{t3}{TAB}<image l:href=\"{}\"/>\n{t3}</coverpage>\n",
+            escape(cover.as_str())));
+    };
+
+    s.push_str(&format!("{t3}<lang>{}</lang>\n", partial_escape(meta.language.as_str())));
+
+    if let Some(seq) = &meta.sequence
+        && !seq.name.is_empty() {
+        s.push_str(&format!("{t3}<sequence name=\"{}\"", escape(seq.name.as_str())));
+        if !seq.number.is_empty() {
+            s.push_str(&format!(" number=\"{}\"", escape(seq.number.as_str())));
+        };
+        s.push_str("/>\n");
+    };
+
+    let date = get_date();
+    s.push_str(&format!("{t2}</title-info>\n{t2}<document-info>\n"));
+    s.push_str(&unwrap_author("fb2epub", 3));
+    s.push_str(&format!("{t3}<program-used>fb2epub</program-used>\n"));
+    s.push_str(&format!("{t3}<date value=\"{date}\">{date}</date>\n"));
+    s.push_str(&format!("{t3}<id>{}</id>\n", partial_escape(get_id(data))));
+    s.push_str(&format!("{t3}<version>1.0</version>\n"));
+    s.push_str(&format!("{t2}</document-info>\n{TAB}</description>\n"));
+
+    s
+}
+
+
+/// Записывает книгу в fb2, предварительно исправив структуру (см. normalizer)
+pub fn write_fb2<W: Write>(data: &mut BookData, writer: &mut W) -> Result<(), Box<dyn std::error::Error>> {
+    normalize(data);
+    let data = &*data;
+
+    let mut xml = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>
+<FictionBook xmlns="http://www.gribuser.ru/xml/fictionbook/2.0" xmlns:l="http://www.w3.org/1999/xlink">
+"#);
+    xml.push_str(&get_description(data));
+
+    // Основное тело и тела с примечаниями
+    let is_notes = |name: &Option<String>| matches!(name.as_deref(), Some("notes") | Some("comments"));
+    let main: Vec<_> = data.content.iter().filter(|s| !is_notes(&s.file_name)).collect();
+    xml.push_str(&body_builder(&main, None));
+
+    for section in data.content.iter().filter(|s| is_notes(&s.file_name)) {
+        xml.push_str(&body_builder(&[section], section.file_name.as_deref()));
+    };
+
+    // Картинки в порядке id, чтобы вывод был стабильным
+    let mut images: Vec<_> = data.images.values().collect();
+    images.sort_by(|a, b| a.id.cmp(&b.id));
+    for image in images {
+        xml.push_str(&format!("{TAB}<binary id=\"{}\" content-type=\"{}\">{}</binary>\n",
+            escape(image.id.as_str()),
+            escape(image.content_type.as_str()),
+            image.binary));
+    };
+
+    xml.push_str("</FictionBook>\n");
+
+    writer.write_all(xml.as_bytes())?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::write_fb2;
+    use crate::fb2_parser::get_data_from_bytes;
+
+    const FIXTURE: &[u8] = include_bytes!("../tests/fixtures/roundtrip.fb2");
+    const FULL: &[u8] = include_bytes!("../tests/fixtures/full_description.fb2");
+
+    /// parse -> write -> parse правильной книги даёт то же дерево, что и первое чтение
+    #[test]
+    fn round_trip_keeps_book() {
+        let original = get_data_from_bytes(FULL).unwrap();
+        let mut first = get_data_from_bytes(FULL).unwrap();
+        let mut xml: Vec<u8> = Vec::new();
+        write_fb2(&mut first, &mut xml).unwrap();
+        let second = get_data_from_bytes(&xml).unwrap();
+
+        assert_eq!(original.meta, second.meta);
+        assert_eq!(original.content, second.content);
+        assert_eq!(original.images, second.images);
+        assert_eq!(original.link_map, second.link_map);
+        assert_eq!(original.description, second.description);
+    }
+
+    /// Исправляется только текст: описание со всеми полями остаётся как было
+    #[test]
+    fn repair_keeps_description() {
+        let original = get_data_from_bytes(FULL).unwrap();
+        let mut data = get_data_from_bytes(FULL).unwrap();
+        let mut xml: Vec<u8> = Vec::new();
+        write_fb2(&mut data, &mut xml).unwrap();
+        let xml = String::from_utf8(xml).unwrap();
+
+        assert!(xml.contains(original.description.as_deref().unwrap()));
+        for field in ["<date value=\"1964-01-01\">", "<translator>", "<keywords>", "<src-lang>",
+            "<isbn>5-00-000000-0</isbn>", "<src-ocr>", "<history>", "<version>2.1</version>",
+            "<middle-name>Натанович</middle-name>", "<emphasis>курсивом</emphasis>"] {
+            assert!(xml.contains(field), "{field}")
+        };
+
+        // книга без правильной вложенности тоже сохраняет описание
+        let original = get_data_from_bytes(FIXTURE).unwrap();
+        let mut data = get_data_from_bytes(FIXTURE).unwrap();
+        let mut xml: Vec<u8> = Vec::new();
+        write_fb2(&mut data, &mut xml).unwrap();
+        let second = get_data_from_bytes(&xml).unwrap();
+        assert_eq!(original.meta, second.meta);
+        assert_eq!(original.description, second.description);
+    }
+
+    /// Записанная книга при повторной записи не меняется
+    #[test]
+    fn writing_is_stable() {
+        let mut first = get_data_from_bytes(FIXTURE).unwrap();
+        let mut xml: Vec<u8> = Vec::new();
+        write_fb2(&mut first, &mut xml).unwrap();
+
+        let mut second = get_data_from_bytes(&xml).unwrap();
+        let mut again: Vec<u8> = Vec::new();
+        write_fb2(&mut second, &mut again).unwrap();
+
+        assert_eq!(String::from_utf8(xml).unwrap(), String::from_utf8(again).unwrap());
+    }
+
+    #[test]
+    fn fixture_is_read_fully() {
+        let data = get_data_from_bytes(FIXTURE).unwrap();
+        assert_eq!(data.meta.title, "Пикник & обочина");
+        assert_eq!(data.meta.authors.len(), 2);
+        assert_eq!(data.meta.id.as_deref(), Some("roundtrip-fixture-1"));
+        assert_eq!(data.meta.sequence.as_ref().map(|s| s.number.as_str()), Some("3"));
+        assert_eq!(data.images.len(), 2);
+        assert!(data.content.iter().any(|s| s.id.as_deref() == Some("ch1_2") && s.level == 2));
+        assert!(data.link_map.contains_key("#n2"));
+    }
+}
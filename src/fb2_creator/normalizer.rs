@@ -0,0 +1,518 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::fb2_parser::{BookData, Section, get_counter_str, sanitize_id};
+use crate::fb2_parser::content_reader::*;
+
+
+/// Секция примечаний: файл notes или comments, в сборнике - с префиксом книги, b01_notes
+pub fn is_notes(section: &Section) -> bool {
+    let name = section.file_name.as_deref().unwrap_or_default();
+    let name = match name.split_once('_') {
+        Some((prefix, rest)) if prefix.len() > 1 && prefix.starts_with('b')
+            && prefix[1..].bytes().all(|b| b.is_ascii_digit()) => rest,
+        _ => name
+    };
+
+    matches!(name, "notes" | "comments")
+}
+
+/// Обходит все вложенные списки абзацев (эпиграфы, цитаты, стихи, примечания)
+pub fn walk(paragraphs: &mut Vec<Paragraph>, f: &mut dyn FnMut(&mut Paragraph)) {
+    for p in paragraphs {
+        f(p);
+        match p {
+            Paragraph::Note(s) | Paragraph::Epigraph(s) |
+            Paragraph::Cite(s) | Paragraph::Annotation(s) => {
+                walk(&mut s.title, f);
+                walk(&mut s.paragraphs, f);
+            },
+            Paragraph::Poem(poem) => {
+                walk(&mut poem.title, f);
+                walk(&mut poem.paragraphs, f);
+                for stanza in &mut poem.stanzas {
+                    walk(&mut stanza.title, f);
+                    walk(&mut stanza.v, f);
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+pub fn walk_content(content: &mut Vec<Section>, f: &mut dyn FnMut(&mut Paragraph)) {
+    for section in content {
+        walk(&mut section.title, f);
+        walk(&mut section.paragraphs, f);
+    }
+}
+
+pub fn blocks_mut(p: &mut Paragraph) -> Vec<&mut Vec<TextBlock>> {
+    match p {
+        Paragraph::Text(b) | Paragraph::V(b) |
+        Paragraph::TextAuthor(b) | Paragraph::Subtitle(b) => vec![b],
+        Paragraph::Poem(poem) => vec![&mut poem.date],
+        _ => Vec::new()
+    }
+}
+
+
+/// Текст абзаца в том виде, в котором его вернёт парсер:
+/// без управляющих символов, без пустых блоков, соседние блоки одного стиля склеены
+fn clean_blocks(blocks: &mut Vec<TextBlock>) {
+    let mut result: Vec<TextBlock> = Vec::new();
+    for mut block in blocks.drain(..) {
+        block.text.retain(|c| !c.is_control() || matches!(c, '\t' | '\n' | '\r'));
+        if block.text.trim().is_empty() {
+            if result.is_empty() {continue}
+            block.text = String::from(" ");
+        };
+
+        if let Some(last) = result.last_mut()
+            && last.strong == block.strong
+            && last.emphasis == block.emphasis
+            && last.strikethrough == block.strikethrough
+            && last.code == block.code
+            && last.sup == block.sup
+            && last.sub == block.sub
+            && last.link == block.link {
+            last.text.push_str(&block.text);
+            continue
+        };
+        result.push(block);
+    };
+
+    while let Some(last) = result.last_mut() {
+        let trimmed = last.text.trim_end().len();
+        last.text.truncate(trimmed);
+        if last.text.is_empty() {
+            result.pop();
+        } else {
+            break
+        }
+    };
+
+    *blocks = result;
+}
+
+fn clean_paragraphs(paragraphs: &mut Vec<Paragraph>, in_stanza: bool) {
+    for p in paragraphs.iter_mut() {
+        match p {
+            // v бывает только в строфах
+            Paragraph::V(b) if !in_stanza => *p = Paragraph::Text(std::mem::take(b)),
+            Paragraph::Text(b) if in_stanza => *p = Paragraph::V(std::mem::take(b)),
+            _ => {}
+        };
+
+        match p {
+            Paragraph::Text(b) | Paragraph::V(b) |
+            Paragraph::TextAuthor(b) | Paragraph::Subtitle(b) => clean_blocks(b),
+            Paragraph::Note(s) | Paragraph::Epigraph(s) |
+            Paragraph::Cite(s) | Paragraph::Annotation(s) => {
+                clean_paragraphs(&mut s.title, false);
+                clean_paragraphs(&mut s.paragraphs, false);
+            },
+            Paragraph::Poem(poem) => {
+                clean_paragraphs(&mut poem.title, false);
+                clean_blocks(&mut poem.date);
+
+                // в стихе до строф только эпиграфы, после - авторы
+                let mut epigraphs: Vec<Paragraph> = Vec::new();
+                let mut authors: Vec<Paragraph> = Vec::new();
+                for p in poem.paragraphs.drain(..) {
+                    match p {
+                        Paragraph::Epigraph(_) => epigraphs.push(p),
+                        Paragraph::TextAuthor(b) | Paragraph::Text(b) => authors.push(Paragraph::TextAuthor(b)),
+                        _ => {}
+                    }
+                };
+                epigraphs.extend(authors);
+                poem.paragraphs = epigraphs;
+                clean_paragraphs(&mut poem.paragraphs, false);
+
+                for stanza in &mut poem.stanzas {
+                    clean_paragraphs(&mut stanza.title, false);
+                    stanza.v.retain(|p| matches!(p, Paragraph::V(_) | Paragraph::Text(_) | Paragraph::Subtitle(_)));
+                    clean_paragraphs(&mut stanza.v, true);
+                };
+                poem.stanzas.retain(|s| !s.v.is_empty() || !s.title.is_empty());
+            },
+            Paragraph::Image(_) | Paragraph::EmptyLine => {}
+        }
+    };
+
+    paragraphs.retain(|p| match p {
+        Paragraph::Text(b) | Paragraph::V(b) |
+        Paragraph::TextAuthor(b) | Paragraph::Subtitle(b) => !b.is_empty(),
+        Paragraph::Epigraph(s) | Paragraph::Cite(s) | Paragraph::Annotation(s) =>
+            !s.title.is_empty() || !s.paragraphs.is_empty(),
+        Paragraph::Note(s) => !s.title.is_empty() || !s.paragraphs.is_empty() || s.id.is_some(),
+        Paragraph::Poem(poem) => !poem.stanzas.is_empty(),
+        Paragraph::Image(href) => href.is_some(),
+        Paragraph::EmptyLine => true
+    });
+}
+
+
+/// Меняет значения href в исходном описании книги, f возвращает новое значение или None
+fn map_hrefs(description: &str, f: &mut dyn FnMut(&str) -> Option<String>) -> String {
+    let mut result = String::with_capacity(description.len());
+    let mut rest = description;
+    while let Some(i) = rest.find("href=") {
+        let (head, tail) = rest.split_at(i + "href=".len());
+        result.push_str(head);
+        rest = tail;
+
+        let quote = match tail.chars().next() {
+            Some(q @ ('"' | '\'')) => q,
+            _ => continue
+        };
+        let end = match tail[1..].find(quote) {
+            Some(e) => e + 1,
+            None => continue
+        };
+        let value = &tail[1..end];
+        result.push(quote);
+        result.push_str(&f(value).unwrap_or_else(|| value.to_string()));
+        rest = &tail[end..];
+    };
+    result.push_str(rest);
+
+    result
+}
+
+/// Одинаковые картинки остаются в одном экземпляре, неиспользуемые удаляются
+fn dedup_images(data: &mut BookData) {
+    let mut keys: Vec<String> = data.images.keys().cloned().collect();
+    keys.sort();
+
+    let mut seen: HashMap<(String, String), String> = HashMap::new();
+    let mut replace: HashMap<String, String> = HashMap::new();
+    for key in keys {
+        let image = &data.images[&key];
+        let content = (image.content_type.clone(), image.binary.clone());
+        match seen.get(&content) {
+            Some(original) => {replace.insert(key, original.clone());},
+            None => {seen.insert(content, key);}
+        }
+    };
+
+    let mut used: HashSet<String> = HashSet::new();
+    if let Some(cover) = &mut data.meta.cover {
+        if let Some(original) = replace.get(cover) {
+            *cover = original.clone()
+        };
+        used.insert(cover.clone());
+    };
+    walk_content(&mut data.content, &mut |p| {
+        if let Paragraph::Image(Some(href)) = p {
+            if let Some(original) = replace.get(href) {
+                *href = original.clone()
+            };
+            used.insert(href.clone());
+        }
+    });
+    // картинки исходного описания тоже нужны
+    if let Some(description) = data.description.take() {
+        data.description = Some(map_hrefs(&description, &mut |href| {
+            let new = replace.get(href).cloned();
+            used.insert(new.clone().unwrap_or_else(|| href.to_string()));
+            new
+        }))
+    };
+
+    data.images.retain(|key, _| used.contains(key));
+}
+
+
+/// Все id уникальны и допустимы, ссылки ведут только на существующие id
+fn fix_ids(data: &mut BookData) {
+    let mut used: HashSet<String> = HashSet::new();
+    let mut renamed: HashMap<String, String> = HashMap::new();
+
+    let unique = |id: &str, used: &mut HashSet<String>| -> String {
+        let base = sanitize_id(id);
+        let mut new_id = base.clone();
+        let mut counter = 2;
+        while used.contains(&new_id) {
+            new_id = format!("{base}_{counter}");
+            counter += 1;
+        };
+        used.insert(new_id.clone());
+
+        new_id
+    };
+
+    // Картинки
+    let mut keys: Vec<String> = data.images.keys().cloned().collect();
+    keys.sort();
+    let mut images = HashMap::new();
+    for key in keys {
+        let mut image = match data.images.remove(&key) {
+            Some(i) => i,
+            None => continue
+        };
+        image.id = unique(&image.id, &mut used);
+        let new_key = format!("#{}", image.id);
+        renamed.insert(key, new_key.clone());
+        images.insert(new_key, image);
+    };
+    data.images = images;
+
+    // Секции и вложенные элементы
+    let mut ids: HashMap<String, String> = HashMap::new();
+    let mut rename = |id: &mut Option<String>, used: &mut HashSet<String>| {
+        if let Some(old) = id.take() {
+            if old.is_empty() {return}
+            let new_id = unique(&old, used);
+            ids.entry(format!("#{old}")).or_insert(format!("#{new_id}"));
+            *id = Some(new_id);
+        }
+    };
+    for section in &mut data.content {
+        rename(&mut section.id, &mut used);
+        let mut paragraphs = std::mem::take(&mut section.paragraphs);
+        walk(&mut paragraphs, &mut |p| match p {
+            Paragraph::Note(s) | Paragraph::Epigraph(s) |
+            Paragraph::Cite(s) | Paragraph::Annotation(s) => rename(&mut s.id, &mut used),
+            Paragraph::Poem(poem) => {
+                rename(&mut poem.id, &mut used);
+                for stanza in &mut poem.stanzas {
+                    rename(&mut stanza.id, &mut used)
+                }
+            },
+            _ => {}
+        });
+        section.paragraphs = paragraphs;
+    };
+
+    // Ссылки и картинки
+    if let Some(cover) = &data.meta.cover {
+        data.meta.cover = renamed.get(cover).cloned()
+    };
+    if let Some(description) = data.description.take() {
+        data.description = Some(map_hrefs(&description, &mut |href| renamed.get(href).or(ids.get(href)).cloned()))
+    };
+    walk_content(&mut data.content, &mut |p| {
+        if let Paragraph::Image(href) = p {
+            *href = href.as_ref().and_then(|h| renamed.get(h).cloned());
+            return
+        };
+
+        for blocks in blocks_mut(p) {
+            for block in blocks {
+                let internal = match &block.link {
+                    Some(l) => l.link.starts_with('#'),
+                    None => continue
+                };
+                if !internal {continue}
+
+                block.link = block.link.take().and_then(|mut l| {
+                    l.link = ids.get(&l.link)?.clone();
+                    Some(l)
+                });
+            }
+        }
+    });
+}
+
+
+/// Секция не может содержать одновременно текст и подсекции:
+/// текст перед подсекциями выносится в отдельную секцию
+fn fix_nesting(content: Vec<Section>) -> Vec<Section> {
+    let mut result: Vec<Section> = Vec::new();
+    let mut iter = content.into_iter().peekable();
+    let mut is_first = true;
+
+    while let Some(mut section) = iter.next() {
+        if is_notes(&section) {
+            let mut paragraphs: Vec<Paragraph> = Vec::new();
+            for p in section.paragraphs.drain(..) {
+                match p {
+                    Paragraph::Note(mut note) => {
+                        note.level = 1;
+                        paragraphs.push(Paragraph::Note(note))
+                    },
+                    Paragraph::Epigraph(_) | Paragraph::Image(_) if paragraphs.is_empty() => paragraphs.push(p),
+                    // всё остальное в теле примечаний становится отдельным примечанием
+                    p => paragraphs.push(Paragraph::Note(Section {
+                        level: 1,
+                        id: None,
+                        file_name: None,
+                        title: Vec::new(),
+                        paragraphs: vec![p]
+                    }))
+                }
+            };
+            section.paragraphs = paragraphs;
+            section.level = 0;
+            result.push(section);
+            continue
+        };
+
+        // Заголовок тела книги: картинка, заголовок и эпиграфы
+        if section.level == 0 && !is_first {
+            section.level = 1
+        };
+        is_first = false;
+
+        let next_level = match iter.peek() {
+            Some(next) if !is_notes(next) => Some(next.level),
+            _ => None
+        };
+
+        let lead = if section.level == 0 {
+            section.paragraphs.iter()
+                .take_while(|p| matches!(p, Paragraph::Epigraph(_) | Paragraph::Image(_)))
+                .count()
+        } else if next_level.is_some_and(|l| l > section.level) {
+            let mut lead = section.paragraphs.iter()
+                .take_while(|p| matches!(p, Paragraph::Epigraph(_)))
+                .count();
+            for kind in ["image", "annotation"] {
+                match (kind, section.paragraphs.get(lead)) {
+                    ("image", Some(Paragraph::Image(_))) |
+                    ("annotation", Some(Paragraph::Annotation(_))) => lead += 1,
+                    _ => {}
+                }
+            };
+            lead
+        } else {
+            section.paragraphs.len()
+        };
+
+        let rest = section.paragraphs.split_off(lead);
+        let level = if section.level == 0 {1}
+            else {next_level.unwrap_or(section.level + 1).max(section.level + 1)};
+
+        if !section.title.is_empty() || !section.paragraphs.is_empty() {
+            result.push(section);
+        };
+        if !rest.is_empty() {
+            result.push(Section {
+                level,
+                id: None,
+                file_name: None,
+                title: Vec::new(),
+                paragraphs: rest
+            });
+        };
+    };
+
+    result
+}
+
+
+fn set_levels(paragraphs: &mut [Paragraph], level: u8) {
+    for p in paragraphs {
+        match p {
+            Paragraph::Note(s) => {
+                s.level = 1;
+                set_levels(&mut s.title, 2);
+                set_levels(&mut s.paragraphs, 2);
+            },
+            Paragraph::Epigraph(s) | Paragraph::Cite(s) | Paragraph::Annotation(s) => {
+                s.level = level;
+                set_levels(&mut s.title, level);
+                set_levels(&mut s.paragraphs, level);
+            },
+            Paragraph::Poem(poem) => {
+                poem.level = level;
+                set_levels(&mut poem.title, level);
+                set_levels(&mut poem.paragraphs, level);
+                for stanza in &mut poem.stanzas {
+                    stanza.level = level;
+                    set_levels(&mut stanza.title, level);
+                    set_levels(&mut stanza.v, level);
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+pub fn collect_ids(paragraphs: &[Paragraph], ids: &mut Vec<String>) {
+    for p in paragraphs {
+        match p {
+            Paragraph::Note(s) | Paragraph::Epigraph(s) |
+            Paragraph::Cite(s) | Paragraph::Annotation(s) => {
+                ids.extend(s.id.clone());
+                collect_ids(&s.title, ids);
+                collect_ids(&s.paragraphs, ids);
+            },
+            Paragraph::Poem(poem) => {
+                ids.extend(poem.id.clone());
+                collect_ids(&poem.title, ids);
+                collect_ids(&poem.paragraphs, ids);
+                for stanza in &poem.stanzas {
+                    ids.extend(stanza.id.clone());
+                    collect_ids(&stanza.title, ids);
+                    collect_ids(&stanza.v, ids);
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+/// link_map для секций с уже выставленными именами файлов
+pub fn link_map(content: &[Section]) -> HashMap<String, String> {
+    let mut link_map: HashMap<String, String> = HashMap::new();
+    for section in content {
+        let file_name = section.file_name.clone().unwrap_or_default();
+        let mut ids: Vec<String> = section.id.iter().cloned().collect();
+        collect_ids(&section.title, &mut ids);
+        collect_ids(&section.paragraphs, &mut ids);
+        for id in ids {
+            link_map.insert(format!("#{id}"), format!("{file_name}.xhtml#{id}"));
+        }
+    };
+
+    link_map
+}
+
+/// Имена файлов, вложенные уровни и link_map такие же, какие выставит парсер
+fn relink(data: &mut BookData) {
+    data.link_map.clear();
+    let mut counter = 0;
+    for section in &mut data.content {
+        let file_name = if is_notes(section) {
+            section.file_name.clone().unwrap_or_default()
+        } else {
+            counter += 1;
+            let name = format!("section_{}", get_counter_str(counter));
+            section.file_name = Some(name.clone());
+            name
+        };
+
+        set_levels(&mut section.title, section.level + 1);
+        set_levels(&mut section.paragraphs, section.level + 1);
+
+        let mut ids: Vec<String> = section.id.iter().cloned().collect();
+        collect_ids(&section.title, &mut ids);
+        collect_ids(&section.paragraphs, &mut ids);
+        for id in ids {
+            data.link_map.insert(format!("#{id}"), format!("{file_name}.xhtml#{id}"));
+        }
+    }
+}
+
+
+/// Приводит книгу к виду, который можно записать в валидный fb2
+/// и прочитать обратно без изменений
+pub fn normalize(data: &mut BookData) {
+    for section in &mut data.content {
+        clean_paragraphs(&mut section.title, false);
+        clean_paragraphs(&mut section.paragraphs, false);
+    };
+
+    dedup_images(data);
+    data.content = fix_nesting(std::mem::take(&mut data.content));
+    fix_ids(data);
+
+    // после удаления ссылок соседние блоки могли стать одинаковыми
+    for section in &mut data.content {
+        clean_paragraphs(&mut section.title, false);
+        clean_paragraphs(&mut section.paragraphs, false);
+    };
+    relink(data);
+}
@@ -0,0 +1,237 @@
+use quick_xml::escape::{escape, partial_escape};
+
+use crate::fb2_parser::Section;
+use crate::fb2_parser::content_reader::*;
+
+
+pub const TAB: &str = "    ";
+
+
+fn id_attr(id: &Option<String>) -> String {
+    match id {
+        Some(i) if !i.is_empty() => format!(" id=\"{}\"", escape(i.as_str())),
+        _ => String::new()
+    }
+}
+
+fn push_style_tags(s: &mut String, block: &TextBlock, end_tag: bool) {
+    let mut tags: Vec<&str> = Vec::new();
+    if block.strong {tags.push("strong")}
+    if block.emphasis {tags.push("emphasis")}
+    if block.strikethrough {tags.push("strikethrough")}
+    if block.code {tags.push("code")}
+    if block.sup {tags.push("sup")}
+    if block.sub {tags.push("sub")}
+
+    if end_tag {
+        for tag in tags.into_iter().rev() {
+            s.push_str(&format!("</{tag}>"))
+        }
+    } else {
+        for tag in tags {
+            s.push_str(&format!("<{tag}>"))
+        }
+    }
+}
+
+pub fn unwrap_blocks(blocks: &Vec<TextBlock>, tabs: &str, tag: &str) -> String {
+    let mut s = format!("{tabs}<{tag}>");
+
+    for block in blocks {
+        if let Some(link) = &block.link {
+            s.push_str(&format!("<a l:href=\"{}\"", escape(link.link.as_str())));
+            if let Some(t) = &link.link_type {
+                s.push_str(&format!(" type=\"{}\"", escape(t.as_str())))
+            };
+            s.push('>');
+        };
+        push_style_tags(&mut s, block, false);
+        s.push_str(&partial_escape(block.text.as_str()));
+        push_style_tags(&mut s, block, true);
+        if block.link.is_some() {
+            s.push_str("</a>")
+        };
+    };
+
+    s.push_str(&format!("</{tag}>\n"));
+    s
+}
+
+pub fn unwrap_title(title: &Vec<Paragraph>, indent: usize) -> String {
+    if title.is_empty() {
+        return String::new()
+    };
+
+    let tabs = TAB.repeat(indent);
+    let mut s = format!("{tabs}<title>\n");
+    for p in title {
+        s.push_str(&unwrap_paragraph(p, indent + 1))
+    };
+    s.push_str(&format!("{tabs}</title>\n"));
+
+    s
+}
+
+fn unwrap_img(href: &Option<String>, tabs: &str) -> String {
+    match href {
+        Some(h) => format!("{tabs}<image l:href=\"{}\"/>\n", escape(h.as_str())),
+        None => String::new()
+    }
+}
+
+pub fn unwrap_paragraph(paragraph: &Paragraph, indent: usize) -> String {
+    let tabs = TAB.repeat(indent);
+
+    match paragraph {
+        Paragraph::Text(blocks) => unwrap_blocks(blocks, &tabs, "p"),
+        // v бывает только внутри stanza, там он обрабатывается отдельно
+        Paragraph::V(blocks) => unwrap_blocks(blocks, &tabs, "p"),
+        Paragraph::TextAuthor(blocks) => unwrap_blocks(blocks, &tabs, "text-author"),
+        Paragraph::Subtitle(blocks) => unwrap_blocks(blocks, &tabs, "subtitle"),
+        Paragraph::EmptyLine => format!("{tabs}<empty-line/>\n"),
+        Paragraph::Image(href) => unwrap_img(href, &tabs),
+        Paragraph::Epigraph(s) => unwrap_section(s, indent, "epigraph"),
+        Paragraph::Cite(s) => unwrap_section(s, indent, "cite"),
+        Paragraph::Annotation(s) => unwrap_section(s, indent, "annotation"),
+        Paragraph::Note(s) => unwrap_section(s, indent, "section"),
+        Paragraph::Poem(poem) => unwrap_poem(poem, indent)
+    }
+}
+
+fn unwrap_stanza(stanza: &Stanza, indent: usize) -> String {
+    let tabs = TAB.repeat(indent);
+    let mut s = format!("{tabs}<stanza{}>\n", id_attr(&stanza.id));
+    s.push_str(&unwrap_title(&stanza.title, indent + 1));
+
+    for p in &stanza.v {
+        s.push_str(&match p {
+            Paragraph::V(blocks) | Paragraph::Text(blocks) =>
+                unwrap_blocks(blocks, &TAB.repeat(indent + 1), "v"),
+            Paragraph::Subtitle(blocks) =>
+                unwrap_blocks(blocks, &TAB.repeat(indent + 1), "subtitle"),
+            _ => String::new()
+        })
+    };
+
+    s.push_str(&format!("{tabs}</stanza>\n"));
+    s
+}
+
+fn unwrap_poem(poem: &Poem, indent: usize) -> String {
+    let tabs = TAB.repeat(indent);
+    let mut s = format!("{tabs}<poem{}>\n", id_attr(&poem.id));
+    s.push_str(&unwrap_title(&poem.title, indent + 1));
+
+    // эпиграфы идут до строф, всё остальное после
+    for p in &poem.paragraphs {
+        if let Paragraph::Epigraph(_) = p {
+            s.push_str(&unwrap_paragraph(p, indent + 1))
+        }
+    };
+
+    for stanza in &poem.stanzas {
+        s.push_str(&unwrap_stanza(stanza, indent + 1))
+    };
+
+    for p in &poem.paragraphs {
+        match p {
+            Paragraph::Epigraph(_) => {},
+            Paragraph::TextAuthor(blocks) | Paragraph::Text(blocks) =>
+                s.push_str(&unwrap_blocks(blocks, &TAB.repeat(indent + 1), "text-author")),
+            _ => {}
+        }
+    };
+
+    if !poem.date.is_empty() {
+        s.push_str(&unwrap_blocks(&poem.date, &TAB.repeat(indent + 1), "date"))
+    };
+
+    s.push_str(&format!("{tabs}</poem>\n"));
+    s
+}
+
+pub fn unwrap_section(section: &Section, indent: usize, tag: &str) -> String {
+    let tabs = TAB.repeat(indent);
+    let mut s = format!("{tabs}<{tag}{}>\n", id_attr(&section.id));
+    s.push_str(&unwrap_section_content(section, indent + 1));
+    s.push_str(&format!("{tabs}</{tag}>\n"));
+
+    s
+}
+
+pub fn unwrap_section_content(section: &Section, indent: usize) -> String {
+    let mut s = unwrap_title(&section.title, indent);
+    for p in &section.paragraphs {
+        s.push_str(&unwrap_paragraph(p, indent))
+    };
+
+    s
+}
+
+
+/// Собирает тело книги из плоского списка секций, восстанавливая вложенность по level
+pub fn body_builder(sections: &[&Section], name: Option<&str>) -> String {
+    let mut s = match name {
+        Some(n) => format!("{TAB}<body name=\"{}\">\n", escape(n)),
+        None => format!("{TAB}<body>\n")
+    };
+    let base = 2;
+
+    let mut depth: usize = 0;
+    for (i, section) in sections.iter().enumerate() {
+        let level = section.level as usize;
+
+        // Заголовок и эпиграфы тела книги
+        if level == 0 {
+            let indent = base;
+            if i == 0 {
+                s.push_str(&unwrap_title(&section.title, indent));
+            } else if !section.title.is_empty() {
+                s.push_str(&format!("{}<section>\n", TAB.repeat(indent)));
+                s.push_str(&unwrap_title(&section.title, indent + 1));
+                s.push_str(&format!("{}</section>\n", TAB.repeat(indent)));
+            };
+
+            let mut rest: Vec<&Paragraph> = Vec::new();
+            for p in &section.paragraphs {
+                match p {
+                    Paragraph::Epigraph(_) | Paragraph::Image(_) if rest.is_empty() => {
+                        s.push_str(&unwrap_paragraph(p, indent))
+                    },
+                    Paragraph::Note(n) => s.push_str(&unwrap_section(n, indent, "section")),
+                    p => rest.push(p)
+                }
+            };
+
+            if !rest.is_empty() {
+                s.push_str(&format!("{}<section>\n", TAB.repeat(indent)));
+                for p in rest {
+                    s.push_str(&unwrap_paragraph(p, indent + 1))
+                };
+                s.push_str(&format!("{}</section>\n", TAB.repeat(indent)));
+            };
+            continue
+        };
+
+        while depth >= level {
+            depth -= 1;
+            s.push_str(&format!("{}</section>\n", TAB.repeat(base + depth)));
+        };
+        while depth + 1 < level {
+            s.push_str(&format!("{}<section>\n", TAB.repeat(base + depth)));
+            depth += 1;
+        };
+
+        s.push_str(&format!("{}<section{}>\n", TAB.repeat(base + depth), id_attr(&section.id)));
+        depth += 1;
+        s.push_str(&unwrap_section_content(section, base + depth));
+    };
+
+    while depth > 0 {
+        depth -= 1;
+        s.push_str(&format!("{}</section>\n", TAB.repeat(base + depth)));
+    };
+
+    s.push_str(&format!("{TAB}</body>\n"));
+    s
+}
@@ -0,0 +1,357 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use encoding_rs::{Encoding, UTF_8, UTF_16LE, UTF_16BE};
+use quick_xml::escape::{escape, partial_escape};
+use quick_xml::events::Event;
+use quick_xml::reader::Reader;
+
+use crate::fb2_parser::{charset, get_attr};
+
+
+// Порядок элементов title-info по схеме FB2, новые элементы встают на своё место
+const ORDER: [&str; 11] = [
+    "genre", "author", "book-title", "annotation", "keywords", "date",
+    "coverpage", "lang", "src-lang", "translator", "sequence"
+];
+
+
+/// Элемент внутри title-info: имя и где он в тексте
+struct Child {
+    name: String,
+    start: usize,
+    end: usize
+}
+
+/// Текст в кодировке книги. encoding_rs не пишет UTF-16, его собираем сами,
+/// а символы, которых нет в однобайтной кодировке, становятся &#NNNN;
+fn encode(text: &str, encoding: &'static Encoding) -> Vec<u8> {
+    if encoding == UTF_16LE {
+        text.encode_utf16().flat_map(|u| u.to_le_bytes()).collect()
+    } else if encoding == UTF_16BE {
+        text.encode_utf16().flat_map(|u| u.to_be_bytes()).collect()
+    } else {
+        encoding.encode(text).0.into_owned()
+    }
+}
+
+fn find(bytes: &[u8], pattern: &[u8], from: usize) -> Option<usize> {
+    bytes.get(from..)?
+        .windows(pattern.len())
+        .position(|w| w == pattern)
+        .map(|i| i + from)
+}
+
+/// Вложенные элементы: с отступами, в книге без отступов - в одну строку
+fn nest(tag: &str, parts: &[String], indent: &str, unit: &str) -> String {
+    if unit.is_empty() {
+        return format!("<{tag}>{}</{tag}>", parts.concat())
+    };
+    let inner = format!("\n{indent}{unit}");
+
+    format!("<{tag}>{inner}{}\n{indent}</{tag}>", parts.join(&inner))
+}
+
+/// Автор как в fb2_creator: одно слово - ник, иначе имя, отчество и фамилия
+fn render_author(author: &str, indent: &str, unit: &str) -> Option<String> {
+    let words: Vec<&str> = author.split_whitespace().collect();
+    let parts = match words.len() {
+        0 => return None,
+        1 => vec![format!("<nickname>{}</nickname>", partial_escape(words[0]))],
+        n => {
+            let mut parts = vec![format!("<first-name>{}</first-name>", partial_escape(words[0]))];
+            if n > 2 {
+                parts.push(format!("<middle-name>{}</middle-name>", partial_escape(words[1..n - 1].join(" "))))
+            };
+            parts.push(format!("<last-name>{}</last-name>", partial_escape(words[n - 1])));
+            parts
+        }
+    };
+
+    Some(nest("author", &parts, indent, unit))
+}
+
+/// Новые элементы для поля, None - поле не меняется
+fn render(
+    name: &str,
+    meta: &crate::Metadata,
+    sequence: (&str, &str),
+    indent: &str,
+    unit: &str
+) -> Option<Vec<String>> {
+    Some(match name {
+        "genre" => meta.genres.as_ref()?.iter()
+            .map(|g| format!("<genre>{}</genre>", partial_escape(g.trim())))
+            .collect(),
+        "author" => meta.authors.as_ref()?.iter()
+            .filter_map(|a| render_author(a, indent, unit))
+            .collect(),
+        "book-title" => vec![format!("<book-title>{}</book-title>", partial_escape(meta.title.as_ref()?.trim()))],
+        "annotation" => {
+            let paragraphs: Vec<String> = meta.description.as_ref()?.iter()
+                .filter(|p| !p.trim().is_empty())
+                .map(|p| format!("<p>{}</p>", partial_escape(p.trim())))
+                .collect();
+            if paragraphs.is_empty() {Vec::new()}
+            else {vec![nest("annotation", &paragraphs, indent, unit)]}
+        },
+        "lang" => vec![format!("<lang>{}</lang>", partial_escape(meta.language.as_ref()?.trim()))],
+        "sequence" => {
+            if meta.series.is_none() && meta.series_index.is_none() {return None}
+            // меняется только то, что задано, остальное - из старой серии
+            let name = meta.series.as_deref().unwrap_or(sequence.0).trim();
+            let number = meta.series_index.as_deref().unwrap_or(sequence.1).trim();
+            match (name, number) {
+                ("", _) => Vec::new(),
+                (name, "") => vec![format!("<sequence name=\"{}\"/>", escape(name))],
+                (name, number) => vec![format!("<sequence name=\"{}\" number=\"{}\"/>", escape(name), escape(number))]
+            }
+        },
+        _ => return None
+    })
+}
+
+/// Переписывает поля title-info, всё остальное остаётся как было
+fn edit_title_info(xml: &str, meta: &crate::Metadata) -> Result<String, Box<dyn std::error::Error>> {
+    let mut reader = Reader::from_str(xml);
+    reader.config_mut().check_end_names = false;
+    let decoder = reader.decoder();
+
+    let mut children: Vec<Child> = Vec::new();
+    let mut content_start = 0;
+    let mut sequence = (String::new(), String::new());
+    let mut depth = 0;
+    let mut child_start = 0;
+    loop {
+        let before = reader.buffer_position() as usize;
+        let event = reader.read_event()?;
+        let after = reader.buffer_position() as usize;
+        match event {
+            Event::Start(e) => {
+                depth += 1;
+                if depth == 1 {content_start = after}
+                if depth == 2 {child_start = before}
+                if depth == 2 && e.local_name().as_ref() == b"sequence" && sequence.0.is_empty() {
+                    sequence = (get_attr(&e, "name", decoder), get_attr(&e, "number", decoder))
+                }
+            },
+            Event::End(e) => {
+                if depth == 2 {
+                    let name = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
+                    children.push(Child {name, start: child_start, end: after})
+                };
+                depth -= 1;
+                if depth == 0 {break}
+            },
+            Event::Empty(e) if depth == 1 => {
+                let name = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
+                if name == "sequence" && sequence.0.is_empty() {
+                    sequence = (get_attr(&e, "name", decoder), get_attr(&e, "number", decoder))
+                };
+                children.push(Child {name, start: before, end: after})
+            },
+            Event::Eof => return Err("title-info is not closed".into()),
+            _ => {}
+        }
+    };
+
+    // Отступ берётся из книги, по первому элементу
+    let indent = match children.first() {
+        Some(child) => xml[content_start..child.start].rsplit('\n').next().unwrap_or_default(),
+        None => ""
+    };
+    let indent = if indent.trim().is_empty() {indent} else {""};
+    let unit = if indent.is_empty() {""}
+        else if indent.starts_with('\t') {"\t"}
+        else {&indent[..indent.len().min(2)]};
+    let newline = format!("\n{indent}");
+
+    let mut result = String::new();
+    let mut cursor = 0;
+    let mut placed: Vec<&str> = Vec::new();
+    let insert_missing = |result: &mut String, before: Option<usize>, placed: &mut Vec<&str>| {
+        for (i, name) in ORDER.iter().enumerate() {
+            if placed.contains(name) || before.is_some_and(|b| i >= b) {continue}
+            let elements = match render(name, meta, (&sequence.0, &sequence.1), indent, unit) {
+                Some(e) => e,
+                None => continue
+            };
+            placed.push(name);
+            for element in elements {
+                if before.is_some() {
+                    result.push_str(&element);
+                    result.push_str(&newline)
+                } else {
+                    result.push_str(&newline);
+                    result.push_str(&element)
+                }
+            }
+        }
+    };
+
+    for child in &children {
+        let position = ORDER.iter().position(|n| *n == child.name);
+        let new = render(&child.name, meta, (&sequence.0, &sequence.1), indent, unit);
+        if new.is_none() {
+            // поля, которые в книге идут раньше, но которых в ней нет
+            if let Some(position) = position {
+                result.push_str(&xml[cursor..child.start]);
+                cursor = child.start;
+                insert_missing(&mut result, Some(position), &mut placed);
+            };
+            continue
+        };
+
+        let name = ORDER[position.unwrap_or_default()];
+        if placed.contains(&name) {
+            // повторы того же поля удаляются вместе с отступом перед ними
+            let gap = &xml[cursor..child.start];
+            result.push_str(gap.trim_end());
+        } else {
+            result.push_str(&xml[cursor..child.start]);
+            insert_missing(&mut result, position, &mut placed);
+            placed.push(name);
+            let elements = new.unwrap_or_default();
+            if elements.is_empty() {
+                // поле очищено: убираем и отступ
+                let trimmed = result.trim_end().len();
+                result.truncate(trimmed);
+            };
+            result.push_str(&elements.join(&newline));
+        };
+        cursor = child.end;
+    };
+    let last_end = children.last().map(|c| c.end).unwrap_or(content_start);
+    result.push_str(&xml[cursor..last_end]);
+    insert_missing(&mut result, None, &mut placed);
+    result.push_str(&xml[last_end..]);
+
+    Ok(result)
+}
+
+
+/// То же для исходного description книги, прочитанной fb2_parser
+pub fn edit_description(description: &str, meta: &crate::Metadata) -> Result<String, Box<dyn std::error::Error>> {
+    let start = description.find("<title-info").ok_or("There's no title-info in the book")?;
+    let end_tag = "</title-info>";
+    let end = description[start..].find(end_tag).ok_or("title-info is not closed")? + start + end_tag.len();
+
+    Ok(format!("{}{}{}", &description[..start], edit_title_info(&description[start..end], meta)?, &description[end..]))
+}
+
+/// Меняет описание книги в байтах fb2: title-info переписывается в кодировке книги,
+/// остальные байты не меняются
+pub fn edit_bytes(bytes: &[u8], meta: &crate::Metadata) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let decoded = charset::decode(bytes);
+    let encoding = Encoding::for_label(decoded.encoding.as_bytes()).unwrap_or(UTF_8);
+
+    let start = find(bytes, &encode("<title-info", encoding), 0)
+        .ok_or("There's no title-info in the book")?;
+    let end_tag = encode("</title-info>", encoding);
+    let end = find(bytes, &end_tag, start)
+        .ok_or("title-info is not closed")? + end_tag.len();
+
+    let (xml, _) = encoding.decode_without_bom_handling(&bytes[start..end]);
+    let new_xml = edit_title_info(&xml, meta)?;
+
+    let mut result = Vec::with_capacity(bytes.len() + new_xml.len());
+    result.extend_from_slice(&bytes[..start]);
+    result.extend_from_slice(&encode(&new_xml, encoding));
+    result.extend_from_slice(&bytes[end..]);
+
+    // книга должна остаться читаемой
+    crate::fb2_parser::get_meta_from_bytes(&result)?;
+
+    Ok(result)
+}
+
+/// Меняет описание книги и пишет её в output через временный файл в той же папке
+pub fn edit(book: &Path, output: &Path, meta: &crate::Metadata) -> Result<(), Box<dyn std::error::Error>> {
+    let bytes = fs::read(book)?;
+    let edited = edit_bytes(&bytes, meta)
+        .map_err(|err| format!("Cannot edit {:#?}: {err}", book))?;
+
+    let folder = match output.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new(".")
+    };
+    let mut temp = tempfile::NamedTempFile::new_in(folder)?;
+    temp.write_all(&edited)?;
+    // временный файл создаётся только для владельца, а книга сохраняет свои права
+    temp.as_file().set_permissions(fs::metadata(book)?.permissions())?;
+    temp.persist(output)?;
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use encoding_rs::{Encoding, WINDOWS_1251};
+
+    use super::{edit, edit_bytes, find};
+    use crate::Metadata;
+
+    const CP1251: &[u8] = include_bytes!("../tests/fixtures/charset/cp1251.fb2");
+    const UTF8: &[u8] = include_bytes!("../tests/fixtures/charset/utf8.fb2");
+
+    fn meta() -> Metadata {
+        Metadata {
+            title: Some(String::from("Серый волк ✓")),
+            authors: Some(vec![String::from("Иван Петрович Сидоров")]),
+            series: Some(String::from("Сказки")),
+            ..Metadata::default()
+        }
+    }
+
+    /// Меняется только title-info: байты до него и тело те же, кодировка книги остаётся
+    fn check(book: &[u8], encoding: &'static Encoding) {
+        let edited = edit_bytes(book, &meta()).unwrap();
+
+        let start = find(book, b"<title-info", 0).unwrap();
+        assert_eq!(edited[..start], book[..start]);
+        let body = find(book, b"</title-info>", 0).unwrap();
+        let edited_body = find(&edited, b"</title-info>", 0).unwrap();
+        assert_eq!(edited[edited_body..], book[body..]);
+
+        let (text, had_errors) = encoding.decode_without_bom_handling(&edited);
+        assert!(!had_errors);
+        assert!(text.contains("<book-title>Серый волк"));
+        assert!(text.contains("<middle-name>Петрович</middle-name>"));
+        assert!(text.contains("<sequence name=\"Сказки\"/>"));
+
+        let data = crate::fb2_parser::get_meta_from_bytes(&edited).unwrap();
+        assert_eq!(data.meta.title, "Серый волк ✓");
+        assert_eq!(data.encoding.as_deref(), Some(encoding.name()));
+    }
+
+    #[test]
+    fn cp1251_book_stays_cp1251() {
+        check(CP1251, WINDOWS_1251);
+        // символа нет в windows-1251, он пишется ссылкой
+        let edited = edit_bytes(CP1251, &meta()).unwrap();
+        assert!(find(&edited, "✓".as_bytes(), 0).is_none());
+        assert!(find(&edited, b"&#10003;", 0).is_some());
+    }
+
+    #[test]
+    fn utf8_book_stays_utf8() {
+        check(UTF8, encoding_rs::UTF_8);
+    }
+
+    /// После правки на месте у книги те же права, что и до неё
+    #[cfg(unix)]
+    #[test]
+    fn edit_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let book = dir.path().join("book.fb2");
+        std::fs::write(&book, UTF8).unwrap();
+        std::fs::set_permissions(&book, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        edit(&book, &book, &meta()).unwrap();
+        assert_eq!(std::fs::metadata(&book).unwrap().permissions().mode() & 0o777, 0o644);
+    }
+}
@@ -1,15 +1,16 @@
 pub mod metadata_reader;
 pub mod content_reader;
 pub mod binary_reader;
+pub mod charset;
 
 
 use std::path::Path;
-use std::fs::File;
-use std::io::BufReader;
+use std::fs;
+use std::io::BufRead;
 use std::collections::HashMap;
 
 use quick_xml::reader::Reader;
-use quick_xml::events::BytesStart;
+use quick_xml::events::{BytesStart, BytesRef};
 use quick_xml::encoding::Decoder;
 
 
@@ -26,12 +27,15 @@ pub struct BookData {
     pub meta: Metadata,
     pub content: Vec<Section>,
     pub images: HashMap<String, Image>,
-    pub link_map: HashMap<String, String>
+    pub link_map: HashMap<String, String>,
+    pub encoding: Option<String>,     // в какой кодировке была прочитана книга
+    pub warnings: Vec<String>,
+    pub description: Option<String>   // исходный <description> fb2 как есть, его пишет fb2_creator
 } // в images будут биннарные данные изображений
   // в link_map первое значение это ссылка как она была в fb2
   // второе значение - новая ссылка
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Image {
     pub id: String,
     pub content_type: String,
@@ -56,7 +60,7 @@ fn get_href(e: &BytesStart, decoder: Decoder) -> Option<String> {
     return None
 }
 
-fn get_attr(e: &BytesStart, query: &str, decoder: Decoder) -> String {
+pub fn get_attr(e: &BytesStart, query: &str, decoder: Decoder) -> String {
     match e.try_get_attribute(query) {
         Ok(Some(attr)) => {
             attr
@@ -70,6 +74,62 @@ fn get_attr(e: &BytesStart, query: &str, decoder: Decoder) -> String {
     }
 }
 
+/// id в fb2 должен быть NCName
+pub fn sanitize_id(id: &str) -> String {
+    let mut s: String = id.chars()
+        .map(|c| if c.is_alphanumeric() || c == '_' || c == '-' || c == '.' {c} else {'_'})
+        .collect();
+    if !s.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+        s.insert(0, '_')
+    };
+
+    s
+}
+
+/// Предопределённые сущности XML и самые частые из HTML
+pub fn resolve_entity(name: &str) -> Option<&'static str> {
+    if let Some(s) = quick_xml::escape::resolve_predefined_entity(name) {
+        return Some(s)
+    };
+
+    Some(match name {
+        "nbsp" => "\u{a0}",
+        "shy" => "\u{ad}",
+        "mdash" => "—",
+        "ndash" => "–",
+        "hellip" => "…",
+        "laquo" => "«",
+        "raquo" => "»",
+        "ldquo" => "“",
+        "rdquo" => "”",
+        "bdquo" => "„",
+        "lsquo" => "‘",
+        "rsquo" => "’",
+        "copy" => "©",
+        "reg" => "®",
+        "trade" => "™",
+        "deg" => "°",
+        "middot" => "·",
+        "bull" => "•",
+        "sect" => "§",
+        "times" => "×",
+        "minus" => "−",
+        "thinsp" => "\u{2009}",
+        "ensp" => "\u{2002}",
+        "emsp" => "\u{2003}",
+        _ => return None
+    })
+}
+
+/// Текст ссылки на символ (&#160;) или сущность (&amp;)
+pub fn resolve_ref(e: &BytesRef) -> Option<String> {
+    if let Ok(Some(c)) = e.resolve_char_ref() {
+        return Some(c.to_string())
+    };
+    
+    resolve_entity(&e.decode().ok()?).map(|s| s.to_string())
+}
+
 pub fn get_counter_str(c: usize) -> String {
     if c < 10 {
         format!("00{c}")
@@ -83,9 +143,57 @@ pub fn get_counter_str(c: usize) -> String {
 
 pub fn get_data(book: &Path
 ) -> Result<BookData, Box<dyn std::error::Error>> {
-    let file = File::open(book)?;
-    let reader = BufReader::new(file);
+    get_data_from_bytes(&fs::read(book)?)
+}
+
+/// Определяет кодировку, перекодирует в UTF-8 и читает книгу
+pub fn get_data_from_bytes(bytes: &[u8]
+) -> Result<BookData, Box<dyn std::error::Error>> {
+    let decoded = charset::decode(bytes);
+    let mut data = get_data_from_reader(decoded.text.as_bytes())?;
+    data.encoding = Some(decoded.encoding.to_string());
+    data.warnings.extend(decoded.warning);
+    data.description = get_description(&decoded.text);
+
+    Ok(data)
+}
+
+/// Исходный элемент description с отступом перед ним: в нём есть и поля, которых нет в Metadata
+fn get_description(text: &str) -> Option<String> {
+    let end_tag = "</description>";
+    let start = text.find("<description")?;
+    let end = text[start..].find(end_tag)? + start + end_tag.len();
+    let line_start = text[..start].rfind('\n').map(|i| i + 1).unwrap_or(start);
+    let start = if text[line_start..start].trim().is_empty() {line_start} else {start};
+
+    Some(text[start..end].to_string())
+}
+
+/// Только описание книги, текст не разбирается
+pub fn get_meta_from_bytes(bytes: &[u8]
+) -> Result<BookData, Box<dyn std::error::Error>> {
+    let decoded = charset::decode(bytes);
+    let mut xml_reader = Reader::from_reader(decoded.text.as_bytes());
+    xml_reader.config_mut().check_end_names = false;
+    let mut buf = Vec::new();
+
+    Ok(BookData {
+        meta: metadata_reader(&mut xml_reader, &mut buf)?,
+        content: Vec::new(),
+        images: HashMap::new(),
+        link_map: HashMap::new(),
+        encoding: Some(decoded.encoding.to_string()),
+        warnings: decoded.warning.into_iter().collect(),
+        description: None
+    })
+}
+
+/// Читает книгу из потока как есть, кодировку определяет quick-xml по объявлению
+pub fn get_data_from_reader<R: BufRead>(reader: R
+) -> Result<BookData, Box<dyn std::error::Error>> {
     let mut xml_reader = Reader::from_reader(reader);
+    // битые fb2 часто закрывают теги не по порядку
+    xml_reader.config_mut().check_end_names = false;
     let mut buf = Vec::new();
     let sections_counter = 0;
     
@@ -94,7 +202,10 @@ pub fn get_data(book: &Path
         meta: metadata_reader(&mut xml_reader, &mut buf)?,
         content: Vec::new(),
         images: HashMap::new(),
-        link_map: HashMap::new()
+        link_map: HashMap::new(),
+        encoding: None,
+        warnings: Vec::new(),
+        description: None
     };
     content_reader(&mut data,
         &mut xml_reader,
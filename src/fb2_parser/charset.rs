@@ -0,0 +1,230 @@
+use encoding_rs::{Encoding, UTF_8, UTF_16LE, UTF_16BE, WINDOWS_1251, KOI8_R, IBM866, ISO_8859_5, X_MAC_CYRILLIC};
+
+
+pub struct Decoded {
+    pub text: String,
+    pub encoding: &'static str,
+    pub warning: Option<String>
+}
+
+
+/// Кодировка из <?xml ... encoding="..."?>
+fn declared_encoding(bytes: &[u8]) -> Option<String> {
+    let head = String::from_utf8_lossy(&bytes[..bytes.len().min(256)]).to_string();
+    let declaration = &head[head.find("<?xml")?..];
+    let declaration = &declaration[..declaration.find("?>")?];
+
+    let start = declaration.find("encoding")? + "encoding".len();
+    let rest = declaration[start..].trim_start().strip_prefix('=')?.trim_start();
+    let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let value = &rest[1..];
+
+    Some(value[..value.find(quote)?].trim().to_string())
+}
+
+/// UTF-16 без BOM узнаётся по нулевым байтам вокруг '<'
+fn utf16_without_bom(bytes: &[u8]) -> Option<&'static Encoding> {
+    match bytes {
+        [b'<', 0, _, 0, ..] => Some(UTF_16LE),
+        [0, b'<', 0, _, ..] => Some(UTF_16BE),
+        _ => None
+    }
+}
+
+/// Правдоподобность русского текста: строчные кириллические буквы - хорошо,
+/// заглавные посреди слова (типичные кракозябры вроде "РџСЂРёРІРµС‚") - плохо
+fn cyrillic_score(text: &str) -> i64 {
+    let mut score = 0;
+    let mut previous_is_letter = false;
+    for c in text.chars() {
+        let is_cyrillic = ('\u{0400}'..='\u{04FF}').contains(&c);
+        if is_cyrillic && c.is_lowercase() {
+            score += 1
+        } else if is_cyrillic && previous_is_letter {
+            score -= 3
+        } else if matches!(c, '\u{2500}'..='\u{25FF}' | '\u{00A0}'..='\u{00BF}') && previous_is_letter {
+            // псевдографика и знаки из верхней половины таблицы внутри слова
+            score -= 3
+        };
+        previous_is_letter = c.is_alphabetic();
+    };
+
+    score
+}
+
+fn looks_broken(text: &str) -> bool {
+    let mut good = 0;
+    let mut bad = 0;
+    let mut previous_is_letter = false;
+    for c in text.chars() {
+        if ('\u{0400}'..='\u{04FF}').contains(&c) {
+            if c.is_lowercase() {good += 1}
+            else if previous_is_letter {bad += 1}
+        };
+        previous_is_letter = c.is_alphabetic();
+    };
+
+    bad > 20 && bad * 5 > good
+}
+
+/// Меняет кодировку в объявлении на UTF-8, текст уже раскодирован
+fn fix_declaration(text: &mut String) {
+    let end = match text.find("?>") {
+        Some(e) if text.trim_start().starts_with("<?xml") => e,
+        _ => return
+    };
+    let declaration = &text[..end];
+    let start = match declaration.find("encoding") {
+        Some(s) => s,
+        None => return
+    };
+    let value_start = match declaration[start..].find(['"', '\'']) {
+        Some(i) => start + i + 1,
+        None => return
+    };
+    let quote = &declaration[value_start - 1..value_start];
+    let value_end = match declaration[value_start..].find(quote) {
+        Some(i) => value_start + i,
+        None => return
+    };
+
+    text.replace_range(value_start..value_end, "UTF-8");
+}
+
+
+/// Определяет кодировку книги: BOM, объявление, иначе статистика по кириллице
+pub fn decode(bytes: &[u8]) -> Decoded {
+    let mut decoded = decode_text(bytes);
+    decoded.text = decoded.text.trim_start_matches('\u{feff}').to_string();
+    fix_declaration(&mut decoded.text);
+
+    decoded
+}
+
+fn decode_text(bytes: &[u8]) -> Decoded {
+    if let Some((encoding, bom_length)) = Encoding::for_bom(bytes) {
+        let (text, _) = encoding.decode_without_bom_handling(&bytes[bom_length..]);
+        return Decoded {text: text.into_owned(), encoding: encoding.name(), warning: None}
+    };
+
+    if let Some(encoding) = utf16_without_bom(bytes) {
+        let (text, _) = encoding.decode_without_bom_handling(bytes);
+        return Decoded {text: text.into_owned(), encoding: encoding.name(), warning: None}
+    };
+
+    // Без объявления по стандарту XML - UTF-8
+    let label = declared_encoding(bytes);
+    let declared = match &label {
+        Some(l) => Encoding::for_label(l.as_bytes()).unwrap_or(UTF_8),
+        None => UTF_8
+    };
+    // UTF-16 в объявлении без нулевых байт - неправда
+    let declared = if declared == UTF_16LE || declared == UTF_16BE {UTF_8} else {declared};
+
+    let (text, had_errors) = declared.decode_without_bom_handling(bytes);
+    if !had_errors && !looks_broken(&text) {
+        return Decoded {text: text.into_owned(), encoding: declared.name(), warning: None}
+    };
+
+    // Перебор кириллических кодировок
+    let mut best: Option<(i64, &'static Encoding, String)> = None;
+    for encoding in [UTF_8, WINDOWS_1251, KOI8_R, IBM866, ISO_8859_5, X_MAC_CYRILLIC] {
+        let (text, had_errors) = encoding.decode_without_bom_handling(bytes);
+        if had_errors {continue}
+
+        let score = cyrillic_score(&text);
+        if best.as_ref().is_none_or(|(s, _, _)| score > *s) {
+            best = Some((score, encoding, text.into_owned()))
+        }
+    };
+
+    match best {
+        Some((_, encoding, text)) if encoding != declared => Decoded {
+            text,
+            encoding: encoding.name(),
+            warning: Some(match label {
+                Some(l) => format!("Declared encoding {l} is wrong, used {}", encoding.name()),
+                None => format!("There's no encoding declaration, used {}", encoding.name())
+            })
+        },
+        _ => Decoded {
+            text: text.into_owned(),
+            encoding: declared.name(),
+            warning: had_errors.then(|| format!("Some characters cannot be decoded as {}", declared.name()))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, looks_broken, cyrillic_score};
+
+    const TEXT: &str = "Жил-был у бабушки серенький козлик";
+
+    /// Книга раскодирована верно, объявление исправлено на UTF-8
+    fn check(bytes: &[u8], encoding: &str, warning: Option<&str>) {
+        let decoded = decode(bytes);
+        assert_eq!(decoded.encoding, encoding);
+        assert_eq!(decoded.warning.as_deref(), warning);
+        assert!(decoded.text.contains(TEXT), "{encoding}: {}", decoded.text);
+        assert!(!decoded.text.starts_with('\u{feff}'));
+        if decoded.text.starts_with("<?xml") {
+            assert!(decoded.text.contains(r#"encoding="UTF-8""#))
+        }
+    }
+
+    #[test]
+    fn utf8() {
+        check(include_bytes!("../../tests/fixtures/charset/utf8.fb2"), "UTF-8", None);
+        check(include_bytes!("../../tests/fixtures/charset/utf8-bom.fb2"), "UTF-8", None);
+    }
+
+    #[test]
+    fn utf16_with_bom() {
+        check(include_bytes!("../../tests/fixtures/charset/utf16le-bom.fb2"), "UTF-16LE", None);
+        check(include_bytes!("../../tests/fixtures/charset/utf16be-bom.fb2"), "UTF-16BE", None);
+    }
+
+    #[test]
+    fn utf16_without_bom() {
+        check(include_bytes!("../../tests/fixtures/charset/utf16le.fb2"), "UTF-16LE", None);
+        check(include_bytes!("../../tests/fixtures/charset/utf16be.fb2"), "UTF-16BE", None);
+    }
+
+    #[test]
+    fn declared_cyrillic() {
+        check(include_bytes!("../../tests/fixtures/charset/cp1251.fb2"), "windows-1251", None);
+        check(include_bytes!("../../tests/fixtures/charset/koi8r.fb2"), "KOI8-R", None);
+    }
+
+    #[test]
+    fn wrong_declaration() {
+        check(
+            include_bytes!("../../tests/fixtures/charset/mislabeled.fb2"),
+            "KOI8-R",
+            Some("Declared encoding windows-1251 is wrong, used KOI8-R")
+        );
+    }
+
+    #[test]
+    fn no_declaration() {
+        check(
+            include_bytes!("../../tests/fixtures/charset/undeclared.fb2"),
+            "windows-1251",
+            Some("There's no encoding declaration, used windows-1251")
+        );
+    }
+
+    #[test]
+    fn mojibake_is_broken() {
+        let good = "Бабушка козлика очень любила, вот как, вот как, очень любила. ".repeat(3);
+        // тот же текст в UTF-8, прочитанный как windows-1251
+        let (broken, _) = encoding_rs::WINDOWS_1251.decode_without_bom_handling(good.as_bytes());
+
+        assert!(!looks_broken(&good));
+        assert!(looks_broken(&broken));
+        assert!(cyrillic_score(&good) > 0);
+        assert!(cyrillic_score(&broken) < cyrillic_score(&good));
+    }
+}
@@ -3,7 +3,7 @@ use std::io::BufRead;
 use quick_xml::events::Event;
 use quick_xml::reader::Reader;
 
-use crate::fb2_parser::{get_href, get_attr};
+use crate::fb2_parser::{get_href, get_attr, resolve_ref};
 use crate::fb2_parser::binary_reader::binary_reader;
 use crate::fb2_parser::get_counter_str;
 
@@ -70,6 +70,44 @@ pub struct Section {
 }
 
 
+/// Добавляет текст, склеивая его с предыдущим блоком того же стиля
+fn push_text(blocks: &mut Vec<TextBlock>, mut block: TextBlock) {
+    // пробелы между тегами нужны только внутри абзаца
+    if block.text.trim().is_empty() {
+        if blocks.is_empty() {return}
+        block.text = String::from(" ");
+    };
+    
+    if let Some(last) = blocks.last_mut()
+        && last.strong == block.strong
+        && last.emphasis == block.emphasis
+        && last.strikethrough == block.strikethrough
+        && last.code == block.code
+        && last.sup == block.sup
+        && last.sub == block.sub
+        && last.link == block.link {
+        last.text.push_str(&block.text);
+        return
+    };
+    
+    blocks.push(block)
+}
+
+/// Убирает пробелы в конце абзаца
+fn trim_blocks(blocks: &mut Vec<TextBlock>) {
+    while let Some(last) = blocks.last_mut() {
+        let trimmed = last.text.trim_end().len();
+        last.text.truncate(trimmed);
+        
+        if last.text.is_empty() {
+            blocks.pop();
+        } else {
+            break
+        }
+    }
+}
+
+
 pub fn content_reader<R>(
         b_data: &mut super::BookData,
         xml_reader: &mut Reader<R>,
@@ -131,13 +169,12 @@ pub fn content_reader<R>(
             Ok(Event::Start(ref e)) => {
                 match e.name().as_ref() {
                     b"section" => {
-                        section_id.push(get_attr(e, "id", decoder));
-                        
                         if !paragraphs.is_empty() | !title.is_empty() {
                             sections.push(Section {
                                 level: level,
+                                // id родителя забирается, продолжение после подсекций будет без него
                                 id: if level > 0 {
-                                    match section_id.pop() {
+                                    match section_id.last_mut().map(std::mem::take) {
                                         Some(id) if id.is_empty() => None,
                                         Some(id) => {
                                             let l_id = format!("#{id}");
@@ -166,6 +203,7 @@ pub fn content_reader<R>(
                             paragraphs.clear();
                         };
                         
+                        section_id.push(get_attr(e, "id", decoder));
                         level += 1;
                     },
                     b"title" => {
@@ -230,7 +268,8 @@ pub fn content_reader<R>(
                         title.clear();
                         paragraphs.clear();
                     },
-                    b"v" if in_stanza => in_v = true,
+                    // v вне строфы читается как обычный абзац
+                    b"v" => in_v = true,
                     b"date" if in_poem => in_date = true,
                     
                     b"image" => {
@@ -285,6 +324,7 @@ pub fn content_reader<R>(
                     },
                     b"subtitle" => {
                         in_subtitle = false;
+                        trim_blocks(&mut paragraph);
                         if !paragraph.is_empty() {
                             paragraphs.push(Paragraph::Subtitle(paragraph.clone()));
                             paragraph.clear();
@@ -292,6 +332,7 @@ pub fn content_reader<R>(
                     },
                     b"p" => {
                         in_p = false;
+                        trim_blocks(&mut paragraph);
                         if !paragraph.is_empty() {
                             paragraphs.push(Paragraph::Text(paragraph.clone()));
                             paragraph.clear();
@@ -304,9 +345,11 @@ pub fn content_reader<R>(
                     b"code" => code = false,
                     b"sup" => sup = false,
                     b"sub" => sub = false,
+                    b"a" => link = None,
 
                     b"text-author" => {
                         in_text_author = false;
+                        trim_blocks(&mut paragraph);
                         if !paragraph.is_empty() {
                             paragraphs.push(Paragraph::TextAuthor(paragraph.clone()));
                             paragraph.clear();
@@ -401,47 +444,48 @@ pub fn content_reader<R>(
                     },
                     b"v" => {
                         in_v = false;
+                        trim_blocks(&mut paragraph);
                         if !paragraph.is_empty() {
-                            paragraphs.push(Paragraph::V(paragraph.clone()));
+                            paragraphs.push(if in_stanza {Paragraph::V(paragraph.clone())}
+                                else {Paragraph::Text(paragraph.clone())});
                             paragraph.clear();
                         }
                     },
-                    b"date" => in_date = false,
+                    b"date" => {
+                        in_date = false;
+                        trim_blocks(&mut date);
+                    },
                     _ => {}
                 }
             }
             
-            Ok(Event::Text(e)) => {
-                let text = e
-                    .decode()?
-                    .into_owned();
+            Ok(ref event @ (Event::Text(_) | Event::GeneralRef(_))) => {
+                let text = match event {
+                    Event::Text(e) => e.decode()?.into_owned(),
+                    Event::GeneralRef(e) => match resolve_ref(e) {
+                        Some(s) => s,
+                        None => {
+                            buf.clear();
+                            continue
+                        }
+                    },
+                    _ => String::new()
+                };
                 
-                if !text.trim().is_empty() {
-                    if in_date {
-                        date.push(TextBlock {
-                            text: text,
-                            strong,
-                            emphasis,
-                            strikethrough,
-                            code,
-                            sup,
-                            sub,
-                            link: link.clone()
-                        });
-                        link = None;
-                    } else if in_p || in_v || in_text_author || in_subtitle || in_title {
-                        paragraph.push(TextBlock {
-                            text: text,
-                            strong,
-                            emphasis,
-                            strikethrough,
-                            code,
-                            sup,
-                            sub,
-                            link: link.clone()
-                        });
-                        link = None;
-                    }
+                let block = TextBlock {
+                    text,
+                    strong,
+                    emphasis,
+                    strikethrough,
+                    code,
+                    sup,
+                    sub,
+                    link: link.clone()
+                };
+                if in_date {
+                    push_text(&mut date, block);
+                } else if in_p || in_v || in_text_author || in_subtitle || in_title {
+                    push_text(&mut paragraph, block);
                 }
             }
             
@@ -3,23 +3,25 @@ use std::io::BufRead;
 use quick_xml::events::Event;
 use quick_xml::reader::Reader;
 
-use crate::fb2_parser::get_href;
+use crate::fb2_parser::{get_href, resolve_ref};
 
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Sequence {
     pub name: String,
     pub number: String
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Metadata {
     pub title: String,
     pub authors: Vec<String>,
+    pub genres: Vec<String>,
     pub language: String,
     pub sequence: Option<Sequence>,
     pub annotation: Option<Vec<String>>,
-    pub cover: Option<String>
+    pub cover: Option<String>,
+    pub id: Option<String>       // id из document-info
 }
 
 
@@ -31,27 +33,38 @@ pub fn metadata_reader<R>(
     let mut meta = Metadata {
         title: String::new(),
         authors: Vec::new(),
+        genres: Vec::new(),
         language: String::new(),
         sequence: None,
         annotation: None,
-        cover: None
+        cover: None,
+        id: None
     };
     
     let mut in_title_info = false;
+    let mut in_document_info = false;
     
     let mut in_title = false;
+    let mut in_genre = false;
+    let mut in_id = false;
     
     let mut in_author = false;
     let mut in_first_name = false;
     let mut in_middle_name = false;
     let mut in_last_name = false;
+    let mut in_nickname = false;
     let mut current_author = String::new();
     let mut first_name = String::new();
     let mut middle_name = String::new();
     let mut last_name = String::new();
+    let mut nickname = String::new();
     
     let mut in_annotation = false;
     let mut annotation: Vec<String> = Vec::new();
+    let mut annotation_line = String::new();
+    
+    let mut genre = String::new();
+    let mut id = String::new();
     
     let mut in_lang = false;
     let mut in_cover = false;
@@ -67,12 +80,16 @@ pub fn metadata_reader<R>(
             Ok(Event::Start(ref e)) => {
                 match e.name().as_ref() {
                     b"title-info" => in_title_info = true,
+                    b"document-info" => in_document_info = true,
                     b"book-title" if in_title_info => in_title = true,
+                    b"genre" if in_title_info => in_genre = true,
+                    b"id" if in_document_info => in_id = true,
                     
                     b"author" if in_title_info => in_author = true,
                     b"first-name" if in_author => in_first_name = true,
                     b"middle-name" if in_author => in_middle_name = true,
                     b"last-name" if in_author => in_last_name = true,
+                    b"nickname" if in_author => in_nickname = true,
                     
                     b"annotation" if in_title_info => in_annotation = true,
                     b"lang" if in_title_info => in_lang = true,
@@ -85,7 +102,22 @@ pub fn metadata_reader<R>(
             Ok(Event::End(ref e)) => {
                 match e.name().as_ref() {
                     b"title-info" => in_title_info = false,
+                    b"document-info" => in_document_info = false,
                     b"book-title" => in_title = false,
+                    b"genre" => {
+                        in_genre = false;
+                        if !genre.trim().is_empty() {
+                            meta.genres.push(genre.trim().to_string());
+                        };
+                        genre.clear();
+                    },
+                    b"id" => {
+                        in_id = false;
+                        if !id.trim().is_empty() {
+                            meta.id = Some(id.trim().to_string());
+                        };
+                        id.clear();
+                    },
                     b"author" => {
                         in_author = false;
                         
@@ -107,6 +139,12 @@ pub fn metadata_reader<R>(
                             last_name.clear();
                         };
                         
+                        // Псевдоним только если нет имени
+                        if current_author.is_empty() && !nickname.is_empty() {
+                            current_author.push_str(nickname.trim());
+                        };
+                        nickname.clear();
+                        
                         
                         if !current_author.is_empty() {
                             meta.authors
@@ -122,9 +160,21 @@ pub fn metadata_reader<R>(
                     b"first-name" => in_first_name = false,
                     b"middle-name" => in_middle_name = false,
                     b"last-name" => in_last_name = false,
+                    b"nickname" => in_nickname = false,
+                    b"p" | b"v" | b"subtitle" | b"text-author" if in_annotation => {
+                        if !annotation_line.trim().is_empty() {
+                            annotation.push(annotation_line.trim().to_string());
+                        };
+                        annotation_line.clear();
+                    },
                     b"annotation" => {
                         in_annotation = false;
                         
+                        if !annotation_line.trim().is_empty() {
+                            annotation.push(annotation_line.trim().to_string());
+                        };
+                        annotation_line.clear();
+                        
                         if !annotation.is_empty() {
                             meta.annotation = Some(annotation.clone());
                             annotation.clear();
@@ -137,25 +187,31 @@ pub fn metadata_reader<R>(
                 }
             }
             
-            Ok(Event::Text(e)) => {
-                let text = e
-                    .decode()?
-                    .into_owned();
+            Ok(ref event @ (Event::Text(_) | Event::GeneralRef(_))) => {
+                let text = match event {
+                    Event::Text(e) => e.decode()?.into_owned(),
+                    Event::GeneralRef(e) => resolve_ref(e).unwrap_or_default(),
+                    _ => String::new()
+                };
                 
                 if in_title {
-                    meta.title = text;
+                    meta.title.push_str(&text);
                 } else if in_first_name {
-                    first_name = text;
+                    first_name.push_str(&text);
                 } else if in_middle_name {
-                    middle_name = text;
+                    middle_name.push_str(&text);
                 } else if in_last_name {
-                    last_name = text;
+                    last_name.push_str(&text);
+                } else if in_nickname {
+                    nickname.push_str(&text);
+                } else if in_genre {
+                    genre.push_str(&text);
+                } else if in_id {
+                    id.push_str(&text);
                 } else if in_annotation {
-                    if !text.trim().is_empty() {
-                        annotation.push(text.trim().to_string());
-                    }
+                    annotation_line.push_str(&text);
                 } else if in_lang {
-                    meta.language = text;
+                    meta.language.push_str(&text);
                 }
             }
             
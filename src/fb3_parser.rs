@@ -0,0 +1,232 @@
+pub mod description_reader;
+pub mod body_reader;
+
+
+use std::path::Path;
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::collections::HashMap;
+
+use base64::{Engine as _, engine::general_purpose};
+use quick_xml::events::Event;
+use quick_xml::reader::Reader;
+use zip::ZipArchive;
+
+use crate::fb2_parser::{BookData, Image, get_attr, sanitize_id};
+use crate::fb2_parser::content_reader::content_reader;
+use crate::epub_parser::read_entry;
+use crate::epub_parser::opf_reader::resolve_path;
+use crate::fb3_parser::description_reader::description_reader;
+use crate::fb3_parser::body_reader::body_reader;
+
+
+struct Relationship {
+    id: String,
+    rel_type: String,
+    target: String      // полный путь внутри архива
+}
+
+
+/// Путь к .rels для части пакета: fb3/body.xml -> fb3/_rels/body.xml.rels
+fn rels_path(part: &str) -> String {
+    match part.rfind('/') {
+        Some(i) => format!("{}/_rels/{}.rels", &part[..i], &part[i + 1..]),
+        None => format!("_rels/{part}.rels")
+    }
+}
+
+fn rels_reader(xml: &str, part: &str) -> Vec<Relationship> {
+    let mut reader = Reader::from_str(xml);
+    let mut relationships: Vec<Relationship> = Vec::new();
+
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(ref e)) | Ok(Event::Empty(ref e))
+                if e.local_name().as_ref() == b"Relationship" => {
+                let target = get_attr(e, "Target", reader.decoder());
+                if target.is_empty() || get_attr(e, "TargetMode", reader.decoder()) == "External" {continue}
+
+                relationships.push(Relationship {
+                    id: get_attr(e, "Id", reader.decoder()),
+                    rel_type: get_attr(e, "Type", reader.decoder()),
+                    // абсолютные пути считаются от корня пакета
+                    target: if target.starts_with('/') {resolve_path("", &target)}
+                        else {resolve_path(part, &target)}
+                })
+            },
+            Ok(Event::Eof) | Err(_) => break,
+            _ => {}
+        }
+    };
+
+    relationships
+}
+
+fn read_rels<R: Read + Seek>(archive: &mut ZipArchive<R>, part: &str) -> Vec<Relationship> {
+    match read_entry(archive, &rels_path(part)) {
+        Ok(bytes) => rels_reader(&String::from_utf8_lossy(&bytes), part),
+        Err(_) => Vec::new()
+    }
+}
+
+fn read_image<R: Read + Seek>(archive: &mut ZipArchive<R>, id: &str, path: &str) -> Option<Image> {
+    let bytes = read_entry(archive, path).ok()?;
+    let content_type = match path.rsplit('.').next().map(|e| e.to_lowercase()).as_deref() {
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("svg") => "image/svg+xml",
+        _ => "image/jpeg"
+    };
+
+    Some(Image {
+        id: sanitize_id(id),
+        content_type: content_type.to_string(),
+        binary: general_purpose::STANDARD.encode(bytes)
+    })
+}
+
+
+/// Только описание из description.xml, текст и картинки не читаются
+pub fn get_meta_from_reader<R: Read + Seek>(reader: R) -> Result<BookData, Box<dyn std::error::Error>> {
+    let mut archive = ZipArchive::new(reader)?;
+    let description_path = read_rels(&mut archive, "").iter()
+        .find(|r| r.rel_type.ends_with("/relationships/Book"))
+        .map(|r| r.target.clone())
+        .unwrap_or_else(|| String::from("fb3/description.xml"));
+    let description = String::from_utf8_lossy(&read_entry(&mut archive, &description_path)?).to_string();
+
+    Ok(BookData {
+        meta: description_reader(description.trim_start_matches('\u{feff}'))?,
+        content: Vec::new(),
+        images: HashMap::new(),
+        link_map: HashMap::new(),
+        encoding: None,
+        warnings: Vec::new(),
+        description: None
+    })
+}
+
+/// Читает FB3: описание из description.xml, текст из body.xml, картинки по связям
+pub fn get_data(book: &Path) -> Result<BookData, Box<dyn std::error::Error>> {
+    get_data_from_reader(File::open(book)?)
+}
+
+/// То же из любого потока с произвольным доступом, например, книги в памяти
+pub fn get_data_from_reader<R: Read + Seek>(reader: R) -> Result<BookData, Box<dyn std::error::Error>> {
+    let mut archive = ZipArchive::new(reader)?;
+
+    let root_rels = read_rels(&mut archive, "");
+    let description_path = root_rels.iter()
+        .find(|r| r.rel_type.ends_with("/relationships/Book"))
+        .map(|r| r.target.clone())
+        .unwrap_or_else(|| String::from("fb3/description.xml"));
+
+    let description = String::from_utf8_lossy(&read_entry(&mut archive, &description_path)?).to_string();
+    let description_rels = read_rels(&mut archive, &description_path);
+    let body_path = description_rels.iter()
+        .find(|r| r.rel_type.ends_with("/relationships/body"))
+        .map(|r| r.target.clone())
+        .unwrap_or_else(|| resolve_path(&description_path, "body.xml"));
+
+    let mut data = BookData {
+        meta: description_reader(description.trim_start_matches('\u{feff}'))?,
+        content: Vec::new(),
+        images: HashMap::new(),
+        link_map: HashMap::new(),
+        encoding: None,
+        warnings: Vec::new(),
+        description: None
+    };
+
+
+    // Текст переписывается в fb2 и читается как обычная книга
+    let body = String::from_utf8_lossy(&read_entry(&mut archive, &body_path)?).to_string();
+    let fb2_body = body_reader(body.trim_start_matches('\u{feff}'))?;
+    let mut xml_reader = Reader::from_reader(fb2_body.as_bytes());
+    xml_reader.config_mut().check_end_names = false;
+    let mut buf = Vec::new();
+    content_reader(&mut data, &mut xml_reader, &mut buf, None, 0)?;
+
+
+    // Картинки: id связи из body.xml.rels становится id картинки,
+    // исправленный так же, как ссылка на неё в тексте
+    let mut keys: HashMap<String, String> = HashMap::new();
+    for rel in read_rels(&mut archive, &body_path) {
+        if !rel.rel_type.ends_with("/image") {continue}
+        if let Some(image) = read_image(&mut archive, &rel.id, &rel.target) {
+            let key = format!("#{}", image.id);
+            keys.insert(rel.target, key.clone());
+            data.images.insert(key, image);
+        }
+    };
+
+    // Обложка лежит в связях пакета как thumbnail
+    let cover = root_rels.iter()
+        .chain(description_rels.iter())
+        .find(|r| r.rel_type.ends_with("/thumbnail") || r.rel_type.ends_with("/cover"));
+    if let Some(rel) = cover {
+        let key = match keys.get(&rel.target) {
+            Some(k) => Some(k.clone()),
+            None => read_image(&mut archive, "cover", &rel.target).map(|image| {
+                let key = String::from("#cover");
+                data.images.insert(key.clone(), image);
+                key
+            })
+        };
+        data.meta.cover = key;
+    };
+
+    Ok(data)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use zip::ZipWriter;
+    use zip::write::SimpleFileOptions;
+
+    use super::get_data_from_reader;
+    use crate::fb2_parser::content_reader::Paragraph;
+
+    const PNG: &[u8] = &[0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+
+    fn fb3(image_id: &str) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default();
+        let files = [
+            ("_rels/.rels", String::from(r#"<Relationships><Relationship Id="rId0" Type="http://www.fictionbook.org/FictionBook3/relationships/Book" Target="fb3/description.xml"/></Relationships>"#)),
+            ("fb3/description.xml", String::from(r#"<fb3-description><title><main>Book</main></title><lang>en</lang></fb3-description>"#)),
+            ("fb3/_rels/description.xml.rels", String::from(r#"<Relationships><Relationship Id="rId1" Type="http://www.fictionbook.org/FictionBook3/relationships/body" Target="body.xml"/></Relationships>"#)),
+            ("fb3/body.xml", format!(r#"<fb3-body><section><p>Text</p><p><img src="{image_id}"/></p></section></fb3-body>"#)),
+            ("fb3/_rels/body.xml.rels", format!(r#"<Relationships><Relationship Id="{image_id}" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/image" Target="img/pic.png"/></Relationships>"#))
+        ];
+        for (name, text) in files {
+            zip.start_file(name, options).unwrap();
+            zip.write_all(text.as_bytes()).unwrap();
+        };
+        zip.start_file("fb3/img/pic.png", options).unwrap();
+        zip.write_all(PNG).unwrap();
+
+        zip.finish().unwrap().into_inner()
+    }
+
+    /// id связи, который не годится для XML, исправляется одинаково у картинки и ссылки на неё
+    #[test]
+    fn image_key_matches_reference() {
+        for image_id in ["img1", "1 image:png"] {
+            let data = get_data_from_reader(Cursor::new(fb3(image_id))).unwrap();
+            let image = data.images.values().next().unwrap();
+            assert_eq!(data.images.keys().next(), Some(&format!("#{}", image.id)));
+
+            let href = data.content.iter()
+                .flat_map(|s| s.paragraphs.iter())
+                .find_map(|p| match p {
+                    Paragraph::Image(href) => href.clone(),
+                    _ => None
+                });
+            assert_eq!(href, Some(format!("#{}", image.id)));
+        }
+    }
+}
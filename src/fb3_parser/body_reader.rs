@@ -0,0 +1,149 @@
+use quick_xml::escape::{escape, partial_escape};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::reader::Reader;
+
+use crate::fb2_parser::{get_attr, resolve_ref, sanitize_id};
+
+
+// Атрибут xlink:href или просто href, префикс у FB3 бывает любой
+fn get_link(e: &BytesStart, reader: &Reader<&[u8]>) -> String {
+    for attr in e.attributes().flatten() {
+        if attr.key.local_name().as_ref() == b"href" {
+            return attr.decode_and_unescape_value(reader.decoder())
+                .map(|s| s.to_string())
+                .unwrap_or_default()
+        }
+    };
+
+    String::new()
+}
+
+fn id_attr(e: &BytesStart, reader: &Reader<&[u8]>) -> String {
+    match get_attr(e, "id", reader.decoder()) {
+        id if id.is_empty() => String::new(),
+        id => format!(" id=\"{}\"", escape(id.as_str()))
+    }
+}
+
+/// Переводит тег FB3 в тег FB2, None - тег пропускается, текст остаётся
+fn fb2_tag(name: &[u8]) -> Option<&'static str> {
+    Some(match name {
+        b"section" | b"notebody" => "section",
+        b"title" => "title",
+        b"epigraph" => "epigraph",
+        b"annotation" => "annotation",
+        b"blockquote" => "cite",
+        b"poem" => "poem",
+        b"stanza" => "stanza",
+        b"subtitle" => "subtitle",
+        b"text-author" => "text-author",
+        b"date" => "date",
+        b"p" | b"li" | b"tr" => "p",
+        b"strong" => "strong",
+        b"em" | b"underline" => "emphasis",
+        b"strikethrough" => "strikethrough",
+        b"code" => "code",
+        b"sup" => "sup",
+        b"sub" => "sub",
+        _ => return None
+    })
+}
+
+
+/// Переписывает body.xml из FB3 в тела FB2: основное и с примечаниями,
+/// дальше их читает обычный content_reader
+pub fn body_reader(xml: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut reader = Reader::from_str(xml);
+    reader.config_mut().check_end_names = false;
+
+    let mut body = String::from("<body>");
+    let mut notes = String::new();
+    let mut in_notes = false;
+    // закрывающие теги для открытых элементов FB3
+    let mut stack: Vec<String> = Vec::new();
+
+    loop {
+        let out = if in_notes {&mut notes} else {&mut body};
+        match reader.read_event() {
+            Ok(Event::Start(ref e)) => {
+                let name = e.local_name();
+                let close = match name.as_ref() {
+                    b"notes" => {
+                        in_notes = true;
+                        notes.push_str("<body name=\"notes\">");
+                        let title = get_attr(e, "title", reader.decoder());
+                        if !title.is_empty() {
+                            notes.push_str(&format!("<title><p>{}</p></title>", partial_escape(title.as_str())));
+                        };
+                        String::from("</body>")
+                    },
+                    b"pre" => {
+                        out.push_str("<p><code>");
+                        String::from("</code></p>")
+                    },
+                    b"a" => {
+                        out.push_str(&format!("<a l:href=\"{}\">", escape(get_link(e, &reader).as_str())));
+                        String::from("</a>")
+                    },
+                    b"note" => {
+                        out.push_str(&format!("<a l:href=\"{}\" type=\"note\">", escape(get_link(e, &reader).as_str())));
+                        String::from("</a>")
+                    },
+                    name => match fb2_tag(name) {
+                        Some(tag) => {
+                            let id = if matches!(tag, "section" | "epigraph" | "cite" | "poem" | "stanza") {
+                                id_attr(e, &reader)
+                            } else {String::new()};
+                            out.push_str(&format!("<{tag}{id}>"));
+                            format!("</{tag}>")
+                        },
+                        None => String::new()
+                    }
+                };
+                stack.push(close);
+            },
+
+            Ok(Event::Empty(ref e)) => {
+                match e.local_name().as_ref() {
+                    b"img" => {
+                        let src = get_attr(e, "src", reader.decoder());
+                        if !src.is_empty() {
+                            out.push_str(&format!("<image l:href=\"#{}\"/>", escape(sanitize_id(&src).as_str())))
+                        }
+                    },
+                    b"br" | b"td" | b"th" => out.push(' '),
+                    b"p" | b"empty-line" => out.push_str("<empty-line/>"),
+                    _ => {}
+                }
+            },
+
+            Ok(Event::End(ref e)) => {
+                if e.local_name().as_ref() == b"notes" {
+                    in_notes = false;
+                    notes.push_str(&stack.pop().unwrap_or_default());
+                    continue
+                };
+                if matches!(e.local_name().as_ref(), b"td" | b"th") {
+                    out.push(' ')
+                };
+                out.push_str(&stack.pop().unwrap_or_default());
+            },
+
+            Ok(Event::Text(e)) => out.push_str(&partial_escape(e.decode()?.as_ref())),
+            Ok(Event::GeneralRef(e)) => {
+                if let Some(s) = resolve_ref(&e) {
+                    out.push_str(&partial_escape(s.as_str()))
+                }
+            },
+
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(Box::new(e)),
+            _ => {}
+        }
+    };
+
+    body.push_str("</body>");
+    body.push_str(&notes);
+
+    Ok(body)
+}
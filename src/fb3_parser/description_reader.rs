@@ -0,0 +1,145 @@
+use quick_xml::events::Event;
+use quick_xml::reader::Reader;
+
+use crate::fb2_parser::{get_attr, resolve_ref};
+use crate::fb2_parser::metadata_reader::{Metadata, Sequence};
+
+
+// теги внутри абзаца, их текст остаётся частью абзаца
+const INLINE: [&str; 10] = ["strong", "em", "underline", "strikethrough", "sub", "sup", "code", "spacing", "a", "note"];
+
+
+#[derive(Default)]
+struct Author {
+    title: String,
+    first_name: String,
+    middle_name: String,
+    last_name: String
+}
+
+impl Author {
+    fn name(&self) -> String {
+        let parts: Vec<&str> = [&self.first_name, &self.middle_name, &self.last_name]
+            .into_iter()
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .collect();
+
+        if parts.is_empty() {self.title.trim().to_string()}
+        else {parts.join(" ")}
+    }
+}
+
+
+/// Читает description.xml из FB3
+pub fn description_reader(xml: &str) -> Result<Metadata, Box<dyn std::error::Error>> {
+    let mut reader = Reader::from_str(xml);
+    let mut meta = Metadata {
+        title: String::new(),
+        authors: Vec::new(),
+        genres: Vec::new(),
+        language: String::new(),
+        sequence: None,
+        annotation: None,
+        cover: None,
+        id: None
+    };
+
+    // путь из имён открытых тегов, текст собирается для последнего
+    let mut path: Vec<String> = Vec::new();
+    let mut text = String::new();
+    let mut author: Option<Author> = None;
+    let mut sequence: Option<Sequence> = None;
+    let mut annotation: Vec<String> = Vec::new();
+
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(ref e)) => {
+                let name = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
+                match &name[..] {
+                    "fb3-description" => {
+                        let id = get_attr(e, "id", reader.decoder());
+                        if !id.is_empty() {meta.id = Some(id)}
+                    },
+                    "subject" if path.last().map(|s| s.as_str()) == Some("fb3-relations")
+                        && get_attr(e, "link", reader.decoder()) == "author" => {
+                        author = Some(Author::default())
+                    },
+                    "sequence" if sequence.is_none() && meta.sequence.is_none() => {
+                        sequence = Some(Sequence {
+                            name: String::new(),
+                            number: get_attr(e, "number", reader.decoder())
+                        })
+                    },
+                    _ => {}
+                };
+                if !INLINE.contains(&name.as_str()) {
+                    text.clear()
+                };
+                path.push(name);
+            },
+
+            Ok(Event::Text(e)) => text.push_str(&e.decode()?),
+            Ok(Event::GeneralRef(e)) => {
+                if let Some(s) = resolve_ref(&e) {
+                    text.push_str(&s)
+                }
+            },
+
+            Ok(Event::End(_)) => {
+                let name = path.pop().unwrap_or_default();
+                if INLINE.contains(&name.as_str()) {continue}
+
+                let parent = path.last().map(|s| s.as_str()).unwrap_or("");
+                let grandparent = if path.len() > 1 {path[path.len() - 2].as_str()} else {""};
+                let value = text.trim().to_string();
+
+                match (&name[..], parent, grandparent) {
+                    ("main", "title", "fb3-description") => meta.title = value,
+                    ("main", "title", "subject") => {
+                        if let Some(a) = &mut author {a.title = value}
+                    },
+                    ("main", "title", "sequence") => {
+                        if let Some(s) = &mut sequence {s.name = value}
+                    },
+                    ("first-name", "subject", _) => {
+                        if let Some(a) = &mut author {a.first_name = value}
+                    },
+                    ("middle-name", "subject", _) => {
+                        if let Some(a) = &mut author {a.middle_name = value}
+                    },
+                    ("last-name", "subject", _) => {
+                        if let Some(a) = &mut author {a.last_name = value}
+                    },
+                    ("subject", "fb3-relations", _) => {
+                        if let Some(a) = author.take() {
+                            let name = a.name();
+                            if !name.is_empty() {meta.authors.push(name)}
+                        }
+                    },
+                    ("subject", "fb3-classification", _) if !value.is_empty() => meta.genres.push(value),
+                    ("sequence", _, _) => {
+                        if let Some(s) = sequence.take()
+                            && !s.name.is_empty() {
+                            meta.sequence = Some(s)
+                        }
+                    },
+                    ("lang", "fb3-description", _) => meta.language = value,
+                    (_, "annotation", _) if !value.is_empty() => annotation.push(value),
+                    _ => {}
+                };
+                text.clear();
+            },
+
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(Box::new(e)),
+            _ => {}
+        }
+    };
+
+    if !annotation.is_empty() {
+        meta.annotation = Some(annotation)
+    };
+
+    Ok(meta)
+}
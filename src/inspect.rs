@@ -0,0 +1,199 @@
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+
+/// Формат списка книг для ls
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum ListFormat {
+    Table,
+    Csv,
+    Json
+}
+
+/// Строка списка для ls
+#[derive(Serialize)]
+struct Entry {
+    path: PathBuf,
+    source: Option<PathBuf>,
+    title: String,
+    authors: Vec<String>,
+    series: String,
+    series_index: String,
+    genres: Vec<String>,
+    language: String,
+    id: Option<String>
+}
+
+
+/// Имя книги для сообщений: файл, для архивов - с путём внутри
+fn book_name(path: &Path, source: Option<&Path>) -> String {
+    match source {
+        Some(source) => format!("{} ({})", path.display(), source.display()),
+        None => path.display().to_string()
+    }
+}
+
+fn print_info(path: &Path, info: &fb2epub::BookInfo) {
+    println!("File: {}", book_name(path, info.source.as_deref()));
+    println!("Title: {}", info.title);
+    println!("Authors: {}", info.authors.join(", "));
+    if let Some((name, index)) = &info.series {
+        if index.is_empty() {println!("Series: {name}")}
+        else {println!("Series: {name} #{index}")}
+    };
+    println!("Genres: {}", info.genres.join(", "));
+    println!("Language: {}", info.language);
+    if let Some(id) = &info.id {
+        println!("Id: {id}")
+    };
+    if let Some(encoding) = &info.encoding {
+        println!("Encoding: {encoding}")
+    };
+    println!("Cover: {}", if info.cover {"yes"} else {"no"});
+    println!("Images: {}", info.images);
+    println!("Words: {}", info.words);
+
+    if !info.annotation.is_empty() {
+        println!("Annotation:");
+        for p in &info.annotation {
+            println!("    {p}")
+        }
+    };
+
+    if !info.sections.is_empty() {
+        println!("Sections:");
+        for section in &info.sections {
+            let indent = "    ".repeat(section.level.max(1) as usize);
+            let title = if section.title.is_empty() {"(no title)"} else {&section.title};
+            println!("{indent}{title} ({} words)", section.words)
+        }
+    }
+}
+
+/// Подробности о каждой книге, архивы - по книгам
+pub fn info(books: &[PathBuf]) -> bool {
+    let mut ok = true;
+    let mut is_first = true;
+    for path in books {
+        let infos = match fb2epub::info(path, true) {
+            Ok(i) => i,
+            Err(err) => {
+                eprintln!("{:#?}: {err}", path);
+                ok = false;
+                continue
+            }
+        };
+
+        for info in infos {
+            match info {
+                Ok(info) => {
+                    if !is_first {println!()}
+                    is_first = false;
+                    print_info(path, &info)
+                },
+                Err(err) => {
+                    eprintln!("{:#?}: {err}", path);
+                    ok = false
+                }
+            }
+        }
+    };
+
+    ok
+}
+
+
+/// Поле csv в кавычках, если в нём есть разделитель, кавычки или перенос строки
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn print_entries(entries: &[Entry], format: ListFormat) -> Result<(), Box<dyn std::error::Error>> {
+    match format {
+        ListFormat::Json => println!("{}", serde_json::to_string_pretty(entries)?),
+        ListFormat::Csv => {
+            println!("path,source,title,authors,series,series_index,genres,language,id");
+            for e in entries {
+                let source = e.source.as_ref().map(|s| s.display().to_string()).unwrap_or_default();
+                let fields = [
+                    e.path.display().to_string(),
+                    source,
+                    e.title.clone(),
+                    e.authors.join("; "),
+                    e.series.clone(),
+                    e.series_index.clone(),
+                    e.genres.join("; "),
+                    e.language.clone(),
+                    e.id.clone().unwrap_or_default()
+                ];
+                let fields: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+                println!("{}", fields.join(","))
+            }
+        },
+        ListFormat::Table => {
+            for e in entries {
+                let series = match (&e.series[..], &e.series_index[..]) {
+                    ("", _) => String::new(),
+                    (name, "") => format!(" [{name}]"),
+                    (name, index) => format!(" [{name} #{index}]")
+                };
+                println!("{} - {}{series}\t{}",
+                    e.authors.join(", "), e.title, book_name(&e.path, e.source.as_deref()))
+            }
+        }
+    };
+
+    Ok(())
+}
+
+/// Список книг с метаданными, текст книг не читается
+pub fn ls(inputs: &Vec<String>, recursive: bool, format: ListFormat) -> bool {
+    let mut ok = true;
+    let mut entries: Vec<Entry> = Vec::new();
+    for path in crate::get_files(inputs, recursive, false) {
+        let infos = match fb2epub::info(&path, false) {
+            Ok(i) => i,
+            Err(err) => {
+                eprintln!("{:#?}: {err}", path);
+                ok = false;
+                continue
+            }
+        };
+
+        for info in infos {
+            let info = match info {
+                Ok(i) => i,
+                Err(err) => {
+                    eprintln!("{:#?}: {err}", path);
+                    ok = false;
+                    continue
+                }
+            };
+            let (series, series_index) = info.series.unwrap_or_default();
+            entries.push(Entry {
+                path: path.clone(),
+                source: info.source,
+                title: info.title,
+                authors: info.authors,
+                series,
+                series_index,
+                genres: info.genres,
+                language: info.language,
+                id: info.id
+            })
+        }
+    };
+
+    if let Err(err) = print_entries(&entries, format) {
+        eprintln!("Cannot print list: {err}");
+        ok = false
+    };
+
+    ok
+}
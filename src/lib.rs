@@ -1,22 +1,302 @@
 mod fb2_parser;
 mod epub_creator;
+mod epub_parser;
+mod fb3_parser;
+mod fb2_creator;
+mod docx_creator;
 mod zip_reader;
+mod tar_reader;
+mod compressed_reader;
+mod name_template;
+mod manifest;
+mod verifier;
+mod book_info;
+mod fb2_editor;
+mod overrides;
+mod omnibus;
+mod anthology;
 
 use std::path::{PathBuf, Path};
+use std::str::FromStr;
+use std::io::{Cursor, Read, Seek, Write};
 use std::fs;
 
 use crate::fb2_parser::metadata_reader::Sequence;
 
+pub use crate::book_info::{BookInfo, SectionInfo};
+pub use crate::overrides::Overrides;
 
-/// Struct for replacing metadata from a book with yours
-#[derive(Clone)]
+
+/// Struct for replacing metadata from a book with yours.
+/// Start with Metadata::default() and set the fields you need, new fields may be added
+#[derive(Clone, Default, Debug)]
+#[non_exhaustive]
 pub struct Metadata {
     pub title: Option<String>,
     pub authors: Option<Vec<String>>,
     pub language: Option<String>,
     pub series: Option<String>,
     pub series_index: Option<String>,
-    pub description: Option<Vec<String>>
+    pub description: Option<Vec<String>>,
+    pub genres: Option<Vec<String>>
+}
+
+/// Output format
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Epub,
+    Fb2,
+    Docx
+}
+
+impl Format {
+    /// Default output format for given input: epub goes to fb2, everything else to epub
+    pub fn for_input(book: &Path) -> Format {
+        match book.extension().and_then(|s| Some(s.to_str()?.to_lowercase())).as_deref() {
+            Some("epub") => Format::Fb2,
+            _ => Format::Epub
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Epub => "epub",
+            Format::Fb2 => "fb2",
+            Format::Docx => "docx"
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &s.to_lowercase()[..] {
+            "epub" => Ok(Format::Epub),
+            "fb2" => Ok(Format::Fb2),
+            "docx" => Ok(Format::Docx),
+            _ => Err(format!("Unknown format: {s}, expected epub, fb2 or docx"))
+        }
+    }
+}
+
+/// What to do if output book already exists
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum OnExists {
+    /// Replace the old book
+    Overwrite,
+    /// Keep the old book, don't convert
+    Skip,
+    /// Save new book as name-1, name-2...
+    #[default]
+    Rename,
+    /// Convert only if the input is newer than the old book and its content has changed
+    Update
+}
+
+impl FromStr for OnExists {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &s.to_lowercase()[..] {
+            "overwrite" => Ok(OnExists::Overwrite),
+            "skip" => Ok(OnExists::Skip),
+            "rename" => Ok(OnExists::Rename),
+            "update" => Ok(OnExists::Update),
+            _ => Err(format!("Unknown policy: {s}, expected overwrite, skip, rename or update"))
+        }
+    }
+}
+
+/// What happened to an output book
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// New file
+    Create,
+    /// Old book was replaced
+    Overwrite,
+    /// Name was taken, the book got a free one: name-1, name-2...
+    Rename,
+    /// Old book was kept, see OnExists
+    Skip
+}
+
+/// Order of books in an omnibus
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum OmnibusOrder {
+    /// By series index, books without it go last in the given order
+    #[default]
+    Series,
+    /// As the books were given
+    Input
+}
+
+impl FromStr for OmnibusOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &s.to_lowercase()[..] {
+            "series" => Ok(OmnibusOrder::Series),
+            "input" => Ok(OmnibusOrder::Input),
+            _ => Err(format!("Unknown order: {s}, expected series or input"))
+        }
+    }
+}
+
+/// How to split a book into several books, see Options.split
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Split {
+    /// Every top-level section becomes a book
+    Sections,
+    /// Sections with these ids start new books, a book ends where its section ends.
+    /// A book inside another one is cut out of it, the outer book goes on after it
+    Ids(Vec<String>)
+}
+
+/// How the text of an epub is divided into files, Layout::default() - a file for every section
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct Layout {
+    /// Sections deeper than this level go to the file of their parent section,
+    /// 1 - a file for every top-level section
+    pub split_level: Option<u8>,
+    /// Files bigger than this (KB of XHTML) are split at paragraphs
+    pub max_size: Option<usize>,
+    /// Consecutive files smaller than this (KB of XHTML) are merged while they stay smaller.
+    /// Notes always have their own file
+    pub min_size: Option<usize>
+}
+
+/// How notes are shown in epub
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub enum Footnotes {
+    /// Note references and notes are marked for EPUB 3 readers, which show notes in popups
+    #[default]
+    Popup,
+    /// Plain links to the notes file, for readers that hide marked notes or show popups badly
+    Links
+}
+
+impl FromStr for Footnotes {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &s.to_lowercase()[..] {
+            "popup" => Ok(Footnotes::Popup),
+            "links" => Ok(Footnotes::Links),
+            _ => Err(format!("Unknown footnotes mode: {s}, expected popup or links"))
+        }
+    }
+}
+
+/// Version of written epub
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub enum EpubVersion {
+    /// EPUB 2 for old readers: series is written as calibre does, notes are plain links
+    V2,
+    #[default]
+    V3
+}
+
+impl FromStr for EpubVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "2" | "2.0" => Ok(EpubVersion::V2),
+            "3" | "3.0" | "3.3" => Ok(EpubVersion::V3),
+            _ => Err(format!("Unknown EPUB version: {s}, expected 2 or 3"))
+        }
+    }
+}
+
+/// Additional options of conversion, Options::default() keeps the usual behaviour
+#[derive(Clone, Default, Debug)]
+pub struct Options {
+    /// Recreate folders of an archive in the output folder instead of putting all books together
+    pub keep_structure: bool,
+    /// Put books from zip and tar archives into one zip with the same paths instead of a folder
+    pub zip_output: bool,
+    /// Name output books by metadata, e.g. "{author_last}/{series}/{series_index:02} - {title}",
+    /// relative to the output folder. Fields: title, author, authors, author_first, author_last,
+    /// series, series_index, language, genre
+    pub name_template: Option<String>,
+    /// Write Cyrillic in names made by name_template with Latin letters
+    pub transliterate: bool,
+    /// What to do if output book already exists
+    pub on_exists: OnExists,
+    /// Re-open every new book and check its structure, always on with replace
+    pub verify: bool,
+    /// With replace move input books to this folder instead of deleting them
+    pub trash: Option<PathBuf>,
+    /// Number of threads converting books at the same time, None - number of CPUs.
+    /// Calls of convert from other threads count too, so books of archives converted
+    /// in a pool of jobs threads take only its free threads
+    pub jobs: Option<usize>,
+    /// Write and remove nothing: read only metadata of books and return planned
+    /// outputs with their actions in Converted.outputs, sizes are 0
+    pub dry_run: bool,
+    /// Metadata for particular books by path or document id, its fields
+    /// replace fields of metadata given to run
+    pub overrides: std::sync::Arc<Overrides>,
+    /// Split every book into several (e.g. an anthology into its works), they go to a folder
+    /// named as the output book: "book/01 - Title.epub". Each part gets its own notes, images
+    /// and metadata: title and authors from its section, the rest from the book, which becomes
+    /// the series. Not used with zip_output
+    pub split: Option<Split>,
+    /// Files of epub text, not used for other formats
+    pub layout: Layout,
+    /// How notes are shown in epub
+    pub footnotes: Footnotes,
+    /// Version of epub
+    pub epub_version: EpubVersion,
+    /// Images bigger than this (KB) are left out of epub with a warning, the cover is kept
+    pub max_image_size: Option<usize>
+}
+
+impl Options {
+    /// Для плана читаются только метаданные, но части книги без текста не найти
+    fn meta_only(&self) -> bool {
+        self.dry_run && self.split.is_none()
+    }
+}
+
+/// Result of conversion
+#[derive(Debug)]
+pub struct Converted {
+    /// Path to new book, for archives with several books - to output folder
+    pub output: PathBuf,
+    /// Encoding the input book was read in, None for epub, fb3 and archives
+    pub encoding: Option<String>,
+    /// Problems that didn't stop conversion, e.g. wrong encoding declaration
+    pub warnings: Vec<String>,
+    /// Number of written books: 1 for a book, for archives - number of their converted books
+    pub books: usize,
+    /// Number of books left as is because output already exists, see OnExists
+    pub skipped: usize,
+    /// Every written or skipped book
+    pub outputs: Vec<OutputBook>,
+    /// Books of an archive that cannot be converted, with their errors.
+    /// If no book of the archive is converted, the whole conversion is an error
+    pub failed: Vec<String>,
+    /// Files of an archive that are not books or cannot be read, e.g. broken nested zips.
+    /// With replace an archive with them is kept
+    pub kept: Vec<PathBuf>
+}
+
+/// Written or skipped book and its metadata
+#[derive(Debug, Clone)]
+pub struct OutputBook {
+    /// Path to the book, for zip output - path to the archive joined with path inside it
+    pub path: PathBuf,
+    /// Path of the input book inside an archive, None for a single book
+    pub source: Option<PathBuf>,
+    pub action: Action,
+    /// Size in bytes
+    pub size: u64,
+    pub title: String,
+    pub authors: Vec<String>,
+    /// Series name and index
+    pub series: Option<(String, String)>
 }
 
 /*
@@ -48,21 +328,26 @@ fn print_sections(sections: &Vec<crate::fb2_parser::Section>, without_p: bool) {
 }
 */
 
-fn get_free_output(output: &Path) -> Option<PathBuf> {
+/// Имя книги с нужным расширением: book.fb2.epub -> book.epub
+fn get_output_name(output: &Path, extension: &str) -> Option<PathBuf> {
     let mut file_name = output.file_stem()?.to_str()?;
     
-    if file_name.ends_with(".fb2") {
-        if let Some(r_index) = file_name.rfind(".") {
-            file_name = &file_name[..r_index]
-        }
+    if (file_name.ends_with(".fb2") || file_name.ends_with(".epub") || file_name.ends_with(".docx"))
+        && let Some(r_index) = file_name.rfind(".") {
+        file_name = &file_name[..r_index]
     };
     
-    let parent = output.parent()?;
-    let mut free_output = parent.join(format!("{file_name}.epub"));
+    Some(output.parent()?.join(format!("{file_name}.{extension}")))
+}
+
+fn get_free_output(output: &Path, extension: &str) -> Option<PathBuf> {
+    let mut free_output = get_output_name(output, extension)?;
+    let parent = free_output.parent()?.to_path_buf();
+    let file_name = free_output.file_stem()?.to_str()?.to_string();
     
     let mut counter = 1;
     while free_output.exists() {
-        free_output = parent.join(format!("{file_name}-{counter}.epub"));
+        free_output = parent.join(format!("{file_name}-{counter}.{extension}"));
         counter += 1;
     };
     
@@ -70,11 +355,386 @@ fn get_free_output(output: &Path) -> Option<PathBuf> {
 }
 
 
-/// Main function, takes path to fb2 book (or zip archive), returns path to new epub book.
+/// Создаёт файл под свободным именем. Файл создаётся только если его ещё нет,
+/// так что параллельные книги с одинаковыми именами не перезапишут друг друга
+fn create_free_output(output: &Path, extension: &str) -> std::io::Result<(PathBuf, fs::File)> {
+    loop {
+        let free_output = get_free_output(output, extension).unwrap_or(output.to_owned());
+        match fs::File::create_new(&free_output) {
+            Ok(file) => return Ok((free_output, file)),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err)
+        }
+    }
+}
+
+/// Книгу не нужно делать заново: она уже есть, а политика skip или update
+fn should_skip(book: &Path, output: &Path, on_exists: OnExists) -> bool {
+    match on_exists {
+        OnExists::Skip => output.exists(),
+        OnExists::Update => manifest::is_up_to_date(book, output),
+        OnExists::Overwrite | OnExists::Rename => false
+    }
+}
+
+/// Куда и как пойдёт книга по политике on_exists, файл не создаётся
+fn plan_output(book: &Path, output: &Path, extension: &str, on_exists: OnExists) -> (PathBuf, Action) {
+    let output = get_output_name(output, extension).unwrap_or(output.to_owned());
+    if should_skip(book, &output, on_exists) {
+        return (output, Action::Skip)
+    };
+    if !output.exists() {
+        return (output, Action::Create)
+    };
+
+    match on_exists {
+        OnExists::Rename => (get_free_output(&output, extension).unwrap_or(output), Action::Rename),
+        _ => (output, Action::Overwrite)
+    }
+}
+
+/// Открывает файл для книги по политике on_exists, без файла - книгу пропустить
+fn create_output(
+    book: &Path,
+    output: &Path,
+    extension: &str,
+    on_exists: OnExists
+) -> std::io::Result<(PathBuf, Action, Option<fs::File>)> {
+    let output = get_output_name(output, extension).unwrap_or(output.to_owned());
+    if should_skip(book, &output, on_exists) {
+        return Ok((output, Action::Skip, None))
+    };
+
+    match on_exists {
+        OnExists::Rename => {
+            let (free_output, file) = create_free_output(&output, extension)?;
+            let action = if free_output == output {Action::Create} else {Action::Rename};
+            Ok((free_output, action, Some(file)))
+        },
+        _ => {
+            let action = if output.exists() {Action::Overwrite} else {Action::Create};
+            Ok((output.clone(), action, Some(fs::File::create(&output)?)))
+        }
+    }
+}
+
+/// Default number of threads: number of CPUs
+pub fn default_jobs() -> usize {
+    std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+/// Удаляет входную книгу или переносит её в корзину
+fn remove_input(book: &Path, trash: Option<&Path>) -> std::io::Result<()> {
+    let trash = match trash {
+        Some(t) => t,
+        None => return fs::remove_file(book)
+    };
+
+    fs::create_dir_all(trash)?;
+    let file_name = book.file_name().ok_or(std::io::ErrorKind::InvalidInput)?;
+    let target = zip_reader::unique_path(trash.join(file_name), Path::exists);
+    // на другой диск rename не работает, тогда копирование
+    if fs::rename(book, &target).is_err() {
+        fs::copy(book, &target)?;
+        fs::remove_file(book)?
+    };
+
+    Ok(())
+}
+
+fn apply_metadata(data: &mut fb2_parser::BookData, meta: Metadata) {
+    // исходное описание fb2 меняется так же, как командой edit, а если не вышло - строится заново
+    if let Some(description) = &data.description {
+        data.description = fb2_editor::edit_description(description, &meta).ok()
+    }
+    if let Some(title) = meta.title {
+        data.meta.title = title
+    }
+    if let Some(authors) = meta.authors {
+        data.meta.authors = authors
+    }
+    if let Some(language) = meta.language {
+        data.meta.language = language
+    }
+    if let Some(series) = meta.series {
+        if let Some(ref mut seq) = data.meta.sequence {
+            seq.name = series
+        } else {
+            data.meta.sequence = Some(Sequence {
+                name: series,
+                number: String::new()
+            })
+        }
+    }
+    if let Some(series_index) = meta.series_index {
+        if let Some(ref mut seq) = data.meta.sequence {
+            seq.number = series_index
+        } else {
+            data.meta.sequence = Some(Sequence {
+                name: String::new(),
+                number: series_index
+            })
+        }
+    }
+    if let Some(description) = meta.description {
+        data.meta.annotation = Some(description)
+    }
+    if let Some(genres) = meta.genres {
+        data.meta.genres = genres
+    }
+}
+
+/// Метаданные для книги: общие, поверх них - свои для этой книги из options.overrides,
+/// member - путь книги внутри архива
+fn book_metadata(
+    metadata: Option<Metadata>,
+    book: &Path,
+    member: Option<&Path>,
+    data: &fb2_parser::BookData,
+    options: &Options
+) -> Option<Metadata> {
+    if options.overrides.is_empty() {return metadata}
+    let own = options.overrides.get(book, member, data.meta.id.as_deref());
+
+    match (metadata, own) {
+        (Some(metadata), Some(own)) => Some(metadata.merge(own)),
+        (metadata, own) => own.or(metadata)
+    }
+}
+
+
+/// Читает книгу по расширению (без .gz, .bz2 и .xz для сжатых),
+/// meta_only - только описание, без текста
+fn read_book(
+    book: &Path,
+    extension: &str,
+    is_compressed: bool,
+    meta_only: bool
+) -> Result<fb2_parser::BookData, Box<dyn std::error::Error>> {
+    Ok(match extension {
+        _ if is_compressed && extension != "fb2" =>
+            return Err(format!("Compressed {extension} is not supported: {:#?}", book).into()),
+        "epub" if meta_only => epub_parser::get_meta(book)?,
+        "epub" => epub_parser::get_data(book)?,
+        "fb3" if meta_only => fb3_parser::get_meta_from_reader(fs::File::open(book)?)?,
+        "fb3" => fb3_parser::get_data(book)?,
+        _ if is_compressed => read_book_from_reader(compressed_reader::open(book)?, extension, meta_only)?,
+        _ if meta_only => fb2_parser::get_meta_from_bytes(&fs::read(book)?)?,
+        _ => fb2_parser::get_data(book)?
+    })
+}
+
+/// Читает книгу из потока, например, прямо из записи zip-архива.
+/// Книга читается в память целиком: для fb2 нужно определить кодировку,
+/// а fb3 - сам zip, которому нужен произвольный доступ
+fn read_book_from_reader<R: Read>(
+    reader: R,
+    extension: &str,
+    meta_only: bool
+) -> Result<fb2_parser::BookData, Box<dyn std::error::Error>> {
+    let bytes = compressed_reader::read_limited(reader)?;
+
+    match extension {
+        "fb3" if meta_only => fb3_parser::get_meta_from_reader(Cursor::new(bytes)),
+        "fb3" => fb3_parser::get_data_from_reader(Cursor::new(bytes)),
+        "epub" if meta_only => epub_parser::get_meta_from_reader(Cursor::new(bytes)),
+        "epub" => epub_parser::get_data_from_reader(Cursor::new(bytes)),
+        _ if meta_only => fb2_parser::get_meta_from_bytes(&bytes),
+        _ => fb2_parser::get_data_from_bytes(&bytes)
+    }
+}
+
+/// Записывает прочитанную из book книгу в output, если он занят - по политике on_exists
+#[allow(clippy::too_many_arguments)]
+fn save_book(
+    book: &Path,
+    mut data: fb2_parser::BookData,
+    output: &Path,
+    format: Format,
+    styles_path: Option<&Path>,
+    metadata: Option<Metadata>,
+    suspend_error_messages: bool,
+    options: &Options
+) -> Result<Converted, Box<dyn std::error::Error>> {
+    if let Some(meta) = metadata {
+        apply_metadata(&mut data, meta)
+    };
+    if let Some(split) = &options.split {
+        return save_parts(book, data, output, split, format, styles_path, suspend_error_messages, options)
+    };
+
+    // Имя по шаблону строится от папки, куда пошла бы книга
+    let output = &match &options.name_template {
+        Some(template) => {
+            let name = name_template::render(template, &data.meta, options.transliterate);
+            let file_name = format!("{}.{}", name.display(), format.extension());
+            output.parent().unwrap_or(Path::new("")).join(file_name)
+        },
+        None => output.to_owned()
+    };
+
+    if options.dry_run {
+        return Ok(match plan_output(book, output, format.extension(), options.on_exists) {
+            (output, Action::Skip) => skipped(output),
+            (output, action) => converted(output, data, 0, action)
+        })
+    };
+
+    // Проверка имени файла
+    if let Some(p) = output.parent() && !p.exists() {
+        fs::create_dir_all(p)?
+    };
+    
+    let (output, action, mut file) = match create_output(book, output, format.extension(), options.on_exists)? {
+        (output, _, None) => return Ok(skipped(output)),
+        (output, action, Some(file)) => (output, action, file)
+    };
+    let written = write_book(&mut data, format, &mut file, styles_path, suspend_error_messages, options);
+    drop(file);
+    let written = written.and_then(|_| if options.verify {verifier::verify(&output, format)} else {Ok(())});
+    if let Err(err) = written {
+        // недописанная или сломанная книга не остаётся на диске
+        let _ = fs::remove_file(&output);
+        return Err(err)
+    };
+    if options.on_exists == OnExists::Update {
+        manifest::record(book, &output)?
+    };
+
+    let size = fs::metadata(&output)?.len();
+    Ok(converted(output, data, size, action))
+}
+
+/// Делит книгу на части и записывает их в папку с именем выходной книги
+#[allow(clippy::too_many_arguments)]
+fn save_parts(
+    book: &Path,
+    data: fb2_parser::BookData,
+    output: &Path,
+    split: &Split,
+    format: Format,
+    styles_path: Option<&Path>,
+    suspend_error_messages: bool,
+    options: &Options
+) -> Result<Converted, Box<dyn std::error::Error>> {
+    let folder = get_output_name(output, format.extension())
+        .unwrap_or(output.to_owned())
+        .with_extension("");
+    let options = &Options {split: None, ..options.clone()};
+
+    let mut result = Converted {
+        output: folder.clone(),
+        encoding: data.encoding.clone(),
+        warnings: Vec::new(),
+        books: 0,
+        skipped: 0,
+        outputs: Vec::new(),
+        failed: Vec::new(),
+        kept: Vec::new()
+    };
+    for (i, part) in anthology::split(data, split)?.into_iter().enumerate() {
+        let name = name_template::render("{title}", &part.meta, options.transliterate);
+        let output = folder.join(format!("{:02} - {}.{}", i + 1, name.display(), format.extension()));
+        let converted = save_book(book, part, &output, format, styles_path, None, suspend_error_messages, options)?;
+        result.books += converted.books;
+        result.skipped += converted.skipped;
+        result.outputs.extend(converted.outputs);
+        // предупреждения книги есть у каждой части, они выводятся один раз
+        for warning in converted.warnings {
+            if !result.warnings.contains(&warning) {
+                result.warnings.push(warning)
+            }
+        };
+    };
+
+    Ok(result)
+}
+
+/// Записывает книгу в нужном формате в файл или в память
+fn write_book<W: Write + Seek>(
+    data: &mut fb2_parser::BookData,
+    format: Format,
+    writer: &mut W,
+    styles_path: Option<&Path>,
+    suspend_error_messages: bool,
+    options: &Options
+) -> Result<(), Box<dyn std::error::Error>> {
+    match format {
+        Format::Fb2 => fb2_creator::write_fb2(data, writer)
+            .map_err(|err| format!("Error while creating FB2: {}!", err).into()),
+        Format::Docx => docx_creator::write_docx(data, writer, suspend_error_messages)
+            .map_err(|err| format!("Error while creating Docx: {}!", err).into()),
+        Format::Epub => epub_creator::write_epub(data, writer, styles_path, suspend_error_messages, options)
+            .map_err(|err| format!("Error while creating Epub: {}!", err).into())
+    }
+}
+
+fn converted(output: PathBuf, data: fb2_parser::BookData, size: u64, action: Action) -> Converted {
+    let book = OutputBook {
+        path: output.clone(),
+        source: None,
+        action,
+        size,
+        title: data.meta.title,
+        authors: data.meta.authors,
+        series: data.meta.sequence.map(|s| (s.name, s.number))
+    };
+
+    Converted {
+        output,
+        encoding: data.encoding,
+        warnings: data.warnings,
+        books: 1,
+        skipped: 0,
+        outputs: vec![book],
+        failed: Vec::new(),
+        kept: Vec::new()
+    }
+}
+
+/// Пропущенная книга: в outputs старая книга, без метаданных
+fn skipped(output: PathBuf) -> Converted {
+    let book = OutputBook {
+        path: output.clone(),
+        source: None,
+        action: Action::Skip,
+        size: fs::metadata(&output).map(|m| m.len()).unwrap_or_default(),
+        title: String::new(),
+        authors: Vec::new(),
+        series: None
+    };
+
+    Converted {
+        output,
+        encoding: None,
+        warnings: Vec::new(),
+        books: 0,
+        skipped: 1,
+        outputs: vec![book],
+        failed: Vec::new(),
+        kept: Vec::new()
+    }
+}
+
+
+type ConvertArchive = fn(
+    &Path,
+    &Path,
+    Option<&Path>,
+    Option<Metadata>,
+    Option<Format>,
+    bool,
+    &Options
+) -> Result<Converted, Box<dyn std::error::Error>>;
+
+
+/// Takes path to fb2 book (or zip archive), returns path to new epub book.
 ///
 /// If replace = true input fb2 book will be deleted.
 ///
 /// styles_path is path to custom stylesheet, for default styles use None.
+///
+/// Kept for compatibility, see convert for other formats and options.
 pub fn run(
     book: &Path, 
     output: &Path, 
@@ -83,17 +743,74 @@ pub fn run(
     metadata: Option<Metadata>,
     suspend_error_messages: bool
 ) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    convert(book, output, replace, styles_path, metadata, Some(Format::Epub), suspend_error_messages, &Options::default())
+        .map(|converted| converted.output)
+}
+
+/// Main function, takes path to fb2 book (or fb3, fbz, fb2.gz/bz2/xz, zip or tar archive), returns path to new epub book,
+/// encoding of the input and warnings in Converted.
+///
+/// EPUB books go the other way: they are converted to fb2.
+///
+/// If replace = true input book will be deleted (or moved to options.trash) after the new book
+/// is checked. Archives are deleted only if every book from them is converted
+/// and they hold nothing else, see Converted.kept.
+/// Books skipped because of options.on_exists keep their inputs.
+///
+/// styles_path is path to custom stylesheet, for default styles use None.
+///
+/// format is output format, None means epub for fb2 and fb2 for epub.
+/// Fb2 output is normalized: broken nesting, duplicate binaries and ids are fixed.
+/// The description of an fb2 input is kept, metadata changes only its fields.
+///
+/// options are less common settings, see Options.
+#[allow(clippy::too_many_arguments)]
+pub fn convert(
+    book: &Path, 
+    output: &Path, 
+    replace: bool, 
+    styles_path: Option<&Path>,
+    metadata: Option<Metadata>,
+    format: Option<Format>,
+    suspend_error_messages: bool,
+    options: &Options
+) -> Result<Converted, Box<dyn std::error::Error>> {
+    // поток занят, пока конвертирует: книги архивов берут только свободные места
+    let _busy = zip_reader::Busy::new();
 
-    if book.extension().and_then(|s| Some(s.to_str()?.to_lowercase())) == Some("zip".to_string()) {
-        match crate::zip_reader::convert_archive(
+    // Для сжатых файлов расширение берётся без .gz, .bz2 и .xz
+    let is_compressed = compressed_reader::get_compression(book).is_some();
+    let inner_name = compressed_reader::inner_name(book);
+    let extension = Path::new(&inner_name).extension()
+        .and_then(|s| Some(s.to_str()?.to_string()))
+        .unwrap_or_default();
+
+    // Перед удалением входного файла новая книга обязательно проверяется
+    let options = &Options {
+        verify: options.verify || replace,
+        ..options.clone()
+    };
+    let trash = options.trash.as_deref();
+
+    // fbz - тот же zip с одной книгой
+    let convert_archive = match &extension[..] {
+        "zip" | "fbz" if !is_compressed => Some(zip_reader::convert_archive as ConvertArchive),
+        "tar" => Some(tar_reader::convert_archive as ConvertArchive),
+        _ => None
+    };
+    if let Some(convert_archive) = convert_archive {
+        match convert_archive(
             book,
             output,
             styles_path,
             metadata,
-            suspend_error_messages
+            format,
+            suspend_error_messages,
+            options
         ) {
-            Ok(o) if replace => {
-                fs::remove_file(book)?;
+            // архив удаляется, только если все книги из него сконвертированы и ничего другого в нём нет
+            Ok(o) if replace && o.skipped == 0 && o.failed.is_empty() && o.kept.is_empty() && !options.dry_run => {
+                remove_input(book, trash)?;
                 return Ok(o)
             },
             Ok(o) => return Ok(o),
@@ -101,64 +818,261 @@ pub fn run(
         }
     };
 
-    // Чтение входного FB2
-    let mut data = fb2_parser::get_data(book)?;
+    // Без шаблона имя известно заранее, и книгу можно пропустить не читая
+    let format = format.unwrap_or(Format::for_input(book));
+    if options.name_template.is_none() {
+        let output = get_output_name(output, format.extension()).unwrap_or(output.to_owned());
+        if should_skip(book, &output, options.on_exists) {
+            return Ok(skipped(output))
+        }
+    };
+
+    // Чтение входной книги
+    let data = read_book(book, &extension, is_compressed, options.meta_only())?;
     // print_sections(&data.content, true);
+    let metadata = book_metadata(metadata, book, None, &data, options);
     
-    
-    // Проверка имени файла
-    if let Some(p) = output.parent() {
-        if !p.exists() {
-            fs::create_dir_all(p)?
+    let converted = save_book(book, data, output, format, styles_path, metadata, suspend_error_messages, options)?;
+    if replace && converted.skipped == 0 && !options.dry_run {
+        remove_input(book, trash)?
+    };
+
+    Ok(converted)
+}
+
+/// Merges books into one epub (omnibus). Every book becomes a top-level entry of the table
+/// of contents with its own title page, its notes stay separate, equal images are stored once.
+/// Books of zip and tar archives are taken in the order of the archive.
+///
+/// The omnibus is named by the common series of the books or by their titles, metadata
+/// replaces that. If output is a folder, the file is named by the title. With options.dry_run
+/// a missing path without .epub extension is taken as a folder too.
+/// With OnExists::Update the omnibus is always written again.
+pub fn omnibus(
+    books: &[PathBuf],
+    output: &Path,
+    styles_path: Option<&Path>,
+    metadata: Option<Metadata>,
+    order: OmnibusOrder,
+    suspend_error_messages: bool,
+    options: &Options
+) -> Result<Converted, Box<dyn std::error::Error>> {
+    let first = books.first().ok_or("There's no books for omnibus")?;
+
+    let mut parts: Vec<(String, fb2_parser::BookData)> = Vec::new();
+    for book in books {
+        let is_compressed = compressed_reader::get_compression(book).is_some();
+        let inner_name = compressed_reader::inner_name(book);
+        let extension = Path::new(&inner_name).extension()
+            .and_then(|s| Some(s.to_str()?.to_lowercase()))
+            .unwrap_or_default();
+
+        let members = match &extension[..] {
+            "zip" | "fbz" if !is_compressed => zip_reader::read_books(book, options.dry_run)?,
+            "tar" => tar_reader::read_books(book, options.dry_run)?,
+            _ => vec![(PathBuf::new(), read_book(book, &extension, is_compressed, options.dry_run))]
+        };
+        for (member, data) in members {
+            let member = if member.as_os_str().is_empty() {None} else {Some(member)};
+            let name = match &member {
+                Some(m) => format!("{} ({})", book.display(), m.display()),
+                None => book.display().to_string()
+            };
+            let mut data = data.map_err(|err| format!("{name}: {err}"))?;
+            // свои метаданные книги, общие относятся ко всему сборнику
+            if let Some(own) = book_metadata(None, book, member.as_deref(), &data, options) {
+                apply_metadata(&mut data, own)
+            };
+            parts.push((name, data))
         }
     };
-    
-    let output =  &if let Some(o) = get_free_output(output) {o}
-    else {output.to_owned()};
-    
-    
+    if order == OmnibusOrder::Series {
+        omnibus::sort_by_series(&mut parts)
+    };
+
+    let mut data = omnibus::merge(parts);
     if let Some(meta) = metadata {
-        if let Some(title) = meta.title {
-            data.meta.title = title
-        }
-        if let Some(authors) = meta.authors {
-            data.meta.authors = authors
-        }
-        if let Some(language) = meta.language {
-            data.meta.language = language
-        }
-        if let Some(series) = meta.series {
-            if let Some(ref mut seq) = data.meta.sequence {
-                seq.name = series
-            } else {
-                data.meta.sequence = Some(Sequence {
-                    name: series,
-                    number: String::new()
-                })
-            }
-        }
-        if let Some(series_index) = meta.series_index {
-            if let Some(ref mut seq) = data.meta.sequence {
-                seq.number = series_index
-            } else {
-                data.meta.sequence = Some(Sequence {
-                    name: String::new(),
-                    number: series_index
-                })
-            }
-        }
-        if let Some(description) = meta.description {
-            data.meta.annotation = Some(description)
-        }
+        apply_metadata(&mut data, meta)
     };
-    
-    // Создание EPUB
-    match epub_creator::create_epub(&mut data, &output, styles_path, suspend_error_messages) {
-        Ok(o) if replace => {
-            fs::remove_file(book)?;
-            return Ok(o)
-        },
-        Ok(o) => return Ok(o),
-        Err(err) => Err(format!("Error while creating Epub: {}!", err).into())
+    // для плана папки может ещё не быть, тогда папка - путь без .epub
+    let is_dir = output.is_dir() || (options.dry_run && !output.exists()
+        && output.extension().is_none_or(|e| !e.eq_ignore_ascii_case(Format::Epub.extension())));
+    let output = if is_dir {
+        let name = name_template::render("{title}", &data.meta, options.transliterate);
+        output.join(format!("{}.{}", name.display(), Format::Epub.extension()))
+    } else {
+        output.to_owned()
+    };
+    // манифест знает только одну входную книгу
+    let options = &Options {
+        on_exists: if options.on_exists == OnExists::Update {OnExists::Overwrite} else {options.on_exists},
+        split: None,
+        ..options.clone()
+    };
+
+    save_book(first, data, &output, Format::Epub, styles_path, None, suspend_error_messages, options)
+}
+
+/// Book or books converted in memory, see convert_bytes
+#[derive(Debug)]
+pub struct ConvertedBytes {
+    /// New book, for a zip with several books - zip with new books at the same paths
+    pub data: Vec<u8>,
+    /// File name for data: name of the input book with the new extension
+    pub name: String,
+    /// Encoding the input book was read in, None for epub, fb3 and archives
+    pub encoding: Option<String>,
+    /// Problems that didn't stop conversion, for archives also books that cannot be converted
+    pub warnings: Vec<String>,
+    /// Every new book, path is its name, for several books - path inside data
+    pub outputs: Vec<OutputBook>
+}
+
+/// Расширение книги в памяти: по имени, без него - zip или fb2 по содержимому
+fn bytes_extension(bytes: &[u8], name: &str) -> String {
+    match Path::new(name).extension().and_then(|e| e.to_str()) {
+        Some(extension) => extension.to_lowercase(),
+        None if bytes.starts_with(b"PK") => String::from("zip"),
+        None => String::from("fb2")
     }
 }
+
+/// Конвертирует прочитанную книгу в память, book и member - для options.overrides
+#[allow(clippy::too_many_arguments)]
+fn book_to_bytes(
+    mut data: fb2_parser::BookData,
+    book: &Path,
+    member: Option<&Path>,
+    format: Format,
+    styles_path: Option<&Path>,
+    metadata: Option<Metadata>,
+    suspend_error_messages: bool,
+    options: &Options
+) -> Result<(Vec<u8>, Converted), Box<dyn std::error::Error>> {
+    if let Some(meta) = book_metadata(metadata, book, member, &data, options) {
+        apply_metadata(&mut data, meta)
+    };
+
+    let mut writer = Cursor::new(Vec::new());
+    write_book(&mut data, format, &mut writer, styles_path, suspend_error_messages, options)?;
+    let bytes = writer.into_inner();
+    if options.verify {
+        verifier::verify_bytes(&bytes, format)
+            .map_err(|err| format!("New book is broken: {err}"))?
+    };
+
+    let size = bytes.len() as u64;
+    Ok((bytes, converted(PathBuf::new(), data, size, Action::Create)))
+}
+
+/// Converts a book held in memory, nothing is written to disk. name is the file name
+/// of the input, its extension tells the format: fb2, fb3, epub, or zip and fbz with fb2
+/// and fb3 books. Without an extension zip or fb2 is guessed by content.
+/// A zip with one book gives that book, with several - a zip with new books.
+///
+/// format, styles_path, metadata and suspend_error_messages are the same as in run,
+/// from options only verify and overrides are used.
+pub fn convert_bytes(
+    bytes: &[u8],
+    name: &str,
+    format: Option<Format>,
+    styles_path: Option<&Path>,
+    metadata: Option<Metadata>,
+    suspend_error_messages: bool,
+    options: &Options
+) -> Result<ConvertedBytes, Box<dyn std::error::Error>> {
+    let extension = bytes_extension(bytes, name);
+    // как Format::for_input: epub в fb2, остальное, и архивы тоже, в epub
+    let format = format.unwrap_or(if extension == "epub" {Format::Fb2} else {Format::Epub});
+
+    if extension == "zip" || extension == "fbz" {
+        return zip_reader::convert_bytes(bytes, name, styles_path, metadata, format, suspend_error_messages, options)
+    };
+
+    let data = read_book_from_reader(bytes, &extension, false)?;
+    let (book, converted) = book_to_bytes(
+        data, Path::new(name), None, format, styles_path, metadata, suspend_error_messages, options
+    )?;
+    let stem = Path::new(name).file_stem().and_then(|s| s.to_str()).unwrap_or("new_book");
+    let name = format!("{stem}.{}", format.extension());
+
+    Ok(ConvertedBytes {
+        data: book,
+        outputs: converted.outputs.into_iter()
+            .map(|o| OutputBook {path: PathBuf::from(&name), ..o})
+            .collect(),
+        name,
+        encoding: converted.encoding,
+        warnings: converted.warnings
+    })
+}
+
+/// Information about one book or an error while reading it
+pub type InfoResult = Result<BookInfo, Box<dyn std::error::Error>>;
+
+/// Information about a book (fb2, fb3, epub, compressed fb2) or about every book
+/// of a zip or tar archive. If full is false only metadata is read, it's much faster,
+/// but images, sections and words are empty.
+///
+/// For archives a book that cannot be read gets its own error, other books are returned as usual.
+pub fn info(book: &Path, full: bool) -> Result<Vec<InfoResult>, Box<dyn std::error::Error>> {
+    let is_compressed = compressed_reader::get_compression(book).is_some();
+    let inner_name = compressed_reader::inner_name(book);
+    let extension = Path::new(&inner_name).extension()
+        .and_then(|s| Some(s.to_str()?.to_lowercase()))
+        .unwrap_or_default();
+
+    let books = match &extension[..] {
+        "zip" | "fbz" if !is_compressed => zip_reader::read_books(book, !full)?,
+        "tar" => tar_reader::read_books(book, !full)?,
+        _ => return Ok(vec![Ok(book_info::from_data(read_book(book, &extension, is_compressed, !full)?, None))])
+    };
+
+    Ok(books.into_iter()
+        .map(|(name, data)| data.map(|data| book_info::from_data(data, Some(name))))
+        .collect())
+}
+
+/// Bytes of one book of a zip or tar archive, source is its path inside as in BookInfo.source.
+/// The book can be converted with convert_bytes
+pub fn read_archive_book(archive: &Path, source: &Path) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let is_compressed = compressed_reader::get_compression(archive).is_some();
+    let inner_name = compressed_reader::inner_name(archive);
+    let extension = Path::new(&inner_name).extension()
+        .and_then(|s| Some(s.to_str()?.to_lowercase()))
+        .unwrap_or_default();
+
+    match &extension[..] {
+        "zip" | "fbz" if !is_compressed => zip_reader::read_book_bytes(archive, source),
+        "tar" => tar_reader::read_book_bytes(archive, source),
+        _ => Err(format!("{:#?} is not an archive", archive).into())
+    }
+}
+
+/// The same as info, but for a book held in memory, see convert_bytes for name
+pub fn info_bytes(bytes: &[u8], name: &str, full: bool) -> Result<Vec<InfoResult>, Box<dyn std::error::Error>> {
+    let extension = bytes_extension(bytes, name);
+    let books = match &extension[..] {
+        "zip" | "fbz" => zip_reader::read_archive_books(zip::ZipArchive::new(Cursor::new(bytes))?, !full)?,
+        _ => return Ok(vec![Ok(book_info::from_data(read_book_from_reader(bytes, &extension, !full)?, None))])
+    };
+
+    Ok(books.into_iter()
+        .map(|(name, data)| data.map(|data| book_info::from_data(data, Some(name))))
+        .collect())
+}
+
+/// Changes metadata of a fb2 book: fields of metadata that are not None replace fields
+/// of title-info (description replaces annotation), the rest of the book is kept byte for byte
+/// in its original encoding. output can be the same path as book.
+pub fn edit(book: &Path, output: &Path, metadata: &Metadata) -> Result<(), Box<dyn std::error::Error>> {
+    let extension = book.extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    if extension != "fb2" {
+        return Err(format!("Only fb2 books can be edited: {:#?}", book).into())
+    };
+
+    fb2_editor::edit(book, output, metadata)
+}
@@ -1,17 +1,37 @@
 extern crate fb2epub;
 
+use std::collections::HashMap;
 use std::path::{PathBuf, Path};
 use std::fs;
+use std::sync::{Arc, Mutex};
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use indicatif::{ProgressBar, ProgressStyle};
 use threadpool::ThreadPool;
 
+mod report;
+mod inspect;
+mod sidecar;
+mod config;
+mod watch;
+mod serve;
+mod opds;
+
 
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    run: RunArgs
+}
+
+/// Настройки конвертации, общие для обычного запуска и watch
+#[derive(clap::Args, Debug)]
+struct RunArgs {
     /// Input files. Can be a file or a directory. Also you can use it many times
     #[arg(short, long, num_args = 1.., required = true)]
     input: Vec<String>,
@@ -28,11 +48,157 @@ struct Args {
     #[arg(short, long)]
     recursive: bool,
 
-    /// DELETE inputs files after convertation.
+    /// DELETE inputs files after convertation. New books are checked first, archives are deleted only if all their books are converted
     #[arg(long)]
     replace: bool,
 
+    /// Output format: epub, fb2 or docx. By default fb2 goes to epub and epub to fb2
+    #[arg(short, long)]
+    format: Option<fb2epub::Format>,
+
+    /// Keep folders of zip and tar archives in the output folder
+    #[arg(long)]
+    keep_structure: bool,
+
+    /// Convert zip and tar archives to a zip with the same paths inside instead of a folder
+    #[arg(long)]
+    zip_output: bool,
+
+    /// Name output books by metadata, e.g. "{author_last}/{series}/{series_index:02} - {title}".
+    /// Fields: title, author, authors, author_first, author_last, series, series_index, language, genre
+    #[arg(long)]
+    name_template: Option<String>,
+
+    /// Use Latin letters instead of Cyrillic in names made by --name-template
+    #[arg(long)]
+    transliterate: bool,
+
+    /// What to do if output book already exists: overwrite, skip, rename or update.
+    /// update converts only books whose input is newer than output and has changed. rename by default
+    #[arg(long)]
+    on_exists: Option<fb2epub::OnExists>,
+
+    /// Check every new book after writing. Always on with --replace
+    #[arg(long)]
+    verify: bool,
+
+    /// With --replace move input files to this folder instead of deleting them
+    #[arg(long)]
+    trash: Option<PathBuf>,
+
+    /// Number of books converted at the same time, by default number of CPUs
+    #[arg(short, long)]
+    jobs: Option<usize>,
+
+    /// Print only errors
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Print every saved or skipped book, encoding of input books and small errors (image decoder errors, etc)
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Write a JSON report with a record for every input: outputs, status, errors, warnings, metadata, time and size
+    #[arg(long)]
+    report: Option<PathBuf>,
+
+    /// Print the JSON report to stdout instead of usual messages
+    #[arg(long)]
+    json: bool,
+
+    /// Show what would be done: input -> output, collisions and deletions.
+    /// Nothing is written, only metadata of books is read
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Merge all input books into one epub, each book with its own title page.
+    /// --output is the epub or a folder for it
+    #[arg(long, conflicts_with = "replace")]
+    omnibus: bool,
+
+    /// Order of books in --omnibus: series (by series index) or input. series by default
+    #[arg(long, requires = "omnibus")]
+    omnibus_order: Option<fb2epub::OmnibusOrder>,
+
+    /// Split every book into separate books at its top-level sections (e.g. an anthology into its works).
+    /// They go to a folder named as the output book
+    #[arg(long, conflicts_with_all = ["omnibus", "zip_output"])]
+    split: bool,
+
+    /// Split books at sections with these ids instead of top-level sections
+    #[arg(long, value_delimiter = ',', conflicts_with_all = ["omnibus", "zip_output"])]
+    split_ids: Option<Vec<String>>,
+
+    /// Sections deeper than this level go to the file of their parent in epub,
+    /// 1 - a file for every top-level section. By default every section has its own file
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..))]
+    split_level: Option<u8>,
+
+    /// Split epub files bigger than this size in KB at paragraphs
+    #[arg(long)]
+    max_file_size: Option<usize>,
+
+    /// Merge consecutive epub files smaller than this size in KB
+    #[arg(long)]
+    min_file_size: Option<usize>,
+
+    /// How notes are shown in epub: popup (marked for readers that show them in popups)
+    /// or links (plain links to the notes). popup by default
+    #[arg(long)]
+    footnotes: Option<fb2epub::Footnotes>,
+
+    /// Leave out images bigger than this size in KB from epub, the cover is kept
+    #[arg(long)]
+    max_image_size: Option<usize>,
+
+    /// EPUB version: 3 or 2 for old readers. 3 by default
+    #[arg(long)]
+    epub_version: Option<fb2epub::EpubVersion>,
+
+    /// CSV with metadata for particular books: a path column (with source for books in archives,
+    /// as in ls --format csv) or an id column and title, authors, series, series_index, genres,
+    /// language, annotation. Lists are separated by ";"
+    #[arg(long)]
+    metadata_map: Option<PathBuf>,
+
+    /// Don't read metadata from book.fb2.json, book.fb2.toml and metadata.opf next to books
+    #[arg(long)]
+    no_sidecars: bool,
+
+    /// Config file with default settings and presets, by default $XDG_CONFIG_HOME/fb2epub/config.toml.
+    /// Flags given in the command line replace settings from it
+    #[arg(long)]
+    config: Option<PathBuf>,
 
+    /// Use settings of a preset from the config file, e.g. [preset.kobo]
+    #[arg(long)]
+    preset: Option<String>,
+
+    // --no-recursive и другие выключают флаги, включённые конфигом
+    #[arg(long, hide = true, overrides_with = "recursive")]
+    no_recursive: bool,
+    #[arg(long, hide = true, overrides_with = "keep_structure")]
+    no_keep_structure: bool,
+    #[arg(long, hide = true, overrides_with = "zip_output")]
+    no_zip_output: bool,
+    #[arg(long, hide = true, overrides_with = "transliterate")]
+    no_transliterate: bool,
+    #[arg(long, hide = true, overrides_with = "verify")]
+    no_verify: bool,
+    #[arg(long, hide = true, overrides_with = "quiet")]
+    no_quiet: bool,
+    #[arg(long, hide = true, overrides_with = "verbose")]
+    no_verbose: bool,
+    #[arg(long, hide = true, overrides_with = "no_sidecars")]
+    sidecars: bool,
+
+    #[command(flatten)]
+    meta: MetaArgs
+}
+
+/// Метаданные из командной строки, общие для конвертации и edit
+#[derive(clap::Args, Debug)]
+struct MetaArgs {
     /// Use given title for input book(s)
     #[arg(long)]
     title: Option<String>,
@@ -51,7 +217,140 @@ struct Args {
 
     /// Use given series index for input book(s)
     #[arg(long)]
-    series_index: Option<String>
+    series_index: Option<String>,
+
+    /// Use given genre(s) for input book(s)
+    #[arg(long, num_args = 1..)]
+    genre: Option<Vec<String>>,
+
+    /// Use given annotation for input book(s), every value is a paragraph
+    #[arg(long, num_args = 1..)]
+    annotation: Option<Vec<String>>
+}
+
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print metadata and structure of books: title, authors, series, genres, language,
+    /// annotation, cover, number of images, sections and words
+    Info {
+        /// Books or archives with books
+        #[arg(required = true)]
+        books: Vec<PathBuf>
+    },
+
+    /// List metadata of all books in directories, only metadata is read
+    Ls {
+        /// Books, archives or directories
+        #[arg(required = true)]
+        inputs: Vec<String>,
+
+        /// Include books from subdirectories
+        #[arg(short, long)]
+        recursive: bool,
+
+        /// Output format: table, csv or json
+        #[arg(short, long, default_value = "table")]
+        format: inspect::ListFormat
+    },
+
+    /// Change title-info of fb2 books in place: the rest of the book and its encoding are kept
+    Edit {
+        /// Fb2 books
+        #[arg(required = true)]
+        books: Vec<PathBuf>,
+
+        /// Write the edited book here instead of replacing it, only for one book
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        #[command(flatten)]
+        meta: MetaArgs
+    },
+
+    /// Watch folders and convert new or changed books once they are fully written,
+    /// with the same flags as a usual run. Stops on Ctrl+C or SIGTERM after started books
+    Watch {
+        #[command(flatten)]
+        run: Box<RunArgs>,
+
+        /// Seconds without changes after which a book is considered fully written
+        #[arg(long, default_value_t = 2)]
+        settle: u64,
+
+        /// Append a JSON line for every converted, skipped or failed book to this file,
+        /// the same as records of --report with time
+        #[arg(long)]
+        log: Option<PathBuf>,
+
+        /// Also convert books that are already in the folders
+        #[arg(long)]
+        existing: bool
+    },
+
+    /// HTTP service: POST /convert (book or zip in the body -> new book), POST /info
+    /// (metadata as JSON), GET /health. Stops on Ctrl+C or SIGTERM after started requests
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        bind: String,
+
+        /// Maximum size of a request body in MB
+        #[arg(long, default_value_t = 50)]
+        max_size: usize,
+
+        /// Number of requests handled at the same time, by default number of CPUs
+        #[arg(short, long)]
+        jobs: Option<usize>,
+
+        /// Number of requests waiting for a free thread, others get 503
+        #[arg(long, default_value_t = 16)]
+        max_queue: usize,
+
+        /// Custom css styles for epub. Path to a .css file
+        #[arg(long)]
+        styles: Option<PathBuf>,
+
+        /// Check every new book before sending it
+        #[arg(long)]
+        verify: bool,
+
+        /// Don't print a line for every request
+        #[arg(short, long)]
+        quiet: bool
+    },
+
+    /// OPDS catalog of a library folder by author, series, genre and new arrivals.
+    /// Books are converted to epub when downloaded and kept in a cache
+    Opds {
+        /// Library folder, read recursively
+        #[arg(long)]
+        root: PathBuf,
+
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        bind: String,
+
+        /// Folder for converted books, by default $XDG_CACHE_HOME/fb2epub/opds or ~/.cache/fb2epub/opds
+        #[arg(long)]
+        cache: Option<PathBuf>,
+
+        /// Number of requests handled at the same time, by default number of CPUs
+        #[arg(short, long)]
+        jobs: Option<usize>,
+
+        /// Read the library folder again every N seconds, 0 to never
+        #[arg(long, default_value_t = 600)]
+        rescan: u64,
+
+        /// Custom css styles for epub. Path to a .css file
+        #[arg(long)]
+        styles: Option<PathBuf>,
+
+        /// Don't print a line for every request
+        #[arg(short, long)]
+        quiet: bool
+    }
 }
 
 
@@ -62,29 +361,42 @@ fn is_windows() -> bool {true}
 fn is_windows() -> bool {false}
 
 
-fn read_dir(dir: &Path, files: &mut Vec<PathBuf>, recursive: bool) -> std::io::Result<()> {
+/// with_epub - брать и epub: в папках они берутся, только если нужна обратная конвертация,
+/// иначе повторный запуск по папке конвертировал бы её новые epub обратно в fb2
+fn is_supported(path: &Path, with_epub: bool) -> bool {
+    let name = match path.file_name().and_then(|n| n.to_str()) {
+        Some(n) => n.to_lowercase(),
+        None => return false
+    };
+    if name.ends_with(".epub") {
+        return with_epub
+    };
+
+    [
+        ".fb2", ".fb3", ".fbz", ".zip",
+        ".fb2.gz", ".fb2.bz2", ".fb2.xz",
+        ".tar", ".tar.gz", ".tgz", ".tar.bz2", ".tbz2", ".tar.xz", ".txz"
+    ].iter().any(|ext| name.ends_with(ext))
+}
+
+fn read_dir(dir: &Path, files: &mut Vec<PathBuf>, recursive: bool, with_epub: bool) -> std::io::Result<()> {
     let entries = fs::read_dir(dir)?;
     for entry in entries {
         let path = entry?.path();
         if path.is_dir() {
-            if recursive {read_dir(&path, files, recursive)?}
+            if recursive {read_dir(&path, files, recursive, with_epub)?}
             continue
         };
-        if path.is_file() {
-            if let Some(extension) = path.extension() {
-                if let Some(ext) = extension.to_str() {
-                    if ext.to_lowercase() == "fb2" || ext.to_lowercase() == "zip" {
-                        if !files.contains(&path) {files.push(path)}
-                    }
-                }
-            }
+        if path.is_file() && is_supported(&path, with_epub) && !files.contains(&path) {
+            files.push(path)
         }
     };
     
     Ok(())
 }
 
-fn get_files(inputs: &Vec<String>, recursive: bool) -> Vec<PathBuf> {
+/// Книги из путей, epub из папок - только с with_epub, названные явно - всегда
+fn get_files(inputs: &Vec<String>, recursive: bool, with_epub: bool) -> Vec<PathBuf> {
     let mut files: Vec<PathBuf> = Vec::new();
     for i in inputs {
         let path = PathBuf::from(i);
@@ -96,28 +408,24 @@ fn get_files(inputs: &Vec<String>, recursive: bool) -> Vec<PathBuf> {
         };
         
         if path.is_dir() {
-            if let Err(err) = read_dir(&path, &mut files, recursive) {
+            if let Err(err) = read_dir(&path, &mut files, recursive, with_epub) {
                 eprintln!("Error while reading directory {:#?}: {}!", path, err)
             };
             continue
         };
         
-        if path.is_file() {
-            if let Some(extension) = path.extension() {
-                if let Some(ext) = extension.to_str() {
-                    if ext.to_lowercase() == "fb2" || ext.to_lowercase() == "zip" {
-                        if !files.contains(&path) {files.push(path)}
-                    }
-                }
-            }
+        if path.is_file() && is_supported(&path, true) && !files.contains(&path) {
+            files.push(path)
         }
     };
     
     return files
 }
 
-fn get_out_name(file: &Path, output: Option<PathBuf>) -> Option<PathBuf> {
-    let suffix = ".epub";
+fn get_out_name(file: &Path, output: Option<PathBuf>, output_is_dir: bool, format: Option<fb2epub::Format>) -> Option<PathBuf> {
+    // epub по умолчанию конвертируется обратно в fb2
+    let format = format.unwrap_or(fb2epub::Format::for_input(file));
+    let suffix = format!(".{}", format.extension());
     let file_stem: &str = if let Some(name) = file.file_stem() {
         if let Some(n) = name.to_str() {
             n
@@ -127,12 +435,12 @@ fn get_out_name(file: &Path, output: Option<PathBuf>) -> Option<PathBuf> {
     } else {
         "new_book"
     };
-    let file_name = file_stem.to_string() + suffix;
+    let file_name = file_stem.to_string() + &suffix;
     
     let mut parent: PathBuf = file.parent()?.to_path_buf();
     
     if let Some(output) = output {
-        if output.is_dir() {
+        if output_is_dir || output.is_dir() {
             let mut o = output.clone();
             o.push(file_name);
             Some(o)
@@ -145,38 +453,277 @@ fn get_out_name(file: &Path, output: Option<PathBuf>) -> Option<PathBuf> {
     }
 }
 
-fn parse_meta_from_args(args: &Args) -> Option<fb2epub::Metadata> {
-    let metadata = fb2epub::Metadata {
-        title: args.title.clone(),
-        authors: args.author.clone(),
-        language: args.language.clone(),
-        series: args.series.clone(),
-        series_index: args.series_index.clone(),
-        description: None
-    };
+fn parse_meta_from_args(args: &MetaArgs) -> Option<fb2epub::Metadata> {
+    let mut metadata = fb2epub::Metadata::default();
+    metadata.title = args.title.clone();
+    metadata.authors = args.author.clone();
+    metadata.language = args.language.clone();
+    metadata.series = args.series.clone();
+    metadata.series_index = args.series_index.clone();
+    metadata.description = args.annotation.clone();
+    metadata.genres = args.genre.clone();
 
     if metadata.title == None &&
         metadata.authors == None &&
         metadata.language == None &&
         metadata.series == None &&
         metadata.series_index == None &&
-        metadata.description == None { None }
+        metadata.description == None &&
+        metadata.genres.is_none() { None }
     else { Some(metadata) }
 }
 
+/// Подкоманда edit: false, если какую-то книгу изменить не удалось
+fn edit_books(books: &[PathBuf], output: Option<&Path>, meta: &MetaArgs) -> bool {
+    let metadata = match parse_meta_from_args(meta) {
+        Some(m) => m,
+        None => {
+            eprintln!("Nothing to change, use --title, --author, --series and other flags");
+            return false
+        }
+    };
+    if output.is_some() && books.len() > 1 {
+        eprintln!("--output can be used only with one book");
+        return false
+    };
+
+    let mut ok = true;
+    for book in books {
+        if let Err(err) = fb2epub::edit(book, output.unwrap_or(book), &metadata) {
+            eprintln!("{err}");
+            ok = false
+        }
+    };
+
+    ok
+}
+
+/// Метаданные отдельных книг: sidecar-файлы рядом с ними, поверх них --metadata-map
+fn read_overrides(files: &[PathBuf], args: &RunArgs) -> Result<fb2epub::Overrides, String> {
+    let mut overrides = fb2epub::Overrides::default();
+    if !args.no_sidecars {
+        for file in files {
+            match sidecar::read_sidecar(file) {
+                Some(Ok(meta)) => {overrides.by_path.insert(fb2epub::Overrides::key(file, None), meta);},
+                Some(Err(err)) => eprintln!("Cannot read metadata for {:#?}: {err}", file),
+                None => {}
+            }
+        }
+    };
 
-fn main() {
-    let args = Args::parse();
-    let files = get_files(&args.input, args.recursive);
-    if files.is_empty() {
-        panic!("There's no fb2 books in input!")
+    if let Some(path) = &args.metadata_map
+        && let Err(err) = sidecar::read_map(path, &mut overrides) {
+        return Err(format!("Cannot read {:#?}: {err}", path))
     };
-    
+
+    Ok(overrides)
+}
+
+
+/// Итоги запуска
+#[derive(Default)]
+struct Summary {
+    converted: usize,
+    skipped: usize,
+    failed: usize,
+    warnings: usize,
+    records: Vec<report::Record>,
+    // для --dry-run: выходной путь и книги, которые в него пойдут
+    planned: HashMap<PathBuf, Vec<String>>
+}
+
+/// Общие для всех книг настройки
+#[derive(Clone)]
+struct Settings {
+    output: Option<PathBuf>,
+    // --output - папка, даже если её ещё нет
+    output_is_dir: bool,
+    styles_path: Option<PathBuf>,
+    metadata: Option<fb2epub::Metadata>,
+    format: Option<fb2epub::Format>,
+    options: fb2epub::Options,
+    replace: bool,
+    quiet: bool,
+    verbose: bool,
+    // сохранять записи для отчёта
+    report: bool,
+    // печатать план вместо обычных сообщений
+    print_plan: bool
+}
+
+/// Входная книга, для архивов - с путём внутри
+fn input_name(file: &Path, book: &fb2epub::OutputBook) -> String {
+    match &book.source {
+        Some(source) => format!("{:#?} ({})", file, source.display()),
+        None => format!("{:#?}", file)
+    }
+}
+
+/// План для одного входного файла: куда пойдут книги и что будет с ним самим
+fn print_plan(file: &Path, converted: &fb2epub::Converted, settings: &Settings) {
+    for book in &converted.outputs {
+        let input = input_name(file, book);
+        let action = match book.action {
+            fb2epub::Action::Create => "",
+            fb2epub::Action::Overwrite => " (overwrite)",
+            fb2epub::Action::Rename => " (name is taken, renamed)",
+            fb2epub::Action::Skip => " (already exists, skip)"
+        };
+        println!("{input} -> {:#?}{action}", book.path)
+    };
+
+    // как в fb2epub::convert: вход удаляется, только если ничего не пропущено
+    if settings.replace && converted.skipped == 0 && converted.failed.is_empty() && converted.kept.is_empty() {
+        match &settings.options.trash {
+            Some(trash) => println!("Move {:#?} to {:#?}", file, trash),
+            None => println!("Remove {:#?}", file)
+        }
+    }
+}
+
+/// Конвертирует одну книгу, сообщения выводятся над прогресс-баром
+fn convert_file(file: &Path, settings: &Settings, bar: &ProgressBar, summary: &Mutex<Summary>) {
+    let start = std::time::Instant::now();
+    let result = match get_out_name(file, settings.output.clone(), settings.output_is_dir, settings.format) {
+        Some(output) => fb2epub::convert(
+            file,
+            &output,
+            settings.replace,
+            settings.styles_path.as_deref(),
+            settings.metadata.clone(),
+            settings.format,
+            !settings.verbose,
+            &settings.options
+        ),
+        None => Err(format!("Cannot get output name for {:#?}", file).into())
+    };
+
+    let mut summary = summary.lock().unwrap();
+    if settings.report {
+        summary.records.push(report::Record::new(file, &result, start.elapsed()))
+    };
+    match result {
+        Ok(o) => {
+            summary.converted += o.books;
+            summary.skipped += o.skipped;
+            summary.failed += o.failed.len();
+            summary.warnings += o.warnings.len();
+            if settings.options.dry_run {
+                for book in o.outputs.iter().filter(|b| b.action != fb2epub::Action::Skip) {
+                    summary.planned.entry(book.path.clone()).or_default().push(input_name(file, book))
+                }
+            };
+            if settings.print_plan {
+                bar.suspend(|| print_plan(file, &o, settings))
+            };
+            // ошибки книг архива выводятся, как и ошибка всей книги
+            if !o.failed.is_empty() {
+                bar.suspend(|| for err in &o.failed {
+                    eprintln!("{:#?}: {err}", file)
+                })
+            };
+            if settings.quiet {return}
+
+            bar.suspend(|| {
+                for warning in &o.warnings {
+                    eprintln!("{:#?}: {warning}", file)
+                };
+                // архив с другими файлами не удаляется, и это стоит объяснить
+                if settings.replace && !o.kept.is_empty() {
+                    let kept: Vec<String> = o.kept.iter().map(|k| k.display().to_string()).collect();
+                    eprintln!("{:#?}: kept, it also holds files that are not books: {}", file, kept.join(", "))
+                };
+                if !settings.verbose || settings.options.dry_run {return}
+
+                if let Some(encoding) = &o.encoding {
+                    println!("{:#?}: read as {encoding}", file)
+                };
+                if o.books == 0 && o.skipped > 0 {
+                    println!("Skipped {:#?}: {:#?} already exists", file, o.output)
+                } else {
+                    println!("Saved to {:#?}", o.output)
+                }
+            })
+        },
+        Err(err) => {
+            summary.failed += 1;
+            bar.suspend(|| eprintln!("{:#?}: {err}", file))
+        }
+    }
+}
+
+/// --omnibus: все книги в один epub, false - если не получилось
+fn make_omnibus(args: &RunArgs, files: &[PathBuf]) -> bool {
+    if args.format.is_some_and(|f| f != fb2epub::Format::Epub) {
+        eprintln!("--omnibus makes only epub");
+        return false
+    };
+    // без расширения .epub --output - папка
+    let is_file = args.output.as_ref().is_some_and(|o| o.to_lowercase().ends_with(".epub"));
+    let settings = make_settings(args, files, !is_file);
+    let output = match settings.output.clone().or(files[0].parent().map(|p| p.to_path_buf())) {
+        Some(o) => o,
+        None => {
+            eprintln!("Cannot get output name for {:#?}", files[0]);
+            return false
+        }
+    };
+
+    let start = std::time::Instant::now();
+    let result = fb2epub::omnibus(
+        files,
+        &output,
+        settings.styles_path.as_deref(),
+        settings.metadata.clone(),
+        args.omnibus_order.unwrap_or_default(),
+        !settings.verbose,
+        &settings.options
+    );
+    if settings.report {
+        let mut records = vec![report::Record::new(&files[0], &result, start.elapsed())];
+        if let Err(err) = report::write(&mut records, args.report.as_deref(), args.json) {
+            eprintln!("Cannot write report: {err}")
+        }
+    };
+
+    match result {
+        Ok(o) => {
+            if args.quiet || args.json {return true}
+            for warning in &o.warnings {
+                eprintln!("{warning}")
+            };
+            if settings.print_plan {
+                println!("{} books -> {:#?}", files.len(), o.output)
+            } else if o.books == 0 {
+                println!("Skipped: {:#?} already exists", o.output)
+            } else {
+                println!("Saved {} books to {:#?}", files.len(), o.output)
+            };
+            true
+        },
+        Err(err) => {
+            eprintln!("{err}");
+            false
+        }
+    }
+}
+
+/// Конфиг под аргументами командной строки, при ошибке - выход
+fn load_config(args: &mut RunArgs) {
+    if let Err(err) = config::apply(args) {
+        eprintln!("Cannot read config: {err}");
+        std::process::exit(1)
+    }
+}
+
+/// Настройки из аргументов, for_folder - --output всегда папка, как для нескольких книг
+fn make_settings(args: &RunArgs, files: &[PathBuf], for_folder: bool) -> Settings {
     let output = match args.output {
         Some(ref o) => {
             let output_path = PathBuf::from(o);
-            if files.len() > 1 {
-                if output_path.is_dir() {
+            if for_folder {
+                // при --dry-run папка не создаётся, книги всё равно пойдут в неё
+                if output_path.is_dir() || args.dry_run {
                     Some(output_path)
                 } else {
                     fs::create_dir_all(&output_path)
@@ -196,106 +743,163 @@ fn main() {
         else {None}
     } else {None};
 
-    let metadata = parse_meta_from_args(&args);
+    let metadata = parse_meta_from_args(&args.meta);
+    let overrides = read_overrides(files, args).unwrap_or_else(|err| {
+        eprintln!("{err}");
+        std::process::exit(1)
+    });
+    let jobs = args.jobs.unwrap_or_else(fb2epub::default_jobs).max(1);
+    let options = fb2epub::Options {
+        keep_structure: args.keep_structure,
+        zip_output: args.zip_output,
+        name_template: args.name_template.clone(),
+        transliterate: args.transliterate,
+        on_exists: args.on_exists.unwrap_or_default(),
+        verify: args.verify,
+        trash: args.trash.clone(),
+        jobs: Some(jobs),
+        dry_run: args.dry_run,
+        overrides: Arc::new(overrides),
+        split: match &args.split_ids {
+            Some(ids) => Some(fb2epub::Split::Ids(ids.clone())),
+            None if args.split => Some(fb2epub::Split::Sections),
+            None => None
+        },
+        layout: fb2epub::Layout {
+            split_level: args.split_level,
+            max_size: args.max_file_size,
+            min_size: args.min_file_size
+        },
+        footnotes: args.footnotes.unwrap_or_default(),
+        epub_version: args.epub_version.unwrap_or_default(),
+        max_image_size: args.max_image_size
+    };
 
-    
-    if files.len() > 1 {
-        let pool = ThreadPool::new(10);
+    Settings {
+        output,
+        output_is_dir: for_folder,
+        styles_path,
+        metadata,
+        format: args.format,
+        options,
+        replace: args.replace,
+        quiet: args.quiet,
+        verbose: args.verbose && !args.json,
+        report: args.report.is_some() || args.json,
+        print_plan: args.dry_run && !args.json
+    }
+}
 
-        if is_windows() {
-            for file in files {
-                let output = if let Some(o) = get_out_name(&file, output.clone()) {o}
-                else {continue};
-        
-                let styles_path = styles_path.clone();
-                let metadata = metadata.clone();
-                pool.execute(move || {
-                    match fb2epub::run(
-                        &file,
-                        &output,
-                        args.replace,
-                        styles_path.as_deref(),
-                        metadata,
-                        true
-                    ) {
-                        Ok(o) => println!("Saved to {:#?}", o),
-                        Err(err) => eprintln!("{err}")
-                    }
-                });
-            }
-        } else {
-            let bar = ProgressBar::new(files.len().try_into().unwrap());
-
-            for file in files {
-                let output = if let Some(o) = get_out_name(&file, output.clone()) {o}
-                else {continue};
-
-                let styles_path = styles_path.clone();
-                let metadata = metadata.clone();
-                let bar = bar.clone();
-                pool.execute(move || {
-                    match fb2epub::run(
-                        &file,
-                        &output,
-                        args.replace,
-                        styles_path.as_deref(),
-                        metadata,
-                        true
-                    ) {
-                        Ok(_) => {}, // bar.println(format!("Saved to {:#?}", o)),
-                        Err(err) => bar.println(format!("{}", err))
-                    };
-                    bar.inc(1);
-                });
+fn main() {
+    let args = Args::parse();
+    let ok = match args.command {
+        Some(Command::Info {books}) => Some(inspect::info(&books)),
+        Some(Command::Ls {inputs, recursive, format}) => Some(inspect::ls(&inputs, recursive, format)),
+        Some(Command::Edit {books, output, meta}) => Some(edit_books(&books, output.as_deref(), &meta)),
+        Some(Command::Watch {run, settle, log, existing}) => Some(watch::watch(*run, settle, log, existing)),
+        Some(Command::Serve {bind, max_size, jobs, max_queue, styles, verify, quiet}) => Some(serve::serve(serve::ServeSettings {
+            bind,
+            max_size: max_size.saturating_mul(1024 * 1024),
+            jobs: jobs.unwrap_or_else(fb2epub::default_jobs).max(1),
+            max_queue,
+            styles,
+            verify,
+            quiet
+        })),
+        Some(Command::Opds {root, bind, cache, jobs, rescan, styles, quiet}) => {
+            match cache.or_else(opds::default_cache) {
+                Some(cache) => Some(opds::opds(opds::OpdsSettings {
+                    root,
+                    bind,
+                    cache,
+                    jobs: jobs.unwrap_or_else(fb2epub::default_jobs).max(1),
+                    rescan,
+                    styles,
+                    quiet
+                })),
+                None => {
+                    eprintln!("Cannot find a cache folder, set --cache");
+                    Some(false)
+                }
             }
-        };
+        },
+        None => None
+    };
+    if let Some(ok) = ok {
+        std::process::exit(if ok {0} else {1})
+    };
+
+    let mut args = args.run;
+    load_config(&mut args);
+
+    let files = get_files(&args.input, args.recursive, args.format == Some(fb2epub::Format::Fb2));
+    if files.is_empty() {
+        panic!("There's no fb2 or epub books in input!")
+    };
+    if args.omnibus {
+        std::process::exit(if make_omnibus(&args, &files) {0} else {1})
+    };
+
+    let settings = Arc::new(make_settings(&args, &files, files.len() > 1));
+    let jobs = settings.options.jobs.unwrap_or(1);
+    let summary = Arc::new(Mutex::new(Summary::default()));
+
+    let bar = if args.quiet || is_windows() {
+        ProgressBar::hidden()
+    } else if files.len() > 1 {
+        ProgressBar::new(files.len().try_into().unwrap())
+    } else {
+        let file_name = if let Some(name) = files[0].file_name()
+            .and_then(|n| n.to_str()) {name}
+        else {"Cannot get file name"};
+
+        let sp = ProgressBar::new_spinner();
+        sp.set_style(
+            ProgressStyle::default_spinner()
+                .template("{spinner:.green} {msg:.green}").unwrap()
+        );
+        sp.enable_steady_tick(std::time::Duration::from_millis(100));
+        sp.set_message(file_name.to_owned());
+        sp
+    };
 
+    if files.len() > 1 {
+        let pool = ThreadPool::new(jobs);
+        for file in files {
+            let settings = Arc::clone(&settings);
+            let summary = Arc::clone(&summary);
+            let bar = bar.clone();
+            pool.execute(move || {
+                convert_file(&file, &settings, &bar, &summary);
+                bar.inc(1);
+            });
+        };
         pool.join();
     } else {
-        if is_windows() {
-            let file = &files[0];
-            let output = get_out_name(file, output.clone()).unwrap();
-    
-            match fb2epub::run(
-                file,
-                &output,
-                args.replace,
-                styles_path.as_deref(),
-                metadata,
-                true
-            ) {
-                Ok(o) => println!("Saved to {:#?}", o),
-                Err(err) => eprintln!("{err}")
-            }
-        } else {
-            let file = &files[0];
-            let output = get_out_name(file, output.clone()).unwrap();
+        convert_file(&files[0], &settings, &bar, &summary);
+    };
+    bar.finish_and_clear();
 
-        
-            let file_name = if let Some(name) = file.file_name()
-                .and_then(|n| n.to_str()) {name}
-            else {"Cannot get file name"};
-            
-            let sp = ProgressBar::new_spinner();
-            sp.set_style(
-                ProgressStyle::default_spinner()
-                    .template("{spinner:.green} {msg:.green}").unwrap()
-            );
-            sp.enable_steady_tick(std::time::Duration::from_millis(100));
-            sp.set_message(file_name.to_owned());
-        
-            if let Err(err) = fb2epub::run(
-                file,
-                &output,
-                args.replace,
-                styles_path.as_deref(),
-                metadata,
-                true
-            ) {
-                eprintln!("{err}")
-            };
-            
-            sp.finish_and_clear();
+    let mut summary = summary.lock().unwrap();
+    if settings.report
+        && let Err(err) = report::write(&mut summary.records, args.report.as_deref(), args.json) {
+        eprintln!("Cannot write report: {err}")
+    };
+    if settings.print_plan {
+        let mut collisions: Vec<_> = summary.planned.iter().filter(|(_, inputs)| inputs.len() > 1).collect();
+        collisions.sort();
+        for (output, inputs) in collisions {
+            println!("Collision: {:#?} is planned for {}", output, inputs.join(", "))
         }
+    };
+    if args.dry_run && !args.quiet && !args.json {
+        println!("Would convert: {}, skip: {}, failed: {}, warnings: {}",
+            summary.converted, summary.skipped, summary.failed, summary.warnings)
+    } else if !args.quiet && !args.json {
+        println!("Converted: {}, skipped: {}, failed: {}, warnings: {}",
+            summary.converted, summary.skipped, summary.failed, summary.warnings)
+    };
+    if summary.failed > 0 {
+        std::process::exit(1)
     }
 }
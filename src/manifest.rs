@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+use std::time::SystemTime;
+
+use sha2::{Digest, Sha256};
+
+
+// Файл в папке с книгами: SHA-256 входного файла и имя книги, сделанной из него.
+// Хеш с постоянным алгоритмом, чтобы манифесты не устаревали с новой версией Rust
+const MANIFEST_NAME: &str = ".fb2epub-sources";
+
+// Время изменения, размер и хеш файла
+type Hashed = (Option<SystemTime>, u64, String);
+
+// Прочитанные манифесты по папкам и посчитанные хеши входных файлов
+// с их временем изменения и размером: изменённый файл считается заново
+static MANIFESTS: LazyLock<Mutex<HashMap<PathBuf, HashMap<String, String>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+static HASHES: LazyLock<Mutex<HashMap<PathBuf, Hashed>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+
+fn split(output: &Path) -> Option<(PathBuf, String)> {
+    let folder = output.parent()?.to_path_buf();
+    let name = output.file_name()?.to_str()?.to_string();
+
+    Some((folder, name))
+}
+
+fn read_manifest(folder: &Path) -> HashMap<String, String> {
+    let mut records: HashMap<String, String> = HashMap::new();
+    let text = fs::read_to_string(folder.join(MANIFEST_NAME)).unwrap_or_default();
+    for line in text.lines() {
+        // более поздняя запись о той же книге главнее
+        if let Some((hash, name)) = line.split_once('\t')
+            && hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            records.insert(name.to_string(), hash.to_string());
+        }
+    };
+
+    records
+}
+
+/// Хеш содержимого входного файла, архив считается один раз для всех его книг,
+/// пока он не изменился (например, в watch)
+fn input_hash(input: &Path) -> io::Result<String> {
+    let metadata = fs::metadata(input)?;
+    let (modified, len) = (metadata.modified().ok(), metadata.len());
+    if let Some((m, l, hash)) = HASHES.lock().unwrap().get(input)
+        && *m == modified && *l == len {
+        return Ok(hash.clone())
+    };
+
+    let mut file = File::open(input)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {break}
+        hasher.update(&buf[..n]);
+    };
+    let hash: String = hasher.finalize().iter().map(|b| format!("{b:02x}")).collect();
+
+    HASHES.lock().unwrap().insert(input.to_path_buf(), (modified, len, hash.clone()));
+    Ok(hash)
+}
+
+fn is_newer(output: &Path, input: &Path) -> bool {
+    let modified = |p: &Path| fs::metadata(p).and_then(|m| m.modified()).ok();
+    match (modified(output), modified(input)) {
+        (Some(o), Some(i)) => o >= i,
+        _ => false
+    }
+}
+
+
+/// Книга не требует обновления: она новее входного файла
+/// или сделана из файла с тем же содержимым
+pub fn is_up_to_date(input: &Path, output: &Path) -> bool {
+    if !output.exists() {return false}
+    if is_newer(output, input) {return true}
+
+    let (folder, name) = match split(output) {
+        Some(s) => s,
+        None => return false
+    };
+    let recorded = MANIFESTS.lock().unwrap()
+        .entry(folder.clone())
+        .or_insert_with(|| read_manifest(&folder))
+        .get(&name)
+        .cloned();
+
+    match (recorded, input_hash(input)) {
+        (Some(recorded), Ok(hash)) => recorded == hash,
+        _ => false
+    }
+}
+
+/// Запоминает, из какого входного файла сделана книга
+pub fn record(input: &Path, output: &Path) -> io::Result<()> {
+    let (folder, name) = match split(output) {
+        Some(s) => s,
+        None => return Ok(())
+    };
+    let hash = input_hash(input)?;
+
+    let mut manifests = MANIFESTS.lock().unwrap();
+    let records = manifests.entry(folder.clone())
+        .or_insert_with(|| read_manifest(&folder));
+    if records.get(&name) == Some(&hash) {return Ok(())}
+    records.insert(name.clone(), hash.clone());
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(folder.join(MANIFEST_NAME))?;
+    writeln!(file, "{hash}\t{name}")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+
+    use super::{is_up_to_date, record};
+
+    fn write(path: &Path, text: &str, seconds: u64) {
+        fs::write(path, text).unwrap();
+        File::options().write(true).open(path).unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)).unwrap();
+    }
+
+    /// Книга из файла с тем же содержимым не обновляется, даже если файл новее
+    #[test]
+    fn same_content_is_up_to_date() {
+        let dir = tempfile::tempdir().unwrap();
+        let (input, output) = (dir.path().join("a.fb2"), dir.path().join("a.epub"));
+        write(&output, "book", 1000);
+        write(&input, "one", 2000);
+        record(&input, &output).unwrap();
+
+        write(&input, "one", 3000);
+        assert!(is_up_to_date(&input, &output));
+    }
+
+    /// Изменённый файл хешируется заново, а не берётся из кеша (как в watch)
+    #[test]
+    fn changed_input_is_converted_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let (input, output) = (dir.path().join("a.fb2"), dir.path().join("a.epub"));
+        write(&output, "book", 1000);
+        write(&input, "one", 2000);
+        record(&input, &output).unwrap();
+        assert!(is_up_to_date(&input, &output));
+
+        write(&input, "two", 3000);
+        assert!(!is_up_to_date(&input, &output));
+        record(&input, &output).unwrap();
+        assert!(is_up_to_date(&input, &output));
+        let manifest = fs::read_to_string(dir.path().join(super::MANIFEST_NAME)).unwrap();
+        assert_eq!(manifest.lines().count(), 2);
+    }
+}
@@ -0,0 +1,164 @@
+use std::path::PathBuf;
+
+use crate::fb2_parser::metadata_reader::Metadata;
+
+
+// Имена, которые Windows не даёт использовать даже с расширением
+const RESERVED: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9"
+];
+
+// Место под "-N" и расширение в пределах 255 байт
+const MAX_NAME_LENGTH: usize = 200;
+
+
+fn transliterate_char(c: char) -> Option<&'static str> {
+    Some(match c.to_lowercase().next()? {
+        'а' => "a", 'б' => "b", 'в' => "v", 'г' => "g", 'д' => "d",
+        'е' => "e", 'ё' => "yo", 'ж' => "zh", 'з' => "z", 'и' => "i",
+        'й' => "y", 'к' => "k", 'л' => "l", 'м' => "m", 'н' => "n",
+        'о' => "o", 'п' => "p", 'р' => "r", 'с' => "s", 'т' => "t",
+        'у' => "u", 'ф' => "f", 'х' => "kh", 'ц' => "ts", 'ч' => "ch",
+        'ш' => "sh", 'щ' => "shch", 'ъ' => "", 'ы' => "y", 'ь' => "",
+        'э' => "e", 'ю' => "yu", 'я' => "ya",
+        // украинские и белорусские буквы
+        'і' => "i", 'ї' => "yi", 'є' => "ye", 'ґ' => "g", 'ў' => "u",
+        _ => return None
+    })
+}
+
+/// Кириллица латиницей: "Толстой" -> "Tolstoy"
+pub fn transliterate(s: &str) -> String {
+    let mut result = String::new();
+    for c in s.chars() {
+        match transliterate_char(c) {
+            Some(latin) if c.is_uppercase() => {
+                let mut chars = latin.chars();
+                if let Some(first) = chars.next() {
+                    result.extend(first.to_uppercase());
+                    result.push_str(chars.as_str());
+                }
+            },
+            Some(latin) => result.push_str(latin),
+            None => result.push(c)
+        }
+    };
+
+    result
+}
+
+/// Часть пути, допустимая в Windows, macOS и Linux
+fn sanitize(name: &str) -> String {
+    let mut result: String = name.chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c
+        })
+        .collect();
+
+    if result.len() > MAX_NAME_LENGTH {
+        let mut end = MAX_NAME_LENGTH;
+        while !result.is_char_boundary(end) {end -= 1}
+        result.truncate(end)
+    };
+
+    // Windows не любит точки и пробелы в конце
+    let result = result.trim_end_matches(['.', ' ']).trim_start().to_string();
+    let stem = result.split('.').next().unwrap_or_default();
+    if RESERVED.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        return format!("{result}_")
+    };
+
+    result
+}
+
+fn get_value(meta: &Metadata, key: &str) -> Option<String> {
+    let first_author = meta.authors.first().map(|a| a.trim()).unwrap_or_default();
+    Some(match key {
+        "title" => meta.title.clone(),
+        "author" => first_author.to_string(),
+        "authors" => meta.authors.join(", "),
+        "author_first" => match first_author.split_whitespace().collect::<Vec<_>>()[..] {
+            [first, _, ..] => first.to_string(),
+            _ => String::new()
+        },
+        "author_last" => first_author.split_whitespace().last().unwrap_or_default().to_string(),
+        "series" => meta.sequence.as_ref().map(|s| s.name.clone()).unwrap_or_default(),
+        "series_index" => meta.sequence.as_ref().map(|s| s.number.clone()).unwrap_or_default(),
+        "language" => meta.language.clone(),
+        "genre" => meta.genres.first().cloned().unwrap_or_default(),
+        _ => return None
+    })
+}
+
+/// {key:02} - дополнить нулями слева до двух знаков
+fn apply_format(value: String, spec: &str) -> String {
+    let width = match spec.strip_prefix('0').and_then(|w| w.parse::<usize>().ok()) {
+        Some(w) if !value.is_empty() => w,
+        _ => return value
+    };
+
+    format!("{value:0>width$}")
+}
+
+/// Одна часть пути: None, если все её поля пустые
+fn render_part(part: &str, meta: &Metadata) -> Option<String> {
+    let mut result = String::new();
+    let mut has_fields = false;
+    let mut has_values = false;
+
+    let mut rest = part;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        let end = match rest[start..].find('}') {
+            Some(e) => start + e,
+            None => break
+        };
+
+        let field = &rest[start + 1..end];
+        let (key, spec) = field.split_once(':').unwrap_or((field, ""));
+        match get_value(meta, key.trim()) {
+            Some(value) => {
+                has_fields = true;
+                let value = apply_format(value.trim().to_string(), spec);
+                has_values |= !value.is_empty();
+                result.push_str(&value.replace(['/', '\\'], "_"));
+            },
+            // неизвестное поле остаётся как есть
+            None => result.push_str(&rest[start..=end])
+        };
+        rest = &rest[end + 1..];
+    };
+    result.push_str(rest);
+
+    if has_fields && !has_values {return None}
+    // разделители вокруг пустых полей: " - Название" -> "Название"
+    Some(result.trim_matches([' ', '-', '_', ',', '.']).to_string())
+}
+
+
+/// Путь книги по шаблону вроде "{author_last}/{series}/{series_index:02} - {title}",
+/// без расширения. Части с пустыми полями пропускаются
+pub fn render(template: &str, meta: &Metadata, transliterate_names: bool) -> PathBuf {
+    let mut path = PathBuf::new();
+    for part in template.split(['/', '\\']) {
+        let part = match render_part(part, meta) {
+            Some(p) => p,
+            None => continue
+        };
+        let part = if transliterate_names {transliterate(&part)} else {part};
+        let part = sanitize(&part);
+        if !part.is_empty() && part != ".." {
+            path.push(part)
+        }
+    };
+
+    if path.as_os_str().is_empty() {
+        path.push("new_book")
+    };
+
+    path
+}
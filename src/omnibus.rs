@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+
+use crate::fb2_creator::normalizer::{walk, walk_content, blocks_mut, link_map};
+use crate::fb2_parser::{BookData, Section, Image};
+use crate::fb2_parser::metadata_reader::{Metadata, Sequence};
+use crate::fb2_parser::content_reader::*;
+
+
+/// Номер в серии для сортировки, без номера - None
+fn series_index(data: &BookData) -> Option<f64> {
+    data.meta.sequence.as_ref()?.number.trim().replace(',', ".").parse::<f64>().ok()
+}
+
+/// Книги по номерам в серии, книги без номера - после них в прежнем порядке
+pub fn sort_by_series(books: &mut [(String, BookData)]) {
+    books.sort_by(|(_, a), (_, b)| match (series_index(a), series_index(b)) {
+        (Some(a), Some(b)) => a.total_cmp(&b),
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => std::cmp::Ordering::Equal
+    })
+}
+
+
+fn text_block(s: &str) -> TextBlock {
+    TextBlock {
+        text: s.to_string(),
+        strong: false,
+        emphasis: false,
+        strikethrough: false,
+        code: false,
+        sup: false,
+        sub: false,
+        link: None
+    }
+}
+
+fn text(s: &str) -> Paragraph {
+    Paragraph::Text(vec![text_block(s)])
+}
+
+/// Титульная страница книги: название, авторы, серия и аннотация
+fn title_page(meta: &Metadata, prefix: &str) -> Section {
+    let mut paragraphs: Vec<Paragraph> = Vec::new();
+    if !meta.authors.is_empty() {
+        paragraphs.push(Paragraph::Subtitle(vec![TextBlock {
+            emphasis: true,
+            ..text_block(&meta.authors.join(", "))
+        }]))
+    };
+    if let Some(seq) = &meta.sequence && !seq.name.is_empty() {
+        let series = if seq.number.is_empty() {seq.name.clone()} else {format!("{} #{}", seq.name, seq.number)};
+        paragraphs.push(Paragraph::Subtitle(vec![text_block(&series)]))
+    };
+    if let Some(annotation) = &meta.annotation {
+        paragraphs.push(Paragraph::EmptyLine);
+        paragraphs.extend(annotation.iter().map(|p| text(p)))
+    };
+
+    Section {
+        level: 0,
+        id: None,
+        file_name: Some(format!("{prefix}title")),
+        title: vec![text(&meta.title)],
+        paragraphs
+    }
+}
+
+/// Добавляет к id префикс книги, чтобы id разных книг не совпадали
+fn prefix_ids(section: &mut Section, prefix: &str) {
+    let rename = |id: &mut Option<String>| {
+        if let Some(id) = id && !id.is_empty() {
+            *id = format!("{prefix}{id}")
+        }
+    };
+    rename(&mut section.id);
+
+    let mut paragraphs = std::mem::take(&mut section.paragraphs);
+    walk(&mut paragraphs, &mut |p| match p {
+        Paragraph::Note(s) | Paragraph::Epigraph(s) |
+        Paragraph::Cite(s) | Paragraph::Annotation(s) => rename(&mut s.id),
+        Paragraph::Poem(poem) => {
+            rename(&mut poem.id);
+            for stanza in &mut poem.stanzas {
+                rename(&mut stanza.id)
+            }
+        },
+        _ => {}
+    });
+    section.paragraphs = paragraphs;
+}
+
+/// Собирает несколько книг в одну. Каждая книга - раздел верхнего уровня
+/// со своей титульной страницей, её файлы и id получают префикс b01_, b02_...,
+/// примечания остаются отдельными для каждой книги, одинаковые картинки хранятся один раз.
+/// Имя книги нужно для её предупреждений
+pub fn merge(books: Vec<(String, BookData)>) -> BookData {
+    let mut content: Vec<Section> = Vec::new();
+    let mut images: HashMap<String, Image> = HashMap::new();
+    // картинка по типу и содержимому -> её ключ в общей книге
+    let mut seen: HashMap<(String, String), String> = HashMap::new();
+    let mut warnings: Vec<String> = Vec::new();
+
+    let mut titles: Vec<String> = Vec::new();
+    let mut authors: Vec<String> = Vec::new();
+    let mut genres: Vec<String> = Vec::new();
+    let mut languages: Vec<String> = Vec::new();
+    let mut series: Vec<String> = Vec::new();
+    let mut cover: Option<String> = None;
+
+    for (i, (name, mut data)) in books.into_iter().enumerate() {
+        let prefix = format!("b{:02}_", i + 1);
+
+        // Картинки
+        let mut keys: Vec<String> = data.images.keys().cloned().collect();
+        keys.sort();
+        let mut renamed: HashMap<String, String> = HashMap::new();
+        for key in keys {
+            let mut image = match data.images.remove(&key) {
+                Some(i) => i,
+                None => continue
+            };
+            let content = (image.content_type.clone(), image.binary.clone());
+            let new_key = match seen.get(&content) {
+                Some(k) => k.clone(),
+                None => {
+                    image.id = format!("{prefix}{}", image.id);
+                    let new_key = format!("#{}", image.id);
+                    seen.insert(content, new_key.clone());
+                    images.insert(new_key.clone(), image);
+                    new_key
+                }
+            };
+            renamed.insert(key, new_key);
+        };
+        if cover.is_none() {
+            cover = data.meta.cover.as_ref().and_then(|c| renamed.get(c).cloned())
+        };
+
+        // Ссылки внутри книги и картинки
+        walk_content(&mut data.content, &mut |p| {
+            if let Paragraph::Image(href) = p {
+                *href = href.as_ref().and_then(|h| renamed.get(h).cloned());
+                return
+            };
+            for blocks in blocks_mut(p) {
+                for block in blocks {
+                    if let Some(link) = &mut block.link && link.link.starts_with('#') {
+                        link.link = format!("#{prefix}{}", &link.link[1..])
+                    }
+                }
+            }
+        });
+
+        content.push(title_page(&data.meta, &prefix));
+        for (j, mut section) in data.content.into_iter().enumerate() {
+            prefix_ids(&mut section, &prefix);
+            section.level += 1;
+            section.file_name = Some(match section.file_name {
+                Some(file_name) => format!("{prefix}{file_name}"),
+                None => format!("{prefix}part_{}", j + 1)
+            });
+            content.push(section)
+        };
+
+        let meta = data.meta;
+        for author in meta.authors {
+            if !authors.contains(&author) {authors.push(author)}
+        };
+        for genre in meta.genres {
+            if !genres.contains(&genre) {genres.push(genre)}
+        };
+        if !meta.language.is_empty() {languages.push(meta.language)};
+        series.push(meta.sequence.map(|s| s.name).unwrap_or_default());
+        titles.push(meta.title);
+        warnings.extend(data.warnings.into_iter().map(|w| format!("{name}: {w}")));
+    };
+
+    // Название - общая серия, если она есть, иначе названия книг
+    let common_series = series.first()
+        .filter(|s| !s.is_empty() && series.iter().all(|other| other == *s))
+        .cloned();
+    let meta = Metadata {
+        title: common_series.clone().unwrap_or_else(|| titles.join("; ")),
+        authors,
+        genres,
+        language: languages.into_iter().next().unwrap_or_default(),
+        sequence: common_series.map(|name| Sequence {name, number: String::new()}),
+        annotation: Some(titles),
+        cover,
+        id: None
+    };
+
+    BookData {
+        meta,
+        link_map: link_map(&content),
+        content,
+        images,
+        encoding: None,
+        warnings,
+        description: None
+    }
+}
@@ -0,0 +1,510 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use quick_xml::escape::escape;
+use sha2::{Digest, Sha256};
+use threadpool::ThreadPool;
+use tiny_http::{Request, Response, Server};
+
+use crate::serve::{decode_component, encode_component, error_response, header};
+
+
+const NAVIGATION: &str = "application/atom+xml;profile=opds-catalog;kind=navigation";
+const ACQUISITION: &str = "application/atom+xml;profile=opds-catalog;kind=acquisition";
+// записей на странице каталога
+const PAGE_SIZE: usize = 50;
+
+
+/// Настройки каталога из командной строки
+pub struct OpdsSettings {
+    pub root: PathBuf,
+    pub bind: String,
+    /// Папка для сконвертированных книг
+    pub cache: PathBuf,
+    pub jobs: usize,
+    /// Через сколько секунд перечитывать папку, 0 - никогда
+    pub rescan: u64,
+    pub styles: Option<PathBuf>,
+    pub quiet: bool
+}
+
+/// Книга каталога
+#[derive(Clone)]
+struct Book {
+    id: String,
+    path: PathBuf,
+    // путь внутри архива
+    source: Option<PathBuf>,
+    title: String,
+    authors: Vec<String>,
+    series: Option<(String, String)>,
+    genres: Vec<String>,
+    language: String,
+    annotation: String,
+    modified: SystemTime,
+    size: u64
+}
+
+#[derive(Default)]
+struct Index {
+    books: Vec<Book>,
+    by_id: HashMap<String, usize>
+}
+
+/// $XDG_CACHE_HOME/fb2epub/opds, без XDG_CACHE_HOME - ~/.cache
+pub fn default_cache() -> Option<PathBuf> {
+    let folder = std::env::var_os("XDG_CACHE_HOME")
+        .filter(|f| !f.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| Some(crate::config::home_dir()?.join(".cache")))?;
+
+    Some(folder.join("fb2epub").join("opds"))
+}
+
+/// SHA-256 частей, первые 16 байт: id книг в ссылках и имена в кэше
+/// не должны меняться от версии Rust
+fn hash_hex(parts: &[&[u8]]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        // длина перед частью, чтобы "ab" + "c" и "a" + "bc" не совпали
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    };
+
+    hasher.finalize()[..16].iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn book_id(path: &Path, source: Option<&Path>) -> String {
+    let source = source.map(|s| s.as_os_str().as_encoded_bytes()).unwrap_or_default();
+    hash_hex(&[path.as_os_str().as_encoded_bytes(), source])
+}
+
+/// Время в формате Atom (RFC 3339, UTC)
+fn rfc3339(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default() as i64;
+    let (days, rest) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+    // дата из числа дней от 1970-01-01 (алгоритм civil_from_days)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 {mp + 3} else {mp - 9};
+    let year = yoe + era * 400 + if month <= 2 {1} else {0};
+
+    format!("{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z", rest / 3600, rest % 3600 / 60, rest % 60)
+}
+
+
+/// Читает описания всех книг в root, книги старого индекса с тем же временем
+/// изменения и размером файла не перечитываются
+fn scan(root: &Path, old: Option<&Index>, quiet: bool) -> Index {
+    let mut previous: HashMap<&Path, Vec<&Book>> = HashMap::new();
+    if let Some(old) = old {
+        for book in &old.books {
+            previous.entry(book.path.as_path()).or_default().push(book)
+        }
+    };
+
+    let mut books: Vec<Book> = Vec::new();
+    for path in crate::get_files(&vec![root.to_string_lossy().to_string()], true, false) {
+        let (modified, size) = match fs::metadata(&path) {
+            Ok(m) => (m.modified().unwrap_or(UNIX_EPOCH), m.len()),
+            Err(_) => continue
+        };
+        if let Some(old) = previous.get(path.as_path())
+            && old.iter().all(|b| b.modified == modified && b.size == size) {
+            books.extend(old.iter().map(|b| (*b).clone()));
+            continue
+        };
+
+        let infos = match fb2epub::info(&path, false) {
+            Ok(i) => i,
+            Err(err) => {
+                if !quiet {eprintln!("{:#?}: {err}", path)}
+                continue
+            }
+        };
+        for info in infos {
+            let info = match info {
+                Ok(i) => i,
+                Err(err) => {
+                    if !quiet {eprintln!("{:#?}: {err}", path)}
+                    continue
+                }
+            };
+            books.push(Book {
+                id: book_id(&path, info.source.as_deref()),
+                path: path.clone(),
+                source: info.source,
+                title: info.title,
+                authors: info.authors,
+                series: info.series.filter(|(name, _)| !name.trim().is_empty()),
+                genres: info.genres,
+                language: info.language,
+                annotation: info.annotation.join("\n"),
+                modified,
+                size
+            })
+        }
+    };
+
+    books.sort_by_key(|b| b.title.to_lowercase());
+    let by_id = books.iter().enumerate().map(|(i, b)| (b.id.clone(), i)).collect();
+    Index {books, by_id}
+}
+
+
+fn feed(id: &str, title: &str, url: &str, kind: &str, page: usize, total: usize, entries: &str) -> String {
+    let mut links = format!(
+        "  <link rel=\"self\" href=\"{}\" type=\"{kind}\"/>\n  <link rel=\"start\" href=\"/opds\" type=\"{NAVIGATION}\"/>\n",
+        escape(url)
+    );
+    let separator = if url.contains('?') {'&'} else {'?'};
+    let base = url.split("?page=").next().unwrap_or(url);
+    if page > 0 {
+        links.push_str(&format!("  <link rel=\"previous\" href=\"{}{separator}page={}\" type=\"{kind}\"/>\n", escape(base), page - 1))
+    };
+    if (page + 1) * PAGE_SIZE < total {
+        links.push_str(&format!("  <link rel=\"next\" href=\"{}{separator}page={}\" type=\"{kind}\"/>\n", escape(base), page + 1))
+    };
+
+    format!(concat!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n",
+        "<feed xmlns=\"http://www.w3.org/2005/Atom\" xmlns:dc=\"http://purl.org/dc/terms/\" ",
+        "xmlns:opds=\"http://opds-spec.org/2010/catalog\">\n",
+        "  <id>urn:fb2epub:{}</id>\n  <title>{}</title>\n  <updated>{}</updated>\n{}{}</feed>\n"),
+        escape(id), escape(title), rfc3339(SystemTime::now()), links, entries)
+}
+
+fn navigation_entry(title: &str, href: &str, kind: &str, content: &str) -> String {
+    format!(concat!(
+        "  <entry>\n    <title>{}</title>\n    <id>urn:fb2epub:{}</id>\n    <updated>{}</updated>\n",
+        "    <content type=\"text\">{}</content>\n",
+        "    <link rel=\"subsection\" href=\"{}\" type=\"{}\"/>\n  </entry>\n"),
+        escape(title), escape(href), rfc3339(SystemTime::now()), escape(content), escape(href), kind)
+}
+
+fn book_entry(book: &Book) -> String {
+    let mut entry = format!("  <entry>\n    <title>{}</title>\n    <id>urn:fb2epub:{}</id>\n    <updated>{}</updated>\n",
+        escape(&book.title), book.id, rfc3339(book.modified));
+    for author in &book.authors {
+        entry.push_str(&format!("    <author><name>{}</name></author>\n", escape(author)))
+    };
+    if !book.language.is_empty() {
+        entry.push_str(&format!("    <dc:language>{}</dc:language>\n", escape(&book.language)))
+    };
+    for genre in &book.genres {
+        entry.push_str(&format!("    <category term=\"{0}\" label=\"{0}\"/>\n", escape(genre)))
+    };
+    let mut summary = book.annotation.clone();
+    if let Some((name, index)) = &book.series {
+        let series = if index.is_empty() {name.clone()} else {format!("{name} #{index}")};
+        summary = if summary.is_empty() {series} else {format!("{series}\n{summary}")}
+    };
+    if !summary.is_empty() {
+        entry.push_str(&format!("    <summary type=\"text\">{}</summary>\n", escape(&summary)))
+    };
+    entry.push_str(&format!(
+        "    <link rel=\"http://opds-spec.org/acquisition\" href=\"/download/{}.epub\" type=\"application/epub+zip\"/>\n  </entry>\n",
+        book.id
+    ));
+
+    entry
+}
+
+/// Список групп (авторы, серии, жанры) с числом книг, по алфавиту
+fn groups(books: &[Book], key: impl Fn(&Book) -> Vec<String>) -> Vec<(String, usize)> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for book in books {
+        for name in key(book) {
+            if !name.trim().is_empty() {
+                *counts.entry(name).or_default() += 1
+            }
+        }
+    };
+    let mut groups: Vec<(String, usize)> = counts.into_iter().collect();
+    groups.sort_by_key(|(name, _)| name.to_lowercase());
+
+    groups
+}
+
+/// Значения, по которым книга попадает в группы
+type Key = fn(&Book) -> Vec<String>;
+
+fn authors(book: &Book) -> Vec<String> {book.authors.clone()}
+fn series(book: &Book) -> Vec<String> {book.series.iter().map(|(name, _)| name.clone()).collect()}
+fn genres(book: &Book) -> Vec<String> {book.genres.clone()}
+
+fn page_of<T>(items: &[T], page: usize) -> &[T] {
+    let start = (page * PAGE_SIZE).min(items.len());
+    &items[start..(start + PAGE_SIZE).min(items.len())]
+}
+
+/// Каталог по пути запроса, None - такого нет
+fn catalog(index: &Index, path: &str, url: &str, page: usize) -> Option<String> {
+    let segments: Vec<String> = path.trim_matches('/').split('/').map(decode_component).collect();
+    let segments: Vec<&str> = segments.iter().map(|s| s.as_str()).collect();
+    let by: [(&str, &str, Key); 3] = [
+        ("authors", "Authors", authors),
+        ("series", "Series", series),
+        ("genres", "Genres", genres)
+    ];
+
+    match segments[..] {
+        ["opds"] | [""] => {
+            let mut entries = String::new();
+            for (name, title, key) in by {
+                let count = groups(&index.books, key).len();
+                let title = title.to_lowercase();
+                entries.push_str(&navigation_entry(&format!("By {title}"), &format!("/opds/{name}"), NAVIGATION, &format!("{count} {title}")))
+            };
+            entries.push_str(&navigation_entry("New arrivals", "/opds/new", ACQUISITION, &format!("{} books", index.books.len())));
+            Some(feed("root", "Library", url, NAVIGATION, 0, 0, &entries))
+        },
+        ["opds", "new"] => {
+            let mut books: Vec<&Book> = index.books.iter().collect();
+            books.sort_by_key(|b| std::cmp::Reverse(b.modified));
+            let entries: String = page_of(&books, page).iter().map(|b| book_entry(b)).collect();
+            Some(feed("new", "New arrivals", url, ACQUISITION, page, books.len(), &entries))
+        },
+        ["opds", group] => {
+            let (name, title, key) = by.into_iter().find(|(name, _, _)| *name == group)?;
+            let list = groups(&index.books, key);
+            let entries: String = page_of(&list, page).iter()
+                .map(|(value, count)| navigation_entry(value, &format!("/opds/{name}/{}", encode_component(value)), ACQUISITION, &format!("{count} books")))
+                .collect();
+            Some(feed(name, title, url, NAVIGATION, page, list.len(), &entries))
+        },
+        ["opds", group, value] => {
+            let (name, _, key) = by.into_iter().find(|(name, _, _)| *name == group)?;
+            let mut books: Vec<&Book> = index.books.iter().filter(|b| key(b).iter().any(|v| v == value)).collect();
+            if books.is_empty() {return None}
+            if name == "series" {
+                // книги серии - по номеру
+                let number = |b: &Book| b.series.as_ref().and_then(|(_, i)| i.trim().parse::<f64>().ok()).unwrap_or(f64::MAX);
+                books.sort_by(|a, b| number(a).total_cmp(&number(b)))
+            };
+            let entries: String = page_of(&books, page).iter().map(|b| book_entry(b)).collect();
+            Some(feed(&format!("{name}:{value}"), value, url, ACQUISITION, page, books.len(), &entries))
+        },
+        _ => None
+    }
+}
+
+
+/// Удаляет из кэша epub, для которых stale(id книги, путь) - true.
+/// Имя в кэше - "id книги-ключ версии.epub", временные файлы не трогаются
+fn prune_cache(cache: &Path, stale: impl Fn(&str, &Path) -> bool) {
+    let entries = match fs::read_dir(cache) {
+        Ok(e) => e,
+        Err(_) => return
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().to_string();
+        let stem = match name.strip_suffix(".epub") {
+            Some(s) if !s.starts_with(".tmp") => s,
+            _ => continue
+        };
+        // старые имена без ключа версии тоже убираются
+        let id = stem.split_once('-').map(|(id, _)| id).unwrap_or(stem);
+        if stale(id, &path) {
+            let _ = fs::remove_file(&path);
+        }
+    }
+}
+
+/// Epub для книги из кэша, если его нет - конвертирует. Имя в кэше зависит от файла,
+/// времени его изменения и размера, содержимого стилей и версии fb2epub,
+/// так что изменённая книга конвертируется заново, а старый epub удаляется
+fn cached_epub(book: &Book, settings: &OpdsSettings) -> Result<PathBuf, Box<dyn Error>> {
+    let modified = book.modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+    let styles = match &settings.styles {
+        Some(path) => fs::read(path)?,
+        None => Vec::new()
+    };
+    let key = hash_hex(&[
+        &modified.to_le_bytes(),
+        &book.size.to_le_bytes(),
+        &styles,
+        env!("CARGO_PKG_VERSION").as_bytes()
+    ]);
+    let cached = settings.cache.join(format!("{}-{key}.epub", book.id));
+    if cached.is_file() {
+        return Ok(cached)
+    };
+
+    fs::create_dir_all(&settings.cache)?;
+    let options = fb2epub::Options {
+        on_exists: fb2epub::OnExists::Overwrite,
+        ..fb2epub::Options::default()
+    };
+    // с расширением epub run запишет книгу в этот же файл
+    let mut temp = tempfile::Builder::new().suffix(".epub").tempfile_in(&settings.cache)?;
+    match &book.source {
+        Some(source) => {
+            let bytes = fb2epub::read_archive_book(&book.path, source)?;
+            let name = source.file_name().and_then(|n| n.to_str()).unwrap_or("book.fb2");
+            let converted = fb2epub::convert_bytes(
+                &bytes, name, Some(fb2epub::Format::Epub), settings.styles.as_deref(), None, true, &options
+            )?;
+            std::io::Write::write_all(&mut temp, &converted.data)?
+        },
+        None => {
+            fb2epub::convert(
+                &book.path, temp.path(), false, settings.styles.as_deref(), None,
+                Some(fb2epub::Format::Epub), true, &options
+            )?;
+        }
+    };
+    temp.persist(&cached)?;
+    // прежние версии этой книги больше не нужны
+    prune_cache(&settings.cache, |id, path| id == book.id && path != cached);
+
+    Ok(cached)
+}
+
+fn download(index: &RwLock<Index>, id: &str, settings: &OpdsSettings) -> Result<Response<fs::File>, (u16, String)> {
+    let book = {
+        let index = index.read().unwrap();
+        let i = *index.by_id.get(id).ok_or((404, format!("There's no book {id}")))?;
+        index.books[i].clone()
+    };
+    let path = cached_epub(&book, settings).map_err(|err| (500, err.to_string()))?;
+    let file = fs::File::open(&path).map_err(|err| (500, err.to_string()))?;
+
+    let name = match book.authors.first() {
+        Some(author) => format!("{author} - {}.epub", book.title),
+        None => format!("{}.epub", book.title)
+    };
+    Ok(Response::from_file(file)
+        .with_header(header("Content-Type", "application/epub+zip"))
+        .with_header(header("Content-Disposition", &format!("attachment; filename*=UTF-8''{}", encode_component(&name)))))
+}
+
+fn handle(request: Request, index: &RwLock<Index>, settings: &OpdsSettings) {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let page = query.split('&')
+        .find_map(|p| p.strip_prefix("page="))
+        .and_then(|p| p.parse::<usize>().ok())
+        .unwrap_or_default();
+
+    let response = if let Some(id) = path.strip_prefix("/download/").and_then(|p| p.strip_suffix(".epub")) {
+        match download(index, id, settings) {
+            Ok(response) => response.boxed(),
+            Err((status, message)) => error_response(status, &message).boxed()
+        }
+    } else {
+        let xml = catalog(&index.read().unwrap(), path, &url, page);
+        match xml {
+            Some(xml) => Response::from_string(xml)
+                .with_header(header("Content-Type", "application/atom+xml;charset=utf-8"))
+                .boxed(),
+            None => error_response(404, &format!("There's no {path}")).boxed()
+        }
+    };
+    let status = response.status_code().0;
+    let result = request.respond(response);
+
+    if !settings.quiet {
+        println!("GET {url} {status}")
+    };
+    if let Err(err) = result {
+        eprintln!("Cannot send response: {err}")
+    }
+}
+
+/// Подкоманда opds: каталог книг root, epub делаются при скачивании.
+/// Работает до Ctrl+C или SIGTERM, false - если не удалось запустить
+pub fn opds(settings: OpdsSettings) -> bool {
+    if !settings.root.is_dir() {
+        eprintln!("There's no such directory: {:#?}!", settings.root);
+        return false
+    };
+    let index = Arc::new(RwLock::new(scan(&settings.root, None, settings.quiet)));
+    prune_cache(&settings.cache, |id, _| !index.read().unwrap().by_id.contains_key(id));
+    if !settings.quiet {
+        println!("{} books in {:#?}", index.read().unwrap().books.len(), settings.root)
+    };
+
+    let server = match Server::http(&settings.bind) {
+        Ok(s) => Arc::new(s),
+        Err(err) => {
+            eprintln!("Cannot listen on {}: {err}", settings.bind);
+            return false
+        }
+    };
+    let stop = Arc::clone(&server);
+    if let Err(err) = ctrlc::set_handler(move || stop.unblock()) {
+        eprintln!("Cannot set signal handler: {err}");
+        return false
+    };
+    if !settings.quiet {
+        println!("Catalog on http://{}/opds, press Ctrl+C to stop", server.server_addr())
+    };
+
+    let settings = Arc::new(settings);
+    if settings.rescan > 0 {
+        let (index, settings) = (Arc::clone(&index), Arc::clone(&settings));
+        // поток сканирования не держит сервер при выходе
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(settings.rescan));
+            let new = scan(&settings.root, Some(&index.read().unwrap()), settings.quiet);
+            // книги, которых больше нет в папке
+            prune_cache(&settings.cache, |id, _| !new.by_id.contains_key(id));
+            *index.write().unwrap() = new;
+        });
+    };
+
+    let pool = ThreadPool::new(settings.jobs);
+    for request in server.incoming_requests() {
+        let (index, settings) = (Arc::clone(&index), Arc::clone(&settings));
+        pool.execute(move || handle(request, &index, &settings));
+    };
+    pool.join();
+
+    true
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::{book_id, prune_cache};
+
+    /// id книги не зависит от версии Rust: ссылки в читалках и кэш остаются рабочими
+    #[test]
+    fn book_id_is_stable() {
+        assert_eq!(book_id(Path::new("library/book.fb2"), None), "d38bb9c1ced50d18303e58cfa462ae27");
+        assert_ne!(book_id(Path::new("books.zip"), Some(Path::new("a.fb2"))), book_id(Path::new("books.zipa.fb2"), None));
+    }
+
+    #[test]
+    fn prune_keeps_wanted_and_temporary_files() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["aa-1.epub", "aa-2.epub", "bb-1.epub", "0123abcd.epub", ".tmp123.epub"] {
+            fs::write(dir.path().join(name), "epub").unwrap()
+        };
+
+        prune_cache(dir.path(), |id, path| id == "aa" && path != dir.path().join("aa-2.epub"));
+        prune_cache(dir.path(), |id, _| !["aa", "bb"].contains(&id));
+
+        let mut left: Vec<String> = fs::read_dir(dir.path()).unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        left.sort();
+        assert_eq!(left, [".tmp123.epub", "aa-2.epub", "bb-1.epub"]);
+    }
+}
@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::Metadata;
+use crate::epub_parser::opf_reader::opf_reader;
+
+
+/// Metadata for particular books, see Options.overrides
+#[derive(Clone, Default, Debug)]
+pub struct Overrides {
+    /// By path of the input book, for books inside archives - path of the archive
+    /// joined with path inside it. Paths are compared after Overrides::key
+    pub by_path: HashMap<PathBuf, Metadata>,
+    /// By id of the document (document-info/id in fb2, identifier in epub)
+    pub by_id: HashMap<String, Metadata>
+}
+
+impl Overrides {
+    /// Path as it's stored in by_path: absolute, for books in archives - with path inside
+    pub fn key(book: &Path, member: Option<&Path>) -> PathBuf {
+        let book = fs::canonicalize(book).unwrap_or(book.to_path_buf());
+        match member {
+            Some(member) => book.join(member),
+            None => book
+        }
+    }
+
+    /// Metadata for the book: by path or by path of its archive (for every book in it),
+    /// fields set by id replace them
+    pub fn get(&self, book: &Path, member: Option<&Path>, id: Option<&str>) -> Option<Metadata> {
+        let by_path = if self.by_path.is_empty() {None} else {
+            self.by_path.get(&Overrides::key(book, member))
+                .or_else(|| self.by_path.get(&Overrides::key(book, None)))
+        };
+        let by_id = id.and_then(|id| self.by_id.get(id.trim()));
+
+        match (by_path, by_id) {
+            (Some(path), Some(id)) => Some(path.clone().merge(id.clone())),
+            (path, id) => path.or(id).cloned()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_path.is_empty() && self.by_id.is_empty()
+    }
+}
+
+impl Metadata {
+    /// Fields set in other replace fields of self
+    pub fn merge(self, other: Metadata) -> Metadata {
+        Metadata {
+            title: other.title.or(self.title),
+            authors: other.authors.or(self.authors),
+            language: other.language.or(self.language),
+            series: other.series.or(self.series),
+            series_index: other.series_index.or(self.series_index),
+            description: other.description.or(self.description),
+            genres: other.genres.or(self.genres)
+        }
+    }
+
+    /// Metadata from OPF, e.g. metadata.opf that calibre puts next to books.
+    /// Fields missing in OPF are None
+    pub fn from_opf(xml: &str) -> Result<Metadata, Box<dyn std::error::Error>> {
+        let meta = opf_reader(xml.trim_start_matches('\u{feff}'), "metadata.opf")?.meta;
+        let some = |s: String| if s.trim().is_empty() {None} else {Some(s)};
+        let some_vec = |v: Vec<String>| if v.is_empty() {None} else {Some(v)};
+
+        Ok(Metadata {
+            title: some(meta.title),
+            authors: some_vec(meta.authors),
+            language: some(meta.language),
+            series: meta.sequence.as_ref().and_then(|s| some(s.name.clone())),
+            series_index: meta.sequence.and_then(|s| some(s.number)),
+            description: meta.annotation.and_then(some_vec),
+            genres: some_vec(meta.genres)
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::Overrides;
+    use crate::Metadata;
+
+    #[test]
+    fn id_replaces_fields_of_path() {
+        let mut overrides = Overrides::default();
+        overrides.by_path.insert(Overrides::key(Path::new("book.fb2"), None), Metadata {
+            title: Some(String::from("By path")),
+            series: Some(String::from("Series")),
+            ..Metadata::default()
+        });
+        overrides.by_id.insert(String::from("doc-1"), Metadata {
+            title: Some(String::from("By id")),
+            ..Metadata::default()
+        });
+
+        let meta = overrides.get(Path::new("book.fb2"), None, Some(" doc-1 ")).unwrap();
+        assert_eq!(meta.title.as_deref(), Some("By id"));
+        assert_eq!(meta.series.as_deref(), Some("Series"));
+
+        let meta = overrides.get(Path::new("other.fb2"), None, Some("doc-1")).unwrap();
+        assert_eq!(meta.title.as_deref(), Some("By id"));
+        assert!(overrides.get(Path::new("other.fb2"), None, None).is_none());
+    }
+}
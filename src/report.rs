@@ -0,0 +1,177 @@
+use std::error::Error;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Converted,
+    Skipped,
+    Failed
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Action {
+    Create,
+    Overwrite,
+    Rename,
+    Skip
+}
+
+impl From<fb2epub::Action> for Action {
+    fn from(action: fb2epub::Action) -> Self {
+        match action {
+            fb2epub::Action::Create => Action::Create,
+            fb2epub::Action::Overwrite => Action::Overwrite,
+            fb2epub::Action::Rename => Action::Rename,
+            fb2epub::Action::Skip => Action::Skip
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Series {
+    name: String,
+    index: String
+}
+
+#[derive(Serialize)]
+struct Output {
+    path: PathBuf,
+    // путь книги внутри архива
+    source: Option<PathBuf>,
+    action: Action,
+    size: u64,
+    title: String,
+    authors: Vec<String>,
+    series: Option<Series>
+}
+
+#[derive(Serialize)]
+struct ErrorInfo {
+    kind: &'static str,
+    message: String
+}
+
+/// Запись отчёта об одном входном файле
+#[derive(Serialize)]
+pub struct Record {
+    input: PathBuf,
+    status: Status,
+    outputs: Vec<Output>,
+    skipped: usize,
+    encoding: Option<String>,
+    warnings: Vec<String>,
+    // книги архива, которые не удалось сконвертировать
+    failed: Vec<String>,
+    // файлы архива, которые не книги
+    kept: Vec<PathBuf>,
+    error: Option<ErrorInfo>,
+    // секунды
+    elapsed: f64
+}
+
+
+/// Вид ошибки по её типу, ошибки конвертации приходят строками
+fn error_kind(err: &(dyn Error + 'static)) -> &'static str {
+    if err.is::<io::Error>() {"io"}
+    else if err.is::<zip::result::ZipError>() {"zip"}
+    else if err.is::<quick_xml::Error>() {"xml"}
+    else {"conversion"}
+}
+
+impl Record {
+    pub fn new(
+        input: &Path,
+        result: &Result<fb2epub::Converted, Box<dyn Error>>,
+        elapsed: Duration
+    ) -> Self {
+        let mut record = Record {
+            input: input.to_path_buf(),
+            status: Status::Failed,
+            outputs: Vec::new(),
+            skipped: 0,
+            encoding: None,
+            warnings: Vec::new(),
+            failed: Vec::new(),
+            kept: Vec::new(),
+            error: None,
+            elapsed: elapsed.as_secs_f64()
+        };
+
+        match result {
+            Ok(converted) => {
+                record.status = if converted.books == 0 && converted.skipped > 0 {Status::Skipped}
+                    else {Status::Converted};
+                record.outputs = converted.outputs.iter()
+                    .map(|book| Output {
+                        path: book.path.clone(),
+                        source: book.source.clone(),
+                        action: book.action.into(),
+                        size: book.size,
+                        title: book.title.clone(),
+                        authors: book.authors.clone(),
+                        series: book.series.clone().map(|(name, index)| Series {name, index})
+                    })
+                    .collect();
+                record.skipped = converted.skipped;
+                record.encoding = converted.encoding.clone();
+                record.warnings = converted.warnings.clone();
+                record.failed = converted.failed.clone();
+                record.kept = converted.kept.clone();
+            },
+            Err(err) => record.error = Some(ErrorInfo {
+                kind: error_kind(err.as_ref()),
+                message: err.to_string()
+            })
+        };
+
+        record
+    }
+}
+
+
+/// Дописывает запись в лог одной строкой JSON, time - время записи в секундах Unix
+pub fn append(record: &Record, path: Option<&Path>, stdout: bool) -> Result<(), Box<dyn Error>> {
+    #[derive(Serialize)]
+    struct Line<'a> {
+        time: u64,
+        #[serde(flatten)]
+        record: &'a Record
+    }
+
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+    let json = serde_json::to_string(&Line {time, record})?;
+
+    if let Some(path) = path {
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{json}")?
+    };
+    if stdout {
+        println!("{json}")
+    };
+
+    Ok(())
+}
+
+/// Пишет отчёт в файл и/или в stdout одним JSON массивом
+pub fn write(records: &mut [Record], path: Option<&Path>, stdout: bool) -> Result<(), Box<dyn Error>> {
+    // книги конвертируются параллельно, порядок записей - по входным файлам
+    records.sort_by(|a, b| a.input.cmp(&b.input));
+    let json = serde_json::to_string_pretty(records)?;
+
+    if let Some(path) = path {
+        fs::write(path, &json)?
+    };
+    if stdout {
+        println!("{json}")
+    };
+
+    Ok(())
+}
@@ -0,0 +1,362 @@
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+
+use base64::{Engine as _, engine::general_purpose};
+use serde::{Deserialize, Serialize};
+use threadpool::ThreadPool;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+
+/// Настройки сервиса из командной строки
+pub struct ServeSettings {
+    pub bind: String,
+    /// Максимальный размер тела запроса в байтах
+    pub max_size: usize,
+    pub jobs: usize,
+    /// Сколько запросов может ждать свободного потока
+    pub max_queue: usize,
+    pub styles: Option<PathBuf>,
+    pub verify: bool,
+    pub quiet: bool
+}
+
+/// Параметры запроса: из строки запроса или из JSON-тела, где книга - в base64
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Params {
+    book: Option<String>,
+    name: Option<String>,
+    format: Option<String>,
+    full: bool,
+    title: Option<String>,
+    author: Option<Vec<String>>,
+    language: Option<String>,
+    series: Option<String>,
+    series_index: Option<String>,
+    genre: Option<Vec<String>>,
+    annotation: Option<Vec<String>>
+}
+
+#[derive(Serialize)]
+struct SectionEntry {
+    level: u8,
+    title: String,
+    words: usize
+}
+
+/// Книга в ответе /info, для книг архива, которые не прочитались, - только source и error
+#[derive(Serialize, Default)]
+struct InfoEntry {
+    source: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    title: String,
+    authors: Vec<String>,
+    series: String,
+    series_index: String,
+    genres: Vec<String>,
+    language: String,
+    annotation: Vec<String>,
+    id: Option<String>,
+    cover: bool,
+    encoding: Option<String>,
+    images: usize,
+    words: usize,
+    sections: Vec<SectionEntry>
+}
+
+impl From<fb2epub::BookInfo> for InfoEntry {
+    fn from(info: fb2epub::BookInfo) -> Self {
+        let (series, series_index) = info.series.unwrap_or_default();
+        InfoEntry {
+            source: info.source,
+            error: None,
+            title: info.title,
+            authors: info.authors,
+            series,
+            series_index,
+            genres: info.genres,
+            language: info.language,
+            annotation: info.annotation,
+            id: info.id,
+            cover: info.cover,
+            encoding: info.encoding,
+            images: info.images,
+            words: info.words,
+            sections: info.sections.into_iter()
+                .map(|s| SectionEntry {level: s.level, title: s.title, words: s.words})
+                .collect()
+        }
+    }
+}
+
+/// Ответ с ошибкой: код и текст
+struct Failure(u16, String);
+
+impl<E: std::fmt::Display> From<E> for Failure {
+    fn from(err: E) -> Self {
+        Failure(422, err.to_string())
+    }
+}
+
+
+pub fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("Header must be ASCII")
+}
+
+pub fn json_response(status: u16, json: String) -> Response<std::io::Cursor<Vec<u8>>> {
+    Response::from_string(json)
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"))
+}
+
+pub fn error_response(status: u16, message: &str) -> Response<std::io::Cursor<Vec<u8>>> {
+    json_response(status, serde_json::json!({"error": message}).to_string())
+}
+
+/// Раскодирует %XX и + из строки запроса
+pub fn decode_component(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok()
+                    .and_then(|h| u8::from_str_radix(h, 16).ok());
+                match hex {
+                    Some(b) => {
+                        decoded.push(b);
+                        i += 2
+                    },
+                    None => decoded.push(b'%')
+                }
+            },
+            b => decoded.push(b)
+        };
+        i += 1
+    };
+
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Кодирует всё, кроме букв, цифр и -._, как %XX: для путей в ссылках
+/// и имени файла в Content-Disposition (filename*=UTF-8''...)
+pub fn encode_component(name: &str) -> String {
+    name.bytes()
+        .map(|b| if b.is_ascii_alphanumeric() || b"-._".contains(&b) {(b as char).to_string()}
+            else {format!("%{b:02X}")})
+        .collect()
+}
+
+/// Параметры из строки запроса, повторяющиеся author, genre и annotation собираются в списки
+fn query_params(url: &str) -> Result<Params, Failure> {
+    let mut params = Params::default();
+    let query = match url.split_once('?') {
+        Some((_, q)) => q,
+        None => return Ok(params)
+    };
+
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let value = decode_component(value);
+        match &decode_component(key)[..] {
+            "name" => params.name = Some(value),
+            "format" => params.format = Some(value),
+            "full" => params.full = value.is_empty() || value == "true" || value == "1",
+            "title" => params.title = Some(value),
+            "author" => params.author.get_or_insert_default().push(value),
+            "language" => params.language = Some(value),
+            "series" => params.series = Some(value),
+            "series_index" => params.series_index = Some(value),
+            "genre" => params.genre.get_or_insert_default().push(value),
+            "annotation" => params.annotation.get_or_insert_default().push(value),
+            key => return Err(Failure(400, format!("Unknown parameter: {key}")))
+        }
+    };
+
+    Ok(params)
+}
+
+/// Тело запроса не больше max_size
+fn read_body(request: &mut Request, max_size: usize) -> Result<Vec<u8>, Failure> {
+    let mut body: Vec<u8> = Vec::new();
+    request.as_reader()
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut body)
+        .map_err(|err| Failure(400, err.to_string()))?;
+    if body.len() > max_size {
+        return Err(Failure(413, format!("Request is larger than {max_size} bytes")))
+    };
+
+    Ok(body)
+}
+
+/// Книга и параметры: JSON с книгой в base64 или сама книга с параметрами в строке запроса
+fn book_and_params(request: &mut Request, max_size: usize) -> Result<(Vec<u8>, Params), Failure> {
+    let is_json = request.headers().iter()
+        .any(|h| h.field.equiv("Content-Type") && h.value.as_str().starts_with("application/json"));
+    let query = query_params(request.url())?;
+    let body = read_body(request, max_size)?;
+    if !is_json {
+        return Ok((body, query))
+    };
+
+    let params: Params = serde_json::from_slice(&body)
+        .map_err(|err| Failure(400, format!("Bad JSON: {err}")))?;
+    let book = general_purpose::STANDARD.decode(params.book.as_deref().unwrap_or_default())
+        .map_err(|err| Failure(400, format!("Bad base64 in book: {err}")))?;
+
+    Ok((book, params))
+}
+
+fn metadata(params: &Params) -> Option<fb2epub::Metadata> {
+    let mut metadata = fb2epub::Metadata::default();
+    metadata.title = params.title.clone();
+    metadata.authors = params.author.clone();
+    metadata.language = params.language.clone();
+    metadata.series = params.series.clone();
+    metadata.series_index = params.series_index.clone();
+    metadata.description = params.annotation.clone();
+    metadata.genres = params.genre.clone();
+
+    let is_empty = metadata.title.is_none() && metadata.authors.is_none() && metadata.language.is_none()
+        && metadata.series.is_none() && metadata.series_index.is_none()
+        && metadata.description.is_none() && metadata.genres.is_none();
+    if is_empty {None} else {Some(metadata)}
+}
+
+fn content_type(name: &str) -> &'static str {
+    match name.rsplit('.').next().unwrap_or_default() {
+        "epub" => "application/epub+zip",
+        "fb2" => "application/x-fictionbook+xml",
+        "docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        _ => "application/zip"
+    }
+}
+
+fn convert(request: &mut Request, settings: &ServeSettings) -> Result<Response<std::io::Cursor<Vec<u8>>>, Failure> {
+    let (book, params) = book_and_params(request, settings.max_size)?;
+    let format = match &params.format {
+        Some(f) => Some(f.parse::<fb2epub::Format>().map_err(|err| Failure(400, err))?),
+        None => None
+    };
+    let options = fb2epub::Options {
+        verify: settings.verify,
+        ..fb2epub::Options::default()
+    };
+
+    let converted = fb2epub::convert_bytes(
+        &book,
+        params.name.as_deref().unwrap_or_default(),
+        format,
+        settings.styles.as_deref(),
+        metadata(&params),
+        true,
+        &options
+    )?;
+
+    Ok(Response::from_data(converted.data)
+        .with_header(header("Content-Type", content_type(&converted.name)))
+        .with_header(header("Content-Disposition",
+            &format!("attachment; filename*=UTF-8''{}", encode_component(&converted.name))))
+        .with_header(header("X-Warnings", &converted.warnings.len().to_string())))
+}
+
+fn info(request: &mut Request, settings: &ServeSettings) -> Result<Response<std::io::Cursor<Vec<u8>>>, Failure> {
+    let (book, params) = book_and_params(request, settings.max_size)?;
+    let books = fb2epub::info_bytes(&book, params.name.as_deref().unwrap_or_default(), params.full)?;
+    let entries: Vec<InfoEntry> = books.into_iter()
+        .map(|info| match info {
+            Ok(info) => info.into(),
+            Err(err) => InfoEntry {error: Some(err.to_string()), ..InfoEntry::default()}
+        })
+        .collect();
+
+    Ok(json_response(200, serde_json::to_string_pretty(&entries)?))
+}
+
+/// Обрабатывает запрос на конвертацию или метаданные в потоке из пула
+fn handle(mut request: Request, settings: &ServeSettings) {
+    let start = Instant::now();
+    let method = request.method().clone();
+    let path = request.url().split('?').next().unwrap_or_default().to_string();
+
+    let result = match &path[..] {
+        "/convert" => convert(&mut request, settings),
+        "/info" => info(&mut request, settings),
+        _ => Err(Failure(404, format!("There's no {path}")))
+    };
+    let (status, response) = match result {
+        Ok(response) => (200, response),
+        Err(Failure(status, message)) => (status, error_response(status, &message))
+    };
+
+    if !settings.quiet {
+        println!("{method} {path} {status} {}ms", start.elapsed().as_millis())
+    };
+    if let Err(err) = request.respond(response) {
+        eprintln!("Cannot send response: {err}")
+    }
+}
+
+/// Подкоманда serve: HTTP-сервис до Ctrl+C или SIGTERM, false - если не удалось запустить
+pub fn serve(settings: ServeSettings) -> bool {
+    let server = match Server::http(&settings.bind) {
+        Ok(s) => Arc::new(s),
+        Err(err) => {
+            eprintln!("Cannot listen on {}: {err}", settings.bind);
+            return false
+        }
+    };
+    let stop = Arc::clone(&server);
+    if let Err(err) = ctrlc::set_handler(move || stop.unblock()) {
+        eprintln!("Cannot set signal handler: {err}");
+        return false
+    };
+    if !settings.quiet {
+        println!("Listening on http://{}, press Ctrl+C to stop", server.server_addr())
+    };
+
+    let pool = ThreadPool::new(settings.jobs);
+    let settings = Arc::new(settings);
+    for request in server.incoming_requests() {
+        let is_post = *request.method() == Method::Post;
+        let path = request.url().split('?').next().unwrap_or_default().to_string();
+
+        // проверки без чтения тела - сразу, не занимая поток
+        let refusal = match &path[..] {
+            "/health" => Some((200, json_response(200, serde_json::json!({
+                "status": "ok",
+                "version": env!("CARGO_PKG_VERSION"),
+                "active": pool.active_count(),
+                "queued": pool.queued_count()
+            }).to_string()))),
+            "/convert" | "/info" if !is_post => Some((405, error_response(405, "Use POST"))),
+            "/convert" | "/info" if request.body_length().is_some_and(|l| l > settings.max_size) =>
+                Some((413, error_response(413, &format!("Request is larger than {} bytes", settings.max_size)))),
+            "/convert" | "/info" if pool.queued_count() >= settings.max_queue =>
+                Some((503, error_response(503, "Too many requests, try later").with_header(header("Retry-After", "1")))),
+            _ => None
+        };
+        if let Some((status, response)) = refusal {
+            if !settings.quiet && path != "/health" {
+                println!("{} {path} {status}", request.method())
+            };
+            if let Err(err) = request.respond(response) {
+                eprintln!("Cannot send response: {err}")
+            };
+            continue
+        };
+
+        let settings = Arc::clone(&settings);
+        pool.execute(move || handle(request, &settings));
+    };
+
+    // новые запросы больше не принимаются, начатые доделываются
+    pool.join();
+    true
+}
@@ -0,0 +1,211 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+
+/// Строка или список строк: "author": "A" и "author": ["A", "B"]
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>)
+}
+
+impl OneOrMany {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            OneOrMany::One(s) => vec![s],
+            OneOrMany::Many(v) => v
+        }
+    }
+}
+
+/// Номер в серии может быть и строкой, и числом
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Index {
+    Text(String),
+    Integer(i64),
+    Float(f64)
+}
+
+/// Поля sidecar-файла book.fb2.json или book.fb2.toml, остальные поля не читаются
+#[derive(Deserialize)]
+struct SidecarMeta {
+    title: Option<String>,
+    #[serde(alias = "author")]
+    authors: Option<OneOrMany>,
+    language: Option<String>,
+    series: Option<String>,
+    series_index: Option<Index>,
+    #[serde(alias = "description")]
+    annotation: Option<OneOrMany>,
+    #[serde(alias = "genre")]
+    genres: Option<OneOrMany>
+}
+
+impl From<SidecarMeta> for fb2epub::Metadata {
+    fn from(meta: SidecarMeta) -> Self {
+        let mut metadata = fb2epub::Metadata::default();
+        metadata.title = meta.title;
+        metadata.authors = meta.authors.map(OneOrMany::into_vec);
+        metadata.language = meta.language;
+        metadata.series = meta.series;
+        metadata.series_index = meta.series_index.map(|index| match index {
+            Index::Text(s) => s,
+            Index::Integer(i) => i.to_string(),
+            Index::Float(f) => f.to_string()
+        });
+        metadata.description = meta.annotation.map(OneOrMany::into_vec);
+        metadata.genres = meta.genres.map(OneOrMany::into_vec);
+
+        metadata
+    }
+}
+
+
+/// В папке одна книга: calibre кладёт в папку книги её форматы (book.fb2, book.epub)
+/// с одним именем, обложку и metadata.opf
+fn is_single_book(book: &Path) -> bool {
+    let Some(folder) = book.parent() else {return false};
+    let folder = if folder.as_os_str().is_empty() {Path::new(".")} else {folder};
+    let Ok(entries) = fs::read_dir(folder) else {return false};
+
+    let mut names: HashSet<String> = HashSet::new();
+    for path in entries.flatten().map(|e| e.path()) {
+        if path.is_file() && crate::is_supported(&path, true)
+            && let Some(name) = path.file_prefix().and_then(|n| n.to_str()) {
+            names.insert(name.to_string());
+        }
+    };
+
+    names.len() == 1
+}
+
+/// Файлы с метаданными рядом с книгой: book.fb2.json, book.fb2.toml
+/// и metadata.opf, как у calibre, если книга в папке одна
+fn sidecar_paths(book: &Path) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    if let Some(name) = book.file_name().and_then(|n| n.to_str()) {
+        paths.push(book.with_file_name(format!("{name}.json")));
+        paths.push(book.with_file_name(format!("{name}.toml")));
+    };
+    let opf = book.with_file_name("metadata.opf");
+    if opf.is_file() && is_single_book(book) {
+        paths.push(opf)
+    };
+
+    paths
+}
+
+/// Метаданные из первого найденного sidecar-файла книги
+pub fn read_sidecar(book: &Path) -> Option<Result<fb2epub::Metadata, Box<dyn Error>>> {
+    let path = sidecar_paths(book).into_iter().find(|p| p.is_file())?;
+    let read = || -> Result<fb2epub::Metadata, Box<dyn Error>> {
+        let text = fs::read_to_string(&path)?;
+        let text = text.trim_start_matches('\u{feff}');
+        Ok(match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => serde_json::from_str::<SidecarMeta>(text)?.into(),
+            Some("toml") => toml::from_str::<SidecarMeta>(text)?.into(),
+            _ => fb2epub::Metadata::from_opf(text)?
+        })
+    };
+
+    Some(read().map_err(|err| format!("{:#?}: {err}", path).into()))
+}
+
+
+/// Разбор csv: поля в кавычках могут содержать запятые, кавычки ("") и переносы строк
+fn parse_csv(text: &str) -> Vec<Vec<String>> {
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    let mut row: Vec<String> = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = text.trim_start_matches('\u{feff}').chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            },
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => row.push(std::mem::take(&mut field)),
+            '\r' if !in_quotes => {},
+            '\n' if !in_quotes => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row))
+            },
+            c => field.push(c)
+        }
+    };
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row)
+    };
+
+    rows.into_iter()
+        .filter(|r| r.iter().any(|f| !f.trim().is_empty()))
+        .collect()
+}
+
+/// Значение колонки строки, пустое - None
+fn cell(row: &[String], columns: &HashMap<String, usize>, name: &str) -> Option<String> {
+    let value = row.get(*columns.get(name)?)?.trim();
+    if value.is_empty() {None} else {Some(value.to_string())}
+}
+
+/// Список через ";", как в выводе ls --format csv
+fn list_cell(row: &[String], columns: &HashMap<String, usize>, name: &str) -> Option<Vec<String>> {
+    let list: Vec<String> = cell(row, columns, name)?
+        .split(';')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
+    if list.is_empty() {None} else {Some(list)}
+}
+
+/// Читает --metadata-map: csv с заголовком, книга ищется по колонкам path и source
+/// (как в ls --format csv) или по id документа
+pub fn read_map(path: &Path, overrides: &mut fb2epub::Overrides) -> Result<(), Box<dyn Error>> {
+    let rows = parse_csv(&fs::read_to_string(path)?);
+    let mut rows = rows.into_iter();
+    let header = rows.next().ok_or("The file is empty")?;
+    let columns: HashMap<String, usize> = header.iter()
+        .enumerate()
+        .map(|(i, name)| (name.trim().to_lowercase(), i))
+        .collect();
+    if !columns.contains_key("path") && !columns.contains_key("id") {
+        return Err("There's no path or id column".into())
+    };
+
+    for (i, row) in rows.enumerate() {
+        let mut metadata = fb2epub::Metadata::default();
+        metadata.title = cell(&row, &columns, "title");
+        metadata.authors = list_cell(&row, &columns, "authors");
+        metadata.language = cell(&row, &columns, "language");
+        metadata.series = cell(&row, &columns, "series");
+        metadata.series_index = cell(&row, &columns, "series_index");
+        metadata.description = cell(&row, &columns, "annotation")
+            .map(|a| a.lines().map(|l| l.trim().to_string()).filter(|l| !l.is_empty()).collect());
+        metadata.genres = list_cell(&row, &columns, "genres");
+
+        if let Some(book) = cell(&row, &columns, "path") {
+            let source = cell(&row, &columns, "source").map(PathBuf::from);
+            let key = fb2epub::Overrides::key(Path::new(&book), source.as_deref());
+            // поверх sidecar-файла, пустые ячейки его не трогают
+            let old = overrides.by_path.remove(&key).unwrap_or_default();
+            overrides.by_path.insert(key, old.merge(metadata));
+        } else if let Some(id) = cell(&row, &columns, "id") {
+            let old = overrides.by_id.remove(&id).unwrap_or_default();
+            overrides.by_id.insert(id, old.merge(metadata));
+        } else {
+            // строка 1 - заголовок
+            return Err(format!("Line {}: there's no path or id", i + 2).into())
+        }
+    };
+
+    Ok(())
+}
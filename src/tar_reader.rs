@@ -0,0 +1,113 @@
+use std::fs::{self, File};
+use std::path::{PathBuf, Path};
+use std::io::Cursor;
+
+use tempfile::TempDir;
+use zip::ZipArchive;
+
+use crate::compressed_reader;
+use crate::zip_reader::{ArchiveBooks, decode_name, extract_zip, get_extension, safe_path, unique_path};
+
+
+/// Извлекает книги tar в temp_path, остальные файлы и битые вложенные zip - в kept
+fn extract_books(path: &Path, temp_path: &Path, kept: &mut Vec<PathBuf>) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let mut archive = tar::Archive::new(compressed_reader::open(path)?);
+
+    let mut files: Vec<PathBuf> = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type().is_dir() {continue}
+
+        // имена в tar - байты как есть, кодировку определяем как в zip
+        let raw_name = decode_name(&entry.path_bytes());
+        let name = match safe_path(&raw_name) {
+            Some(n) if entry.header().entry_type().is_file() => n,
+            _ => {
+                kept.push(PathBuf::from(raw_name));
+                continue
+            }
+        };
+        let extension = name.extension().and_then(|s| Some(s.to_str()?.to_lowercase()));
+
+        match extension.as_deref() {
+            Some("zip") => {
+                let bytes = compressed_reader::read_limited(&mut entry)?;
+                match ZipArchive::new(Cursor::new(bytes)) {
+                    Ok(nested) => {
+                        // пути в kept - внутри tar, а не во временной папке
+                        let mut nested_kept: Vec<PathBuf> = Vec::new();
+                        extract_zip(nested, &temp_path.join(name.with_extension("")), &mut files, &mut nested_kept)?;
+                        kept.extend(nested_kept.into_iter().map(|k| k.strip_prefix(temp_path).unwrap_or(&k).to_path_buf()))
+                    },
+                    Err(_) => kept.push(name)
+                }
+            },
+            Some("fb2") | Some("fb3") => {
+                let outpath = unique_path(temp_path.join(&name), Path::exists);
+                if let Some(parent) = outpath.parent() {
+                    fs::create_dir_all(parent)?
+                };
+                let mut outfile = File::create(&outpath)?;
+                compressed_reader::copy_limited(&mut entry, &mut outfile)?;
+                files.push(outpath);
+            },
+            _ => kept.push(name)
+        }
+    };
+
+    Ok(files)
+}
+
+/// То же, что zip_reader::read_books, но для tar
+pub fn read_books(
+    path: &Path,
+    meta_only: bool
+) -> Result<ArchiveBooks, Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let temp_path = temp_dir.path();
+
+    let files = extract_books(path, temp_path, &mut Vec::new())?;
+    Ok(files.iter()
+        .map(|f| (
+            f.strip_prefix(temp_path).unwrap_or(f).to_path_buf(),
+            crate::read_book(f, &get_extension(f), false, meta_only)
+        ))
+        .collect())
+}
+
+/// То же, что zip_reader::read_book_bytes, но для tar
+pub fn read_book_bytes(path: &Path, name: &Path) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let temp_path = temp_dir.path();
+
+    let files = extract_books(path, temp_path, &mut Vec::new())?;
+    let file = files.iter()
+        .find(|f| f.strip_prefix(temp_path).is_ok_and(|f| f == name))
+        .ok_or(format!("There's no {:#?} in {:#?}", name, path))?;
+
+    Ok(fs::read(file)?)
+}
+
+/// То же, что zip_reader::convert_archive, но для .tar, .tar.gz, .tar.bz2 и .tar.xz
+pub fn convert_archive(
+    path: &Path,
+    output: &Path,
+    styles_path: Option<&Path>,
+    metadata: Option<crate::Metadata>,
+    format: Option<crate::Format>,
+    suspend_error_messages: bool,
+    options: &crate::Options
+) -> Result<crate::Converted, Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let temp_path = temp_dir.path();
+
+    let mut kept: Vec<PathBuf> = Vec::new();
+    let files = extract_books(path, temp_path, &mut kept)?;
+    let names: Vec<PathBuf> = files.iter()
+        .map(|f| f.strip_prefix(temp_path).unwrap_or(f).to_path_buf())
+        .collect();
+    let load = |i: usize| crate::read_book(&files[i], &get_extension(&files[i]), false, options.meta_only());
+
+    crate::zip_reader::convert_books(path, &names, &load, output, styles_path, metadata, format, suspend_error_messages, options)
+        .map(|converted| crate::Converted {kept, ..converted})
+}
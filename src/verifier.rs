@@ -0,0 +1,90 @@
+use std::fs::File;
+use std::io::{Cursor, Read, Seek};
+use std::path::Path;
+
+use zip::ZipArchive;
+
+use crate::Format;
+use crate::epub_parser::read_entry;
+use crate::epub_parser::opf_reader::{container_reader, opf_reader};
+
+
+/// EPUB: mimetype, container.xml, OPF, непустой spine и все файлы из манифеста на месте
+fn verify_epub<R: Read + Seek>(reader: R) -> Result<(), Box<dyn std::error::Error>> {
+    let mut archive = ZipArchive::new(reader)?;
+
+    let mimetype = read_entry(&mut archive, "mimetype")?;
+    if mimetype.trim_ascii() != b"application/epub+zip" {
+        return Err("wrong mimetype".into())
+    };
+
+    let container = String::from_utf8_lossy(&read_entry(&mut archive, "META-INF/container.xml")?).to_string();
+    let opf_path = container_reader(&container).ok_or("no OPF in container.xml")?;
+    let opf = String::from_utf8_lossy(&read_entry(&mut archive, &opf_path)?).to_string();
+    let package = opf_reader(&opf, &opf_path)?;
+
+    if package.spine.is_empty() {
+        return Err("empty spine".into())
+    };
+    if let Some(idref) = package.spine.iter().find(|id| !package.manifest.contains_key(*id)) {
+        return Err(format!("spine item {idref} is not in manifest").into())
+    };
+    for item in package.manifest.values() {
+        if archive.by_name(&item.href).is_err() {
+            return Err(format!("{} is missing", item.href).into())
+        }
+    };
+
+    Ok(())
+}
+
+/// DOCX: основные части пакета на месте, document.xml читается
+fn verify_docx<R: Read + Seek>(reader: R) -> Result<(), Box<dyn std::error::Error>> {
+    let mut archive = ZipArchive::new(reader)?;
+    for part in ["[Content_Types].xml", "_rels/.rels", "word/_rels/document.xml.rels"] {
+        read_entry(&mut archive, part)?;
+    };
+
+    let document = read_entry(&mut archive, "word/document.xml")?;
+    let mut reader = quick_xml::reader::Reader::from_reader(&document[..]);
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            quick_xml::events::Event::Eof => break,
+            _ => buf.clear()
+        }
+    };
+
+    Ok(())
+}
+
+/// FB2: книга снова читается и в ней есть текст
+fn verify_fb2(bytes: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let data = crate::fb2_parser::get_data_from_bytes(bytes)?;
+    if data.content.is_empty() {
+        return Err("no text".into())
+    };
+
+    Ok(())
+}
+
+
+/// Проверяет книгу в памяти
+pub fn verify_bytes(bytes: &[u8], format: Format) -> Result<(), Box<dyn std::error::Error>> {
+    match format {
+        Format::Epub => verify_epub(Cursor::new(bytes)),
+        Format::Docx => verify_docx(Cursor::new(bytes)),
+        Format::Fb2 => verify_fb2(bytes)
+    }
+}
+
+/// Проверяет записанную книгу, перед тем как удалять входной файл
+pub fn verify(output: &Path, format: Format) -> Result<(), Box<dyn std::error::Error>> {
+    let result = match format {
+        Format::Epub => verify_epub(File::open(output)?),
+        Format::Docx => verify_docx(File::open(output)?),
+        Format::Fb2 => verify_fb2(&std::fs::read(output)?)
+    };
+
+    result.map_err(|err| format!("Output {:#?} is broken: {err}", output).into())
+}
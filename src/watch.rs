@@ -0,0 +1,184 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, mpsc};
+use std::time::{Duration, Instant};
+
+use indicatif::ProgressBar;
+use notify::event::{AccessKind, AccessMode};
+use notify::{EventKind, RecursiveMode, Watcher};
+use threadpool::ThreadPool;
+
+use crate::{report, RunArgs, Settings, Summary};
+
+
+enum Message {
+    Event(notify::Result<notify::Event>),
+    Stop
+}
+
+/// Событие, после которого файл надо конвертировать: создание, изменение, переименование в него
+/// и закрытие после записи (inotify на Linux)
+fn is_write(kind: &EventKind) -> bool {
+    matches!(kind,
+        EventKind::Create(_) | EventKind::Modify(_) |
+        EventKind::Access(AccessKind::Close(AccessMode::Write)))
+}
+
+/// Конвертирует одну книгу, sidecar-файлы и --metadata-map читаются заново:
+/// они могли появиться вместе с книгой
+fn convert(file: &Path, args: &RunArgs, settings: &Settings, summary: &Mutex<Summary>, log: Option<&Path>) {
+    let mut settings = settings.clone();
+    match crate::read_overrides(&[file.to_path_buf()], args) {
+        Ok(overrides) => settings.options.overrides = Arc::new(overrides),
+        Err(err) => eprintln!("{err}")
+    };
+
+    crate::convert_file(file, &settings, &ProgressBar::hidden(), summary);
+    let record = summary.lock().unwrap().records.pop();
+    if let Some(record) = record
+        && let Err(err) = report::append(&record, log, args.json) {
+        eprintln!("Cannot write log: {err}")
+    }
+}
+
+/// Подкоманда watch: следит за папками и конвертирует новые и изменённые книги,
+/// когда их перестают записывать. Останавливается по Ctrl+C или SIGTERM,
+/// дожидаясь начатых книг. false, если следить не удалось или какая-то книга не сконвертирована
+pub fn watch(mut args: RunArgs, settle: u64, log: Option<PathBuf>, existing: bool) -> bool {
+    crate::load_config(&mut args);
+    // изменённая книга заменяет свою старую версию, если политика не задана
+    args.on_exists = args.on_exists.or(Some(fb2epub::OnExists::Update));
+
+    // книги рядом со входными попали бы в папку снова
+    let output = match &args.output {
+        Some(o) => PathBuf::from(o),
+        None => {
+            eprintln!("watch needs --output");
+            return false
+        }
+    };
+    if let Err(err) = fs::create_dir_all(&output) {
+        eprintln!("Cannot create {:#?}: {err}", output);
+        return false
+    };
+    let output = fs::canonicalize(&output).unwrap_or(output);
+
+    let mut folders: Vec<PathBuf> = Vec::new();
+    for input in &args.input {
+        let folder = match fs::canonicalize(input) {
+            Ok(f) if f.is_dir() => f,
+            _ => {
+                eprintln!("There's no such directory: {:?}!", input);
+                return false
+            }
+        };
+        if folder.starts_with(&output) {
+            eprintln!("Output folder {:#?} must not contain {:#?}", output, folder);
+            return false
+        };
+        folders.push(folder)
+    };
+
+    let mut settings = crate::make_settings(&args, &[], true);
+    // записи отчёта идут в лог
+    settings.report = true;
+    settings.print_plan = false;
+    let settle = Duration::from_secs(settle);
+    let jobs = settings.options.jobs.unwrap_or(1);
+
+    let (tx, rx) = mpsc::channel::<Message>();
+    let events = tx.clone();
+    let mut watcher = match notify::recommended_watcher(move |event| {
+        let _ = events.send(Message::Event(event));
+    }) {
+        Ok(w) => w,
+        Err(err) => {
+            eprintln!("Cannot watch folders: {err}");
+            return false
+        }
+    };
+    let mode = if args.recursive {RecursiveMode::Recursive} else {RecursiveMode::NonRecursive};
+    for folder in &folders {
+        if let Err(err) = watcher.watch(folder, mode) {
+            eprintln!("Cannot watch {:#?}: {err}", folder);
+            return false
+        }
+    };
+    if let Err(err) = ctrlc::set_handler(move || {let _ = tx.send(Message::Stop);}) {
+        eprintln!("Cannot set signal handler: {err}");
+        return false
+    };
+
+    let with_epub = args.format == Some(fb2epub::Format::Fb2);
+    // книга и время последнего изменения
+    let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+    if existing {
+        let inputs: Vec<String> = folders.iter().map(|f| f.to_string_lossy().to_string()).collect();
+        for file in crate::get_files(&inputs, args.recursive, with_epub) {
+            pending.insert(file, Instant::now());
+        }
+    };
+
+    let args = Arc::new(args);
+    let settings = Arc::new(settings);
+    let log = Arc::new(log);
+    let summary = Arc::new(Mutex::new(Summary::default()));
+    let running: Arc<Mutex<HashSet<PathBuf>>> = Arc::new(Mutex::new(HashSet::new()));
+    let pool = ThreadPool::new(jobs);
+    if !args.quiet && !args.json {
+        println!("Watching {}, press Ctrl+C to stop",
+            folders.iter().map(|f| format!("{:#?}", f)).collect::<Vec<_>>().join(", "))
+    };
+
+    loop {
+        match rx.recv_timeout(Duration::from_millis(200)) {
+            Ok(Message::Stop) | Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            Ok(Message::Event(Ok(event))) if is_write(&event.kind) => {
+                for path in event.paths {
+                    if crate::is_supported(&path, with_epub) && !path.starts_with(&output) {
+                        pending.insert(path, Instant::now());
+                    }
+                }
+            },
+            Ok(Message::Event(Err(err))) => eprintln!("Watch error: {err}"),
+            _ => {}
+        };
+
+        // книга, которая не менялась settle секунд, считается записанной
+        let ready: Vec<PathBuf> = pending.iter()
+            .filter(|(_, changed)| changed.elapsed() >= settle)
+            .map(|(path, _)| path.clone())
+            .collect();
+        for file in ready {
+            // прошлая версия ещё конвертируется, новая подождёт
+            if running.lock().unwrap().contains(&file) {continue}
+            pending.remove(&file);
+            if !file.is_file() {continue}
+
+            running.lock().unwrap().insert(file.clone());
+            let (args, settings, log, summary, running) = (
+                Arc::clone(&args), Arc::clone(&settings), Arc::clone(&log),
+                Arc::clone(&summary), Arc::clone(&running)
+            );
+            pool.execute(move || {
+                convert(&file, &args, &settings, &summary, log.as_deref());
+                running.lock().unwrap().remove(&file);
+            });
+        }
+    };
+
+    drop(watcher);
+    if !args.quiet && !args.json {
+        println!("Stopping, waiting for {} books", pool.active_count() + pool.queued_count())
+    };
+    pool.join();
+
+    let summary = summary.lock().unwrap();
+    if !args.quiet && !args.json {
+        println!("Converted: {}, skipped: {}, failed: {}, warnings: {}",
+            summary.converted, summary.skipped, summary.failed, summary.warnings)
+    };
+
+    summary.failed == 0
+}
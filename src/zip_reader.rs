@@ -1,60 +1,307 @@
-use std::fs::File;
+use std::collections::HashSet;
+use std::fs::{self, File};
 use std::path::{PathBuf, Path};
-use std::io;
+use std::io::{Cursor, Read, Seek, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 
-use tempfile::TempDir;
+use encoding_rs::IBM866;
+use zip::{ZipArchive, ZipWriter};
+use zip::write::SimpleFileOptions;
 
+use crate::compressed_reader::{copy_limited, read_limited};
+use crate::fb2_parser::BookData;
 
-fn extract_books(path: &Path, temp_path: &Path) -> zip::result::ZipResult<Vec<PathBuf>> {
-    let file = File::open(path)?;
-    let mut archive = zip::ZipArchive::new(file)?;
 
-    let mut files: Vec<PathBuf> = Vec::new();
+/// Читает книгу по номеру в списке
+pub type LoadBook<'a> = dyn Fn(usize) -> Result<BookData, Box<dyn std::error::Error>> + Sync + 'a;
+
+/// Книги архива: путь внутри и прочитанная книга или ошибка
+pub type ArchiveBooks = Vec<(PathBuf, Result<BookData, Box<dyn std::error::Error>>)>;
+
+/// Книга в архиве: путь внутри, вложенный архив (None - сам файл) и номер записи
+struct Member {
+    name: PathBuf,
+    archive: Option<usize>,
+    index: usize
+}
+
+
+/// Имя файла в архиве: UTF-8 (флаг или поле Info-ZIP), иначе CP866,
+/// в котором русские архиваторы для DOS и Windows пишут имена
+pub fn decode_name(raw: &[u8]) -> String {
+    match std::str::from_utf8(raw) {
+        Ok(name) => name.to_string(),
+        Err(_) => IBM866.decode_without_bom_handling(raw).0.into_owned()
+    }
+}
+
+/// Относительный путь без "..", корня и пустых частей
+pub fn safe_path(name: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for part in name.split(['/', '\\']) {
+        match part {
+            "" | "." => continue,
+            ".." => return None,
+            p if p.contains(':') => return None,
+            p => path.push(p)
+        }
+    };
+
+    if path.as_os_str().is_empty() {None}
+    else {Some(path)}
+}
+
+/// Свободный путь: name, name-1, name-2... пока is_taken
+pub fn unique_path(path: PathBuf, is_taken: impl Fn(&Path) -> bool) -> PathBuf {
+    let is_free = |p: &Path| !is_taken(p);
+    if is_free(&path) {return path}
+
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or_default().to_string();
+    let extension = path.extension().and_then(|s| s.to_str()).unwrap_or_default().to_string();
+    let mut counter = 1;
+    loop {
+        let candidate = path.with_file_name(format!("{stem}-{counter}.{extension}"));
+        if is_free(&candidate) {return candidate}
+        counter += 1
+    }
+}
+
+pub fn is_zip(name: &Path) -> bool {
+    name.extension().and_then(|s| s.to_str()).is_some_and(|e| e.eq_ignore_ascii_case("zip"))
+}
+
+pub fn get_extension(name: &Path) -> String {
+    name.extension()
+        .and_then(|s| Some(s.to_str()?.to_lowercase()))
+        .unwrap_or_default()
+}
+
+pub fn is_book(name: &Path) -> bool {
+    matches!(&get_extension(name)[..], "fb2" | "fb3")
+}
+
+/// Извлекает книги из архива в dir с сохранением папок,
+/// вложенные zip распаковываются в папку с именем архива.
+/// Остальные файлы и битые вложенные архивы попадают в kept
+pub fn extract_zip<R: Read + Seek>(
+    mut archive: ZipArchive<R>,
+    dir: &Path,
+    files: &mut Vec<PathBuf>,
+    kept: &mut Vec<PathBuf>
+) -> zip::result::ZipResult<()> {
     for i in 0..archive.len() {
         let mut file = archive.by_index(i)?;
         if file.is_dir() {continue}
 
-        if let Some(name) = file.enclosed_name() {
-            if name.extension().and_then(|s| Some(s.to_str()?.to_lowercase())) == Some("fb2".to_string()) {
-                let outpath: PathBuf = temp_path.to_owned().join(
-                    if let Some(n) = name.file_name() {n}
-                    else {continue}
-                );
-                let mut outfile = File::create(&outpath)?;
-                io::copy(&mut file, &mut outfile)?;
-                files.push(outpath);
+        let raw_name = decode_name(file.name_raw());
+        let name = match safe_path(&raw_name) {
+            Some(n) => n,
+            None => {
+                kept.push(dir.join(raw_name));
+                continue
+            }
+        };
+
+        if is_zip(&name) {
+            let bytes = read_limited(&mut file)?;
+            // битый вложенный архив не должен останавливать остальные книги
+            match ZipArchive::new(Cursor::new(bytes)) {
+                Ok(nested) => extract_zip(nested, &dir.join(name.with_extension("")), files, kept)?,
+                Err(_) => kept.push(dir.join(name))
+            };
+            continue
+        };
+
+        if is_book(&name) {
+            let outpath = unique_path(dir.join(&name), Path::exists);
+            if let Some(parent) = outpath.parent() {
+                fs::create_dir_all(parent)?
             };
+            let mut outfile = File::create(&outpath)?;
+            copy_limited(&mut file, &mut outfile)?;
+            files.push(outpath);
+        } else {
+            kept.push(dir.join(name))
         };
     };
 
-    return Ok(files)
-    
+    Ok(())
+}
+
+
+/// Собирает книги архива без распаковки, вложенные zip читаются в память
+/// и попадают в папку с именем архива. Остальные файлы и битые вложенные архивы - в kept
+fn collect_members<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    dir: &Path,
+    nested: &mut Vec<Mutex<ZipArchive<Cursor<Vec<u8>>>>>,
+    members: &mut Vec<Member>,
+    kept: &mut Vec<PathBuf>
+) -> zip::result::ZipResult<()> {
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        if file.is_dir() {continue}
+
+        let raw_name = decode_name(file.name_raw());
+        let name = match safe_path(&raw_name) {
+            Some(n) => dir.join(n),
+            None => {
+                kept.push(dir.join(raw_name));
+                continue
+            }
+        };
+
+        if is_zip(&name) {
+            let bytes = read_limited(&mut file)?;
+            let mut inner = match ZipArchive::new(Cursor::new(bytes)) {
+                Ok(a) => a,
+                Err(_) => {
+                    kept.push(name);
+                    continue
+                }
+            };
+
+            // книги самого вложенного архива получают его номер после добавления
+            let mut inner_members: Vec<Member> = Vec::new();
+            collect_members(&mut inner, &name.with_extension(""), nested, &mut inner_members, kept)?;
+            nested.push(Mutex::new(inner));
+            let id = nested.len() - 1;
+            members.extend(inner_members.into_iter()
+                .map(|m| Member {archive: m.archive.or(Some(id)), ..m}));
+            continue
+        };
+
+        if is_book(&name) {
+            members.push(Member {name, archive: None, index: i})
+        } else {
+            kept.push(name)
+        };
+    };
+
+    Ok(())
+}
+
+fn read_member<R: Read + Seek>(archive: &Mutex<ZipArchive<R>>, index: usize) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut archive = archive.lock().map_err(|_| "Archive is poisoned")?;
+    let file = archive.by_index(index)?;
+
+    Ok(read_limited(file)?)
 }
 
+
 pub fn convert_archive(
     path: &Path,
     output: &Path,
     styles_path: Option<&Path>,
     metadata: Option<crate::Metadata>,
-    suspend_error_messages: bool
-) -> Result<PathBuf, Box<dyn std::error::Error>> {
-    let temp_dir = TempDir::new()?;
-    let temp_path = temp_dir.path();
+    format: Option<crate::Format>,
+    suspend_error_messages: bool,
+    options: &crate::Options
+) -> Result<crate::Converted, Box<dyn std::error::Error>> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let mut nested: Vec<Mutex<ZipArchive<Cursor<Vec<u8>>>>> = Vec::new();
+    let mut members: Vec<Member> = Vec::new();
+    let mut kept: Vec<PathBuf> = Vec::new();
+    collect_members(&mut archive, Path::new(""), &mut nested, &mut members, &mut kept)?;
+    let archive = Mutex::new(archive);
+
+    // Под блокировкой только распаковка записи, разбор книги идёт параллельно
+    let load = |i: usize| -> Result<BookData, Box<dyn std::error::Error>> {
+        let member = &members[i];
+        let bytes = match member.archive {
+            Some(id) => read_member(&nested[id], member.index)?,
+            None => read_member(&archive, member.index)?
+        };
+        crate::read_book_from_reader(&bytes[..], &get_extension(&member.name), options.meta_only())
+    };
+
+    let names: Vec<PathBuf> = members.iter().map(|m| m.name.clone()).collect();
+    convert_books(path, &names, &load, output, styles_path, metadata, format, suspend_error_messages, options)
+        .map(|converted| crate::Converted {kept, ..converted})
+}
+
+/// Читает все книги архива по очереди, meta_only - только описания.
+/// Ошибка в одной книге не мешает остальным
+pub fn read_books(
+    path: &Path,
+    meta_only: bool
+) -> Result<ArchiveBooks, Box<dyn std::error::Error>> {
+    read_archive_books(ZipArchive::new(File::open(path)?)?, meta_only)
+}
+
+/// Байты одной книги архива по её пути внутри, как в read_books
+pub fn read_book_bytes(path: &Path, name: &Path) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let mut nested: Vec<Mutex<ZipArchive<Cursor<Vec<u8>>>>> = Vec::new();
+    let mut members: Vec<Member> = Vec::new();
+    collect_members(&mut archive, Path::new(""), &mut nested, &mut members, &mut Vec::new())?;
+
+    let member = members.iter()
+        .find(|m| m.name == name)
+        .ok_or(format!("There's no {:#?} in {:#?}", name, path))?;
+    match member.archive {
+        Some(id) => read_member(&nested[id], member.index),
+        None => read_member(&Mutex::new(archive), member.index)
+    }
+}
 
-    let files = extract_books(path, temp_path)?;
-    if files.is_empty() {
+/// То же, что read_books, но для открытого архива, например, в памяти
+pub fn read_archive_books<R: Read + Seek>(
+    mut archive: ZipArchive<R>,
+    meta_only: bool
+) -> Result<ArchiveBooks, Box<dyn std::error::Error>> {
+    let mut nested: Vec<Mutex<ZipArchive<Cursor<Vec<u8>>>>> = Vec::new();
+    let mut members: Vec<Member> = Vec::new();
+    collect_members(&mut archive, Path::new(""), &mut nested, &mut members, &mut Vec::new())?;
+    let archive = Mutex::new(archive);
+
+    Ok(members.into_iter()
+        .map(|member| {
+            let bytes = match member.archive {
+                Some(id) => read_member(&nested[id], member.index),
+                None => read_member(&archive, member.index)
+            };
+            let data = bytes.and_then(|bytes|
+                crate::read_book_from_reader(&bytes[..], &get_extension(&member.name), meta_only));
+            (member.name, data)
+        })
+        .collect())
+}
+
+/// Конвертирует книги архива path: одну - в output, несколько - в папку
+/// или в zip. names - пути книг внутри архива, load читает книгу по номеру,
+/// книги обрабатываются параллельно
+#[allow(clippy::too_many_arguments)]
+pub fn convert_books(
+    path: &Path,
+    names: &[PathBuf],
+    load: &LoadBook,
+    output: &Path,
+    styles_path: Option<&Path>,
+    metadata: Option<crate::Metadata>,
+    format: Option<crate::Format>,
+    suspend_error_messages: bool,
+    options: &crate::Options
+) -> Result<crate::Converted, Box<dyn std::error::Error>> {
+    if names.is_empty() {
         return Err(format!("Nothing to convert in {:#?}", path).into())
     }
 
-    if files.len() == 1 {
-        return crate::run(
-            &files[0],
-            output,
-            false,
-            styles_path,
-            metadata,
-            suspend_error_messages
-        );
+    // в архиве только fb2 и fb3, так что по умолчанию epub
+    let format = format.unwrap_or(crate::Format::Epub);
+    let extension = format.extension();
+
+    if options.zip_output {
+        return convert_to_zip(path, names, load, output, styles_path, metadata, format, suspend_error_messages, options)
+    };
+
+    if names.len() == 1 {
+        let data = load(0)?;
+        let metadata = crate::book_metadata(metadata, path, Some(&names[0]), &data, options);
+        let converted = crate::save_book(path, data, output, format, styles_path, metadata, suspend_error_messages, options)?;
+        return Ok(set_source(converted, &names[0]))
     };
 
     let mut parent = output.parent()
@@ -66,26 +313,422 @@ pub fn convert_archive(
             .and_then(|n| n.to_str())
                 .ok_or(format!("Cannot get output folder for: {:#?}", path))?;
         
-        parent = if let Some(r_index) = out_folder_name.rfind(".epub") {
-            parent.join(format!("{}_out", &out_folder_name[..r_index]))
+        parent = if let Some(r_index) = out_folder_name.rfind(&format!(".{extension}")) {
+            let name = &out_folder_name[..r_index];
+            parent.join(format!("{}_out", name.strip_suffix(".tar").unwrap_or(name)))
         } else {output.to_path_buf()}
     };
-    
-    for file in &files {
-        let file_name = if let Some(name) = file
-            .file_stem().and_then(|os| os.to_str()) {
-                name.to_string() + ".epub"
-        } else {continue};
-        let file_output = parent.join(file_name);
-        crate::run(
-            file,
-            &file_output,
-            false,
+
+    // Имена выбираются заранее, чтобы параллельные книги с одинаковыми
+    // именами не заняли один и тот же файл
+    let mut taken: HashSet<PathBuf> = HashSet::new();
+    let mut outputs: Vec<PathBuf> = Vec::new();
+    let mut renamed: Vec<bool> = Vec::new();
+    for name in names {
+        let file_name = match name.file_stem().and_then(|os| os.to_str()) {
+            Some(stem) => format!("{stem}.{extension}"),
+            None => format!("new_book.{extension}")
+        };
+        let file_output = match name.parent() {
+            Some(folder) if options.keep_structure => parent.join(folder).join(file_name),
+            _ => parent.join(file_name)
+        };
+        // существующие файлы обходятся только при политике rename
+        let free_output = if options.on_exists == crate::OnExists::Rename {
+            unique_path(file_output.clone(), |p| p.exists() || taken.contains(p))
+        } else {
+            unique_path(file_output.clone(), |p| taken.contains(p))
+        };
+        renamed.push(free_output != file_output);
+        let file_output = free_output;
+        taken.insert(file_output.clone());
+        outputs.push(file_output);
+    };
+
+    let converted = for_each_book(path, names, options.jobs, |i| {
+        // без шаблона книгу можно пропустить, не читая её
+        if options.name_template.is_none() && crate::should_skip(path, &outputs[i], options.on_exists) {
+            return Ok(crate::skipped(outputs[i].clone()))
+        };
+
+        let data = load(i)?;
+        let metadata = crate::book_metadata(metadata.clone(), path, Some(&names[i]), &data, options);
+        let mut converted = crate::save_book(
+            path,
+            data,
+            &outputs[i],
+            format,
             styles_path,
-            metadata.clone(),
-            suspend_error_messages
+            metadata,
+            suspend_error_messages,
+            options
         )?;
+        // свободное имя было выбрано заранее, шаблон же строит своё
+        for book in &mut converted.outputs {
+            if renamed[i] && options.name_template.is_none() && book.action == crate::Action::Create {
+                book.action = crate::Action::Rename
+            }
+        };
+
+        Ok(converted)
+    })?;
+
+    Ok(crate::Converted {output: parent, ..converted})
+}
+
+/// Отмечает, из какой книги архива сделаны выходные книги
+fn set_source(mut converted: crate::Converted, name: &Path) -> crate::Converted {
+    for book in &mut converted.outputs {
+        book.source.get_or_insert_with(|| name.to_path_buf());
+    };
+
+    converted
+}
+
+/// Конвертирует книги zip-архива из памяти: одну - в книгу, несколько - в zip
+/// с теми же путями. Книги, которые не удалось сконвертировать, попадают в предупреждения
+pub fn convert_bytes(
+    bytes: &[u8],
+    name: &str,
+    styles_path: Option<&Path>,
+    metadata: Option<crate::Metadata>,
+    format: crate::Format,
+    suspend_error_messages: bool,
+    options: &crate::Options
+) -> Result<crate::ConvertedBytes, Box<dyn std::error::Error>> {
+    let mut books = read_archive_books(ZipArchive::new(Cursor::new(bytes))?, false)?;
+    let stem = Path::new(name).file_stem().and_then(|s| s.to_str()).unwrap_or("books");
+
+    if books.len() == 1 {
+        let (member, data) = books.remove(0);
+        let (book, converted) = crate::book_to_bytes(
+            data?, Path::new(name), Some(&member), format, styles_path, metadata, suspend_error_messages, options
+        )?;
+        let converted = set_source(converted, &member);
+        return Ok(crate::ConvertedBytes {
+            data: book,
+            name: zip_name(Path::new(member.file_name().unwrap_or(member.as_os_str())), format),
+            encoding: converted.encoding,
+            warnings: converted.warnings,
+            outputs: converted.outputs
+        })
+    };
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let zip_options = SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated);
+    let mut warnings: Vec<String> = Vec::new();
+    let mut outputs: Vec<crate::OutputBook> = Vec::new();
+    let mut first_error: Option<Box<dyn std::error::Error>> = None;
+    let entries = zip_names(books.iter().map(|(member, _)| member.as_path()), format);
+    for ((member, data), entry) in books.into_iter().zip(entries) {
+        let result = data.and_then(|data| crate::book_to_bytes(
+            data, Path::new(name), Some(&member), format, styles_path, metadata.clone(), suspend_error_messages, options
+        ));
+        let (book, converted) = match result {
+            Ok(r) => r,
+            Err(err) => {
+                warnings.push(format!("{}: {err}", member.display()));
+                first_error.get_or_insert(err);
+                continue
+            }
+        };
+
+        zip.start_file(entry.as_str(), zip_options)?;
+        zip.write_all(&book)?;
+        warnings.extend(converted.warnings.iter().map(|w| format!("{}: {w}", member.display())));
+        outputs.extend(set_source(converted, &member).outputs.into_iter()
+            .map(|o| crate::OutputBook {path: PathBuf::from(&entry), ..o}));
+    };
+
+    if outputs.is_empty() {
+        return Err(first_error.unwrap_or(format!("Nothing to convert in {name}").into()))
+    };
+
+    Ok(crate::ConvertedBytes {
+        data: zip.finish()?.into_inner(),
+        name: format!("{stem}_out.zip"),
+        encoding: None,
+        warnings,
+        outputs
+    })
+}
+
+/// Путь книги внутри выходного zip, разделитель всегда "/"
+fn zip_name(name: &Path, format: crate::Format) -> String {
+    let parts: Vec<String> = name.with_extension(format.extension()).components()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect();
+
+    parts.join("/")
+}
+
+/// Пути книг внутри выходного zip без повторов: a.fb2 и a.fb3 дают a.epub и a-1.epub
+fn zip_names<'a>(names: impl IntoIterator<Item = &'a Path>, format: crate::Format) -> Vec<String> {
+    let mut taken: HashSet<String> = HashSet::new();
+    names.into_iter()
+        .map(|name| {
+            let entry = unique_path(PathBuf::from(zip_name(name, format)), |p| taken.contains(p.to_string_lossy().as_ref()))
+                .to_string_lossy()
+                .to_string();
+            taken.insert(entry.clone());
+            entry
+        })
+        .collect()
+}
+
+/// Потоки, которые сейчас конвертируют книги: вызовы crate::convert и помощники архивов.
+/// Общий счётчик не даёт архивам внутри пула main запускать ещё jobs потоков каждый
+static BUSY: AtomicUsize = AtomicUsize::new(0);
+
+/// Занятый поток, освобождается при drop
+pub(crate) struct Busy;
+
+impl Busy {
+    /// Поток, который конвертирует сам, занимает место всегда
+    pub(crate) fn new() -> Busy {
+        BUSY.fetch_add(1, Ordering::Relaxed);
+        Busy
+    }
+
+    /// Место для помощника, если занято меньше jobs
+    fn try_new(jobs: usize) -> Option<Busy> {
+        BUSY.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |busy| (busy < jobs).then_some(busy + 1))
+            .ok()
+            .map(|_| Busy)
+    }
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        BUSY.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Обрабатывает книги в вызывающем потоке и в помощниках, пока всего занято меньше jobs
+/// (None - по числу ядер). Собирает предупреждения с именем книги, число готовых и пропущенных
+/// и ошибки по книгам в failed, Err - только если не удалась ни одна книга
+fn for_each_book<F>(
+    path: &Path,
+    names: &[PathBuf],
+    jobs: Option<usize>,
+    convert: F
+) -> Result<crate::Converted, Box<dyn std::error::Error>>
+where F: Fn(usize) -> Result<crate::Converted, Box<dyn std::error::Error>> + Sync {
+    let next = AtomicUsize::new(0);
+    let books = AtomicUsize::new(0);
+    let skipped = AtomicUsize::new(0);
+    let warnings: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    let outputs: Mutex<Vec<crate::OutputBook>> = Mutex::new(Vec::new());
+    let errors: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    let jobs = jobs.unwrap_or_else(crate::default_jobs).max(1);
+
+    let work = |i: usize| {
+        let name = names[i].display();
+        match convert(i).map(|c| set_source(c, &names[i])) {
+            Ok(converted) => {
+                books.fetch_add(converted.books, Ordering::Relaxed);
+                skipped.fetch_add(converted.skipped, Ordering::Relaxed);
+                warnings.lock().unwrap().extend(converted.warnings.into_iter().map(|w| format!("{name}: {w}")));
+                outputs.lock().unwrap().extend(converted.outputs)
+            },
+            Err(err) => errors.lock().unwrap().push(format!("{name}: {err}"))
+        }
+    };
+
+    let helpers = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        loop {
+            let i = next.fetch_add(1, Ordering::Relaxed);
+            if i >= names.len() {break}
+
+            // помощник берёт следующие книги, если в процессе освободилось место
+            if helpers.load(Ordering::Relaxed) + 1 < names.len() - i
+                && let Some(busy) = Busy::try_new(jobs) {
+                helpers.fetch_add(1, Ordering::Relaxed);
+                let (next, work, helpers) = (&next, &work, &helpers);
+                scope.spawn(move || {
+                    let _busy = busy;
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        if i >= names.len() {break}
+                        work(i)
+                    };
+                    helpers.fetch_sub(1, Ordering::Relaxed);
+                });
+            };
+            work(i)
+        }
+    });
+
+    let errors = errors.into_inner().unwrap();
+    if errors.len() == names.len() {
+        return Err(format!("Cannot convert {} books from {:#?}:\n{}",
+            names.len(), path, errors.join("\n")).into())
     };
 
-    Ok(parent)
+    // потоки заканчивают книги в случайном порядке
+    let mut outputs = outputs.into_inner().unwrap();
+    outputs.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(crate::Converted {
+        output: PathBuf::new(),
+        encoding: None,
+        warnings: warnings.into_inner().unwrap(),
+        books: books.into_inner(),
+        skipped: skipped.into_inner(),
+        outputs,
+        failed: errors,
+        kept: Vec::new()
+    })
+}
+
+/// Конвертирует книги в один zip с теми же путями внутри,
+/// каждая книга собирается в памяти и сразу пишется в архив
+#[allow(clippy::too_many_arguments)]
+fn convert_to_zip(
+    path: &Path,
+    names: &[PathBuf],
+    load: &LoadBook,
+    output: &Path,
+    styles_path: Option<&Path>,
+    metadata: Option<crate::Metadata>,
+    format: crate::Format,
+    suspend_error_messages: bool,
+    options: &crate::Options
+) -> Result<crate::Converted, Box<dyn std::error::Error>> {
+    let on_exists = options.on_exists;
+    // books.epub -> books_out.zip, явно заданный .zip остаётся как есть
+    let output = if is_zip(output) {output.to_path_buf()} else {
+        let name = output.file_stem()
+            .and_then(|n| n.to_str())
+            .ok_or(format!("Cannot get output name for: {:#?}", path))?;
+        output.with_file_name(format!("{}_out.zip", name.strip_suffix(".tar").unwrap_or(name)))
+    };
+    // имена выбираются заранее, как и для папки
+    let entries = zip_names(names.iter().map(|n| n.as_path()), format);
+    // для плана книги только читаются, у всех действие самого архива
+    if options.dry_run {
+        let (output, action) = crate::plan_output(path, &output, "zip", on_exists);
+        if action == crate::Action::Skip {
+            return Ok(crate::Converted {skipped: names.len(), ..crate::skipped(output)})
+        };
+        let converted = for_each_book(path, names, options.jobs, |i| {
+            let mut data = load(i)?;
+            if let Some(meta) = crate::book_metadata(metadata.clone(), path, Some(&names[i]), &data, options) {
+                crate::apply_metadata(&mut data, meta)
+            };
+            Ok(crate::converted(output.join(&entries[i]), data, 0, action))
+        })?;
+        return Ok(crate::Converted {output, ..converted})
+    };
+
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)?
+    };
+    // политика применяется ко всему архиву сразу
+    let (output, action, file) = match crate::create_output(path, &output, "zip", on_exists)? {
+        (output, _, None) => return Ok(crate::Converted {skipped: names.len(), ..crate::skipped(output)}),
+        (output, action, Some(file)) => (output, action, file)
+    };
+
+    let zip = Mutex::new(ZipWriter::new(file));
+    let zip_options = SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated);
+
+    let converted = for_each_book(path, names, options.jobs, |i| {
+        let mut data = load(i)?;
+        if let Some(meta) = crate::book_metadata(metadata.clone(), path, Some(&names[i]), &data, options) {
+            crate::apply_metadata(&mut data, meta)
+        };
+        let mut book = Cursor::new(Vec::new());
+        crate::write_book(&mut data, format, &mut book, styles_path, suspend_error_messages, options)?;
+        if options.verify {
+            crate::verifier::verify_bytes(book.get_ref(), format)
+                .map_err(|err| format!("New book is broken: {err}"))?
+        };
+
+        let name = &entries[i];
+        let mut zip = zip.lock().map_err(|_| "Output archive is poisoned")?;
+        zip.start_file(name.as_str(), zip_options)?;
+        zip.write_all(book.get_ref())?;
+
+        Ok(crate::converted(output.join(name), data, book.get_ref().len() as u64, action))
+    });
+    // архив закрывается и при ошибках, чтобы удачные книги остались в нём
+    zip.into_inner().map_err(|_| "Output archive is poisoned")?.finish()?;
+    let converted = converted?;
+    // с неудачными книгами архив при обновлении будет собран снова
+    if on_exists == crate::OnExists::Update && converted.failed.is_empty() {
+        crate::manifest::record(path, &output)?
+    };
+
+    Ok(crate::Converted {output, ..converted})
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use std::io::{Cursor, Write};
+
+    use zip::{ZipArchive, ZipWriter};
+    use zip::write::SimpleFileOptions;
+
+    use super::{collect_members, for_each_book, zip_names};
+    use crate::Format;
+
+    fn zip_of(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, bytes) in files {
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(bytes).unwrap();
+        };
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn zip_names_are_unique() {
+        let names = ["a.fb2", "a.fb3", "dir/a.fb2", "a.fbz", "b.fb2"].map(Path::new);
+        assert_eq!(
+            zip_names(names, Format::Epub),
+            ["a.epub", "a-1.epub", "dir/a.epub", "a-2.epub", "b.epub"]
+        );
+    }
+
+    #[test]
+    fn failed_book_keeps_others() {
+        let names = ["a.fb2", "b.fb2", "c.fb2"].map(PathBuf::from);
+        let convert = |i: usize| match i {
+            1 => Err("broken".into()),
+            _ => Ok(crate::skipped(names[i].with_extension("epub")))
+        };
+        let converted = for_each_book(Path::new("books.zip"), &names, Some(2), convert).unwrap();
+        assert_eq!(converted.skipped, 2);
+        assert_eq!(converted.outputs.len(), 2);
+        assert_eq!(converted.failed, ["b.fb2: broken"]);
+
+        let all_failed = for_each_book(Path::new("books.zip"), &names, Some(2), |_| Err("broken".into()));
+        assert!(all_failed.is_err());
+    }
+
+    /// Не книги и битые вложенные архивы не теряются молча: с ними архив не удаляется
+    #[test]
+    fn other_files_are_kept() {
+        let inner = zip_of(&[("inner.fb2", b"<FictionBook/>"), ("inner.jpg", b"jpg")]);
+        let outer = zip_of(&[
+            ("book.fb2", b"<FictionBook/>"),
+            ("readme.txt", b"text"),
+            ("broken.zip", b"not a zip"),
+            ("more.zip", &inner)
+        ]);
+        let mut archive = ZipArchive::new(Cursor::new(outer)).unwrap();
+        let (mut nested, mut members, mut kept) = (Vec::new(), Vec::new(), Vec::new());
+        collect_members(&mut archive, Path::new(""), &mut nested, &mut members, &mut kept).unwrap();
+
+        let names: Vec<&Path> = members.iter().map(|m| m.name.as_path()).collect();
+        assert_eq!(names, [Path::new("book.fb2"), Path::new("more/inner.fb2")]);
+        assert_eq!(kept, ["readme.txt", "broken.zip", "more/inner.jpg"].map(PathBuf::from));
+    }
 }